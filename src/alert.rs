@@ -0,0 +1,122 @@
+//! Facilities for delivering alerts (e.g., from the `monitor` tool)
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use std::io::Write;
+use std::str::FromStr;
+
+/// Describes something that a person should know about
+#[derive(Debug, serde::Serialize)]
+pub struct Alert {
+    /// short summary of the problem(s)
+    pub summary: String,
+    /// one line per problem found
+    pub details: Vec<String>,
+}
+
+impl Alert {
+    /// Returns the alert as a human-readable, multi-line message
+    pub fn message(&self) -> String {
+        let mut message = format!("{}\n", self.summary);
+        for d in &self.details {
+            message.push_str(&format!("    {}\n", d));
+        }
+        message
+    }
+}
+
+/// Describes a way to deliver an [`Alert`]
+///
+/// These are specified on the command line as:
+///
+/// * `stdout`: print the alert to stdout
+/// * `webhook=URL`: POST a JSON body to `URL`.  The body contains a "text"
+///   property (for compatibility with chat services like Slack) in addition to
+///   the structured "summary" and "details".
+/// * `mail-command=COMMAND`: run `COMMAND` with `sh -c` and write the alert
+///   message to its stdin (e.g., `mail-command=mail -s alert me@example.com`)
+#[derive(Clone, Debug)]
+pub enum AlertSink {
+    Stdout,
+    Webhook(reqwest::Url),
+    MailCommand(String),
+}
+
+impl FromStr for AlertSink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "stdout" {
+            return Ok(AlertSink::Stdout);
+        }
+
+        let (kind, value) = s.split_once('=').ok_or_else(|| {
+            anyhow!(
+                "unsupported alert sink {:?} (expected \"stdout\", \
+                \"webhook=URL\", or \"mail-command=COMMAND\")",
+                s
+            )
+        })?;
+        match kind {
+            "webhook" => Ok(AlertSink::Webhook(
+                reqwest::Url::parse(value)
+                    .with_context(|| format!("parsing URL {:?}", value))?,
+            )),
+            "mail-command" => Ok(AlertSink::MailCommand(value.to_owned())),
+            _ => bail!("unsupported alert sink kind: {:?}", kind),
+        }
+    }
+}
+
+impl AlertSink {
+    pub async fn deliver(&self, alert: &Alert) -> Result<(), anyhow::Error> {
+        match self {
+            AlertSink::Stdout => {
+                print!("{}", alert.message());
+                Ok(())
+            }
+
+            AlertSink::Webhook(url) => {
+                #[derive(serde::Serialize)]
+                struct WebhookBody<'a> {
+                    text: String,
+                    #[serde(flatten)]
+                    alert: &'a Alert,
+                }
+
+                reqwest::Client::new()
+                    .post(url.clone())
+                    .json(&WebhookBody { text: alert.message(), alert })
+                    .send()
+                    .await
+                    .with_context(|| format!("POST {}", url))?
+                    .error_for_status()
+                    .with_context(|| format!("POST {}", url))?;
+                Ok(())
+            }
+
+            AlertSink::MailCommand(command) => {
+                let mut child = std::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(std::process::Stdio::piped())
+                    .spawn()
+                    .with_context(|| format!("spawn {:?}", command))?;
+                child
+                    .stdin
+                    .take()
+                    .unwrap()
+                    .write_all(alert.message().as_bytes())
+                    .with_context(|| format!("write to {:?}", command))?;
+                let status = child
+                    .wait()
+                    .with_context(|| format!("wait for {:?}", command))?;
+                if !status.success() {
+                    bail!("command {:?} failed: {}", command, status);
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! Detecting unusual energy consumption
+//!
+//! We build a baseline of hourly consumption for each hour of the week (e.g.,
+//! Tuesdays from 3am to 4am) in each season, using the median and the median
+//! absolute deviation (MAD) of all the data we have.  These are robust to the
+//! anomalies themselves, so we can use all of the data to build the baseline.
+//! An hour is unusual if its consumption is well above the median for its hour
+//! of the week and season.  Runs of consecutive unusual hours are reported
+//! together as one anomaly, like a heater that was left on overnight or a
+//! pump that got stuck.
+
+use crate::bucket::Season;
+use crate::data_aggregator::DataLoader;
+use crate::stats;
+use chrono::Datelike;
+use chrono::Timelike;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Number of robust standard deviations above the median at which an hour
+/// counts as unusual
+const THRESHOLD_SIGMAS: f64 = 3.0;
+
+/// Minimum amount (in watt-hours) above the median at which an hour counts as
+/// unusual, so that hours with very consistent consumption aren't flagged for
+/// tiny changes
+const MIN_EXCESS_WH_PER_HOUR: f64 = 200.0;
+
+/// Minimum total excess (in watt-hours) for an anomaly to be reported
+const MIN_ANOMALY_EXCESS_WH: f64 = 500.0;
+
+/// Minimum number of samples needed for a baseline
+const MIN_BASELINE_SAMPLES: usize = 4;
+
+/// Converts the median absolute deviation to an estimate of the standard
+/// deviation (for normally-distributed data)
+const MAD_TO_SIGMA: f64 = 1.4826;
+
+/// Describes what an anomaly looks like
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AnomalyKind {
+    /// a short burst (one or two hours)
+    Spike,
+    /// elevated consumption mostly overnight (midnight to 6am), which usually
+    /// means something was left on
+    OvernightBaseLoad,
+    /// elevated consumption for several hours
+    Elevated,
+}
+
+/// Describes a period of unusual consumption
+#[derive(Debug, Serialize)]
+pub struct Anomaly {
+    /// local time at which the anomaly started
+    pub start: chrono::NaiveDateTime,
+    /// local time at which the anomaly ended
+    pub end: chrono::NaiveDateTime,
+    pub nhours: usize,
+    pub kind: AnomalyKind,
+    pub consumed_kwh: f64,
+    /// consumption we'd expect for these hours (the baseline median)
+    pub expected_kwh: f64,
+    /// `consumed_kwh - expected_kwh`
+    pub excess_kwh: f64,
+    /// cost of the excess energy, if we know the electricity rate
+    pub estimated_cost: Option<f64>,
+}
+
+/// Baseline consumption for one hour of the week in one season
+struct Baseline {
+    median: f64,
+    sigma: f64,
+}
+
+/// One hour of consumption data
+struct Hour {
+    start_utc: chrono::DateTime<chrono::Utc>,
+    local: chrono::NaiveDateTime,
+    key: (Season, u32),
+    consumed: f64,
+}
+
+/// Returns the baseline key for a local time: the season and the hour of the
+/// week (0 is Monday from midnight to 1am)
+fn baseline_key(local: &chrono::NaiveDateTime) -> (Season, u32) {
+    let hour_of_week =
+        local.weekday().num_days_from_monday() * 24 + local.hour();
+    (Season::of(&local.date()), hour_of_week)
+}
+
+/// Finds periods of unusual consumption in the loaded data
+///
+/// Only hours with net usage data (and complete production data) are
+/// considered.  If `rate_per_kwh` is given, it's used to estimate what each
+/// anomaly cost.
+pub fn find_anomalies(
+    aggr: &DataLoader,
+    rate_per_kwh: Option<f64>,
+) -> Vec<Anomaly> {
+    let hours: Vec<Hour> = aggr
+        .aggregate(|t: &chrono::DateTime<chrono::Utc>| Some(*t))
+        .into_iter()
+        .filter(|(_, totals)| {
+            totals.net_usage_hours > 0 && totals.production_partial_hours == 0
+        })
+        .map(|(start_utc, totals)| {
+            let local = start_utc.with_timezone(&chrono::Local).naive_local();
+            Hour {
+                start_utc,
+                local,
+                key: baseline_key(&local),
+                consumed: totals.consumed.as_wh() as f64,
+            }
+        })
+        .collect();
+
+    let mut samples: BTreeMap<(Season, u32), Vec<f64>> = BTreeMap::new();
+    for hour in &hours {
+        samples.entry(hour.key).or_default().push(hour.consumed);
+    }
+    let baselines: BTreeMap<(Season, u32), Baseline> = samples
+        .into_iter()
+        .filter(|(_, values)| values.len() >= MIN_BASELINE_SAMPLES)
+        .map(|(key, mut values)| {
+            stats::sort(&mut values);
+            let median = stats::percentile(&values, 50.0);
+            let mut deviations: Vec<f64> =
+                values.iter().map(|v| (v - median).abs()).collect();
+            stats::sort(&mut deviations);
+            let sigma = stats::percentile(&deviations, 50.0) * MAD_TO_SIGMA;
+            (key, Baseline { median, sigma })
+        })
+        .collect();
+
+    // Walk through the hours, grouping consecutive unusual ones.
+    let mut anomalies = Vec::new();
+    let mut current: Vec<(&Hour, f64)> = Vec::new();
+    for hour in &hours {
+        let expected = baselines.get(&hour.key).and_then(|b| {
+            let threshold =
+                (b.sigma * THRESHOLD_SIGMAS).max(MIN_EXCESS_WH_PER_HOUR);
+            if hour.consumed > b.median + threshold {
+                Some(b.median)
+            } else {
+                None
+            }
+        });
+        let contiguous = match current.last() {
+            Some((last, _)) => {
+                hour.start_utc - last.start_utc == chrono::Duration::hours(1)
+            }
+            None => true,
+        };
+        if expected.is_none() || !contiguous {
+            anomalies.extend(make_anomaly(&current, rate_per_kwh));
+            current.clear();
+        }
+        if let Some(expected) = expected {
+            current.push((hour, expected));
+        }
+    }
+    anomalies.extend(make_anomaly(&current, rate_per_kwh));
+    anomalies
+}
+
+/// Summarizes a run of unusual hours (with the expected consumption for each)
+/// as an anomaly, if it's big enough to report
+fn make_anomaly(
+    hours: &[(&Hour, f64)],
+    rate_per_kwh: Option<f64>,
+) -> Option<Anomaly> {
+    let (first, _) = hours.first()?;
+    let (last, _) = hours.last()?;
+    let consumed: f64 = hours.iter().map(|(h, _)| h.consumed).sum();
+    let expected: f64 = hours.iter().map(|(_, e)| e).sum();
+    let excess = consumed - expected;
+    if excess < MIN_ANOMALY_EXCESS_WH {
+        return None;
+    }
+
+    let overnight = hours.iter().filter(|(h, _)| h.local.hour() < 6).count();
+    let kind = if hours.len() <= 2 {
+        AnomalyKind::Spike
+    } else if overnight * 2 > hours.len() {
+        AnomalyKind::OvernightBaseLoad
+    } else {
+        AnomalyKind::Elevated
+    };
+    let kwh = |wh: f64| (wh / 10.0).round() / 100.0;
+    Some(Anomaly {
+        start: first.local,
+        end: (last.start_utc + chrono::Duration::hours(1))
+            .with_timezone(&chrono::Local)
+            .naive_local(),
+        nhours: hours.len(),
+        kind,
+        consumed_kwh: kwh(consumed),
+        expected_kwh: kwh(expected),
+        excess_kwh: kwh(excess),
+        estimated_cost: rate_per_kwh
+            .map(|rate| (excess / 1000.0 * rate * 100.0).round() / 100.0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::local_day_hours;
+    use crate::common::NetEnergyUsed;
+    use crate::common::WattHours;
+    use crate::data_aggregator::Source;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_find_anomalies() {
+        // Six weeks of summer with consumption alternating between 280 and
+        // 320 Wh every hour from one week to the next.  On the last
+        // Wednesday, something ran from 1am to 4am and at 6pm, and a bit
+        // more than usual was used at noon.
+        let first = chrono::NaiveDate::from_ymd(2022, 6, 1);
+        let unusual = first + chrono::Duration::weeks(5);
+        let mut records = Vec::new();
+        for n in 0..42 {
+            let date = first + chrono::Duration::days(n);
+            let normal = if (n / 7) % 2 == 0 { 280 } else { 320 };
+            for (i, hour_start) in
+                local_day_hours(&date).into_iter().enumerate()
+            {
+                let wh = match i {
+                    1..=3 | 18 if date == unusual => 1000,
+                    12 if date == unusual => 480,
+                    _ => normal,
+                };
+                // Data is kept by UTC hour.  In time zones that aren't a
+                // whole number of hours from UTC, use the UTC hour that
+                // starts during this local hour.
+                records.push(NetEnergyUsed {
+                    timestamp_start_utc: hour_start
+                        + chrono::Duration::minutes(59),
+                    net_used_wh: WattHours::from(wh),
+                });
+            }
+        }
+        let mut aggr = DataLoader::new();
+        aggr.load_net_usage(Source::new("test"), records.into_iter().map(Ok))
+            .unwrap();
+
+        // For each of the unusual hours, the six samples are 280, 280, 280,
+        // 320, 320, and 1000 (or 480) Wh.  The median is 300 Wh, and the
+        // median absolute deviation is 20 Wh, so any hour more than the
+        // minimum of 200 Wh above the median is unusual.  That's not true of
+        // noon.
+        let anomalies = find_anomalies(&aggr, Some(0.30));
+        assert_eq!(anomalies.len(), 2, "{:?}", anomalies);
+
+        let overnight = &anomalies[0];
+        assert_eq!(overnight.start.date(), unusual);
+        assert_eq!(overnight.start.hour(), 1);
+        assert_eq!(overnight.end - overnight.start, chrono::Duration::hours(3));
+        assert_eq!(overnight.nhours, 3);
+        assert_eq!(overnight.kind, AnomalyKind::OvernightBaseLoad);
+        assert_eq!(overnight.consumed_kwh, 3.0);
+        assert_eq!(overnight.expected_kwh, 0.9);
+        assert_eq!(overnight.excess_kwh, 2.1);
+        assert_eq!(overnight.estimated_cost, Some(0.63));
+
+        let spike = &anomalies[1];
+        assert_eq!(spike.start.date(), unusual);
+        assert_eq!(spike.start.hour(), 18);
+        assert_eq!(spike.nhours, 1);
+        assert_eq!(spike.kind, AnomalyKind::Spike);
+        assert_eq!(spike.excess_kwh, 0.7);
+        assert_eq!(spike.estimated_cost, Some(0.21));
+    }
+
+    #[test]
+    fn test_make_anomaly_kind() {
+        // Three hours of 500 Wh excess each, starting at the given hour
+        let run = |start_hour: u32| {
+            let start = chrono::Utc.ymd(2022, 6, 1).and_hms(start_hour, 0, 0);
+            (0..3)
+                .map(|i| {
+                    let start_utc = start + chrono::Duration::hours(i);
+                    let local = start_utc.naive_utc();
+                    Hour {
+                        start_utc,
+                        local,
+                        key: baseline_key(&local),
+                        consumed: 800.0,
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        let kind = |hours: &[Hour], n: usize| {
+            let with_expected: Vec<(&Hour, f64)> =
+                hours.iter().take(n).map(|h| (h, 300.0)).collect();
+            make_anomaly(&with_expected, None).map(|a| a.kind)
+        };
+        // Two of the three hours are before 6am.
+        assert_eq!(kind(&run(4), 3), Some(AnomalyKind::OvernightBaseLoad));
+        // Only one is.
+        assert_eq!(kind(&run(5), 3), Some(AnomalyKind::Elevated));
+        assert_eq!(kind(&run(12), 2), Some(AnomalyKind::Spike));
+        // A single hour 500 Wh over is just enough to report.
+        assert_eq!(kind(&run(12), 1), Some(AnomalyKind::Spike));
+        assert_eq!(kind(&[], 0), None);
+    }
+}
@@ -0,0 +1,245 @@
+//! Estimating base load (always-on consumption)
+//!
+//! Base load is what the house uses when nothing is deliberately running:
+//! refrigerators, network gear, devices on standby, etc.  We estimate it for
+//! each day (or week) as a low percentile of the hourly consumption overnight,
+//! when little else is usually on.  Using a percentile rather than the minimum
+//! keeps a single odd hour (e.g., a power outage) from throwing it off.
+//!
+//! Annotations (e.g., "unplugged the old freezer") can be attached to dates so
+//! that the report shows what changed when, and [`annotation_effects()`]
+//! compares the base load before and after each one.
+
+use crate::bucket::Bucketer;
+use crate::data_aggregator::DataLoader;
+use crate::stats;
+use chrono::NaiveDateTime;
+use chrono::Timelike;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Local hours (starting at midnight) that count as overnight
+const OVERNIGHT_HOURS: std::ops::Range<u32> = 0..6;
+
+/// Percentile of overnight hourly consumption used as the base load
+const BASE_LOAD_PERCENTILE: f64 = 10.0;
+
+/// A note about something that happened on a particular date
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Annotation {
+    pub date: chrono::NaiveDate,
+    pub note: String,
+}
+
+/// Base load for one period
+///
+/// Since each value is the energy used in one hour, watt-hours per hour are
+/// the same as average watts.
+#[derive(Debug, Serialize)]
+pub struct BaseLoad {
+    pub period_start: NaiveDateTime,
+    /// number of overnight hours of data in this period
+    pub overnight_hours: usize,
+    /// estimated base load, in watts
+    pub base_load_w: f64,
+    /// median overnight consumption, in watts
+    pub overnight_median_w: f64,
+    /// change in base load from the previous period, in watts
+    pub change_w: Option<f64>,
+    /// notes for dates in this period (separated by "; ")
+    pub annotations: String,
+}
+
+/// Estimates the base load for each period chosen by `bucketer` (e.g.,
+/// [`crate::bucket::Days`])
+///
+/// Only hours with net usage data (and complete production data) are used.
+/// `annotations` are attached to the periods containing their dates.
+pub fn base_load<B>(
+    aggr: &DataLoader,
+    bucketer: B,
+    annotations: &[Annotation],
+) -> Vec<BaseLoad>
+where
+    B: Bucketer<Key = NaiveDateTime>,
+{
+    let mut overnight: BTreeMap<NaiveDateTime, Vec<f64>> = BTreeMap::new();
+    let hours = aggr.aggregate(|t: &chrono::DateTime<chrono::Utc>| Some(*t));
+    for (hour_start, totals) in hours {
+        let local = hour_start.with_timezone(&chrono::Local).naive_local();
+        if totals.net_usage_hours == 0
+            || totals.production_partial_hours > 0
+            || !OVERNIGHT_HOURS.contains(&local.hour())
+        {
+            continue;
+        }
+        if let Some(period_start) = bucketer.bucket(&hour_start) {
+            overnight
+                .entry(period_start)
+                .or_default()
+                .push(totals.consumed.as_wh() as f64);
+        }
+    }
+
+    let mut rv: Vec<BaseLoad> = Vec::new();
+    let mut periods = overnight.into_iter().peekable();
+    while let Some((period_start, mut values)) = periods.next() {
+        stats::sort(&mut values);
+        let base_load_w =
+            round(stats::percentile(&values, BASE_LOAD_PERCENTILE));
+        // Annotations go with the period containing their date.  The last
+        // period also gets any annotations after it.
+        let period_end = periods.peek().map(|(next, _)| next.date());
+        let notes: Vec<&str> = annotations
+            .iter()
+            .filter(|a| {
+                a.date >= period_start.date()
+                    && !matches!(period_end, Some(end) if a.date >= end)
+            })
+            .map(|a| a.note.as_str())
+            .collect();
+        rv.push(BaseLoad {
+            period_start,
+            overnight_hours: values.len(),
+            base_load_w,
+            overnight_median_w: round(stats::percentile(&values, 50.0)),
+            change_w: rv
+                .last()
+                .map(|prev| round(base_load_w - prev.base_load_w)),
+            annotations: notes.join("; "),
+        });
+    }
+    rv
+}
+
+/// Compares the base load before and after an annotated change
+#[derive(Debug)]
+pub struct AnnotationEffect<'a> {
+    pub annotation: &'a Annotation,
+    /// mean base load (in watts) over the periods before the annotation
+    pub before_w: Option<f64>,
+    /// mean base load (in watts) over the periods on or after the annotation
+    pub after_w: Option<f64>,
+}
+
+/// For each annotation, compares the mean base load over the `window` before
+/// its date with the mean over the `window` starting on its date
+pub fn annotation_effects<'a>(
+    base_loads: &[BaseLoad],
+    annotations: &'a [Annotation],
+    window: chrono::Duration,
+) -> Vec<AnnotationEffect<'a>> {
+    let mean_between = |start: chrono::NaiveDate, end: chrono::NaiveDate| {
+        let values: Vec<f64> = base_loads
+            .iter()
+            .filter(|b| {
+                b.period_start.date() >= start && b.period_start.date() < end
+            })
+            .map(|b| b.base_load_w)
+            .collect();
+        (!values.is_empty()).then(|| round(stats::mean(&values)))
+    };
+    annotations
+        .iter()
+        .map(|annotation| AnnotationEffect {
+            annotation,
+            before_w: mean_between(annotation.date - window, annotation.date),
+            after_w: mean_between(annotation.date, annotation.date + window),
+        })
+        .collect()
+}
+
+/// Rounds to tenths of a watt
+fn round(value: f64) -> f64 {
+    (value * 10.0).round() / 10.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket;
+    use crate::common::local_day_hours;
+    use crate::common::NetEnergyUsed;
+    use crate::common::WattHours;
+    use crate::data_aggregator::Source;
+
+    fn june(day: u32) -> chrono::NaiveDate {
+        chrono::NaiveDate::from_ymd(2022, 6, day)
+    }
+
+    #[test]
+    fn test_base_load() {
+        // Overnight (midnight to 6am), June 1 uses 100, 110, ..., 150 Wh each
+        // hour, and June 2 and 3 use 60, 70, ..., 110 Wh.  Daytime use is
+        // much higher, but doesn't count.
+        let mut records = Vec::new();
+        for (day, overnight_start) in [(1, 100), (2, 60), (3, 60)] {
+            for (i, hour_start) in
+                local_day_hours(&june(day)).into_iter().enumerate()
+            {
+                let wh =
+                    if i < 6 { overnight_start + 10 * i as i32 } else { 1000 };
+                // Data is kept by UTC hour.  In time zones that aren't a
+                // whole number of hours from UTC, use the UTC hour that
+                // starts during this local hour.
+                records.push(NetEnergyUsed {
+                    timestamp_start_utc: hour_start
+                        + chrono::Duration::minutes(59),
+                    net_used_wh: WattHours::from(wh),
+                });
+            }
+        }
+        let mut aggr = DataLoader::new();
+        aggr.load_net_usage(Source::new("test"), records.into_iter().map(Ok))
+            .unwrap();
+        let annotations = [
+            Annotation { date: june(2), note: String::from("freezer") },
+            Annotation { date: june(10), note: String::from("later") },
+        ];
+
+        // The 10th percentile of six values is halfway between the lowest
+        // two, and the median is halfway between the middle two.
+        let base_loads = base_load(&aggr, bucket::Days, &annotations);
+        let got: Vec<_> = base_loads
+            .iter()
+            .map(|b| {
+                (
+                    b.period_start,
+                    b.overnight_hours,
+                    b.base_load_w,
+                    b.overnight_median_w,
+                    b.change_w,
+                    b.annotations.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                (june(1).and_hms(0, 0, 0), 6, 105.0, 125.0, None, ""),
+                (
+                    june(2).and_hms(0, 0, 0),
+                    6,
+                    65.0,
+                    85.0,
+                    Some(-40.0),
+                    "freezer"
+                ),
+                (june(3).and_hms(0, 0, 0), 6, 65.0, 85.0, Some(0.0), "later"),
+            ]
+        );
+
+        let effects = annotation_effects(
+            &base_loads,
+            &annotations,
+            chrono::Duration::days(2),
+        );
+        assert_eq!(effects.len(), 2);
+        assert_eq!(effects[0].before_w, Some(105.0));
+        assert_eq!(effects[0].after_w, Some(65.0));
+        assert_eq!(effects[1].before_w, None);
+        assert_eq!(effects[1].after_w, None);
+    }
+}
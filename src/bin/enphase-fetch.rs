@@ -1,25 +1,32 @@
 //! Basic tool for fetching data about solar energy system from Enlighten API
 
+use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Context;
+use chrono::Datelike;
 use chrono::TimeZone;
+use chrono::Utc;
+use home_energy_tools::common::local_midnight;
 use home_energy_tools::common::EnergyProduced;
+use home_energy_tools::common::Quality;
+use home_energy_tools::common::SolarProductionReader;
 use home_energy_tools::common::WattHours;
-use openapi::{
-    self,
-    apis::configuration::{ApiKey, Configuration},
-};
+use home_energy_tools::enlighten;
+use home_energy_tools::enlighten_error::ApiError;
+use home_energy_tools::enlighten_replay;
+use home_energy_tools::fetch_checkpoint::Checkpoint;
+use home_energy_tools::fetch_log::DayFetch;
+use home_energy_tools::fetch_log::FetchLog;
+use std::fs;
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
 use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
 use std::time::Duration;
 use structopt::StructOpt;
 
-/// Describes the config for this tool
-#[derive(serde::Deserialize)]
-struct Config {
-    enlighten_key: String,
-    enlighten_user_id: String,
-}
-
 #[derive(Debug, StructOpt)]
 #[structopt(name = "enphase-fetch")]
 #[structopt(no_version)]
@@ -27,8 +34,59 @@ struct Config {
 struct Args {
     #[structopt(default_value = "enphase_creds.toml", long)]
     creds_file: PathBuf,
-    #[structopt(default_value = "2021-11-06", long)]
-    start_date: chrono::NaiveDate,
+    /// first (local) day to fetch [default: 2021-11-06]
+    #[structopt(long)]
+    start_date: Option<chrono::NaiveDate>,
+    /// last (local) day to fetch [default: yesterday]
+    #[structopt(long)]
+    end_date: Option<chrono::NaiveDate>,
+    /// fetch this many days, ending with --end-date
+    #[structopt(long, conflicts_with = "start-date")]
+    days: Option<u32>,
+    /// fetch the seven days ending yesterday
+    #[structopt(
+        long,
+        conflicts_with_all = &["start-date", "end-date", "days", "last-month"]
+    )]
+    last_week: bool,
+    /// fetch the previous calendar month
+    #[structopt(long, conflicts_with_all = &["start-date", "end-date", "days"])]
+    last_month: bool,
+    /// authorization code from Enphase (v4 API only; needed on first use or
+    /// if the saved tokens stop working)
+    #[structopt(long)]
+    oauth_code: Option<String>,
+    /// file recording which days have been fetched and whether they were
+    /// complete
+    #[structopt(default_value = "local-data/fetch-log.csv", long)]
+    fetch_log: PathBuf,
+    /// with --output-dir, fetch days even if their files already have complete
+    /// data
+    #[structopt(long)]
+    refetch: bool,
+    /// write data into files in this directory (rather than to stdout)
+    #[structopt(long)]
+    output_dir: Option<PathBuf>,
+    /// with --output-dir, write one file per "day" or per "month"
+    #[structopt(default_value = "day", long)]
+    split: Split,
+    /// write data to this file (rather than to stdout)
+    #[structopt(long, conflicts_with = "output-dir")]
+    output: Option<PathBuf>,
+    /// file recording how far the current run has gotten
+    #[structopt(default_value = "local-data/fetch-checkpoint.toml", long)]
+    checkpoint: PathBuf,
+    /// continue a previous run that failed or was interrupted, using the
+    /// output file or directory that it was using
+    #[structopt(long)]
+    resume: bool,
+    /// save raw API responses into this directory
+    #[structopt(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+    /// serve API responses from this directory (as saved by --record)
+    /// instead of talking to Enlighten
+    #[structopt(long)]
+    replay: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -38,74 +96,599 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 async fn fetch_data(args: &Args) -> Result<(), anyhow::Error> {
-    let config: Config = toml::from_str(
-        &std::fs::read_to_string(&args.creds_file).with_context(|| {
-            format!("open creds file {:?}", args.creds_file.display())
-        })?,
-    )
-    .with_context(|| format!("parsing creds file {:?}", args.creds_file))?;
-
-    let enlighten_config = Configuration {
-        base_path: String::from("https://api.enphaseenergy.com/api/v2"),
-        user_agent: None,
-        client: reqwest::Client::new(),
-        basic_auth: None,
-        oauth_access_token: None,
-        bearer_access_token: None,
-        api_key: Some(ApiKey { prefix: None, key: config.enlighten_key }),
+    let config = enlighten::Config::load(&args.creds_file)?;
+    let client = match (&args.record, &args.replay) {
+        (_, Some(dir)) => {
+            let stand_in = enlighten_replay::StandIn::start(
+                enlighten_replay::Mode::Replay { dir: dir.clone() },
+            )
+            .await?;
+            eprintln!("replaying responses from {:?}", dir.display());
+            enlighten::Api::for_replay(&config, stand_in.base_url())?
+        }
+        (Some(dir), None) => {
+            let mut client = enlighten::Api::new(
+                &config,
+                &args.creds_file,
+                args.oauth_code.as_deref(),
+            )
+            .await?;
+            let stand_in = enlighten_replay::StandIn::start(
+                enlighten_replay::Mode::Record {
+                    dir: dir.clone(),
+                    upstream: String::from(enlighten_replay::ENLIGHTEN_URL),
+                },
+            )
+            .await?;
+            eprintln!("recording responses into {:?}", dir.display());
+            client.set_base_url(stand_in.base_url());
+            client
+        }
+        (None, None) => {
+            enlighten::Api::new(
+                &config,
+                &args.creds_file,
+                args.oauth_code.as_deref(),
+            )
+            .await?
+        }
     };
+    let system_id = client.system_id().await?;
 
-    use openapi::apis::default_api as enlighten;
-    let user_id = &config.enlighten_user_id;
-    let response = enlighten::systems(
-        &enlighten_config,
-        user_id,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-    )
-    .await
-    .context("listing systems")?;
-
-    if response.systems.len() != 1 {
+    let mut fetch_log = FetchLog::load(&args.fetch_log)?;
+    let mut checkpoint = if args.resume {
+        let checkpoint = Checkpoint::load(&args.checkpoint)?;
+        if checkpoint.system_id != system_id {
+            bail!(
+                "checkpoint {:?} is for system {}, not {}",
+                args.checkpoint.display(),
+                checkpoint.system_id,
+                system_id
+            );
+        }
+        check_resumed_output("--output", &args.output, &checkpoint.output)?;
+        check_resumed_output(
+            "--output-dir",
+            &args.output_dir,
+            &checkpoint.output_dir,
+        )?;
+        // Relative ranges (and the default end date) depend on what day it
+        // is, so we only resolve the dates if the user gave some.  They must
+        // match what the previous run was doing.
+        if has_date_args(args) {
+            let (first_date, last_date) =
+                resolve_dates(args, &client, system_id).await?;
+            if (first_date, last_date)
+                != (checkpoint.start_date, checkpoint.end_date)
+            {
+                bail!(
+                    "cannot resume with UTC days {} through {}: previous run \
+                    was fetching {} through {}",
+                    first_date,
+                    last_date.pred(),
+                    checkpoint.start_date,
+                    checkpoint.end_date.pred()
+                );
+            }
+        }
+        eprintln!(
+            "resuming run that started at {} from {} (through {})",
+            checkpoint.start_date,
+            checkpoint.next_date(),
+            checkpoint.end_date.pred()
+        );
+        checkpoint
+    } else {
+        let (first_date, last_date) =
+            resolve_dates(args, &client, system_id).await?;
+        Checkpoint {
+            system_id,
+            start_date: first_date,
+            end_date: last_date,
+            last_completed: None,
+            output: args.output.clone(),
+            output_dir: args.output_dir.clone(),
+        }
+    };
+    checkpoint.save(&args.checkpoint)?;
+    let last_date = checkpoint.end_date;
+
+    // There's no need to avoid the rate limit when replaying.
+    let pace = args.replay.is_none();
+    let mut fetcher = Fetcher::new(&client, system_id, pace);
+    match checkpoint.output_dir.clone() {
+        None => {
+            fetch_to_file(
+                args,
+                &mut fetcher,
+                &mut fetch_log,
+                &mut checkpoint,
+                last_date,
+            )
+            .await?
+        }
+        Some(output_dir) => {
+            fetch_to_dir(
+                args,
+                &output_dir,
+                &mut fetcher,
+                &mut fetch_log,
+                &mut checkpoint,
+                last_date,
+            )
+            .await?
+        }
+    }
+
+    Checkpoint::remove(&args.checkpoint)
+}
+
+/// Returns whether the user gave any options selecting which days to fetch
+fn has_date_args(args: &Args) -> bool {
+    args.start_date.is_some()
+        || args.end_date.is_some()
+        || args.days.is_some()
+        || args.last_week
+        || args.last_month
+}
+
+/// Returns the range of UTC days `[first, last)` to fetch in order to cover
+/// the local days that the user asked for
+async fn resolve_dates(
+    args: &Args,
+    client: &enlighten::Api,
+    system_id: i32,
+) -> Result<(chrono::NaiveDate, chrono::NaiveDate), anyhow::Error> {
+    let (first_local, last_local) =
+        requested_dates(args, &chrono::Local::today().naive_local())?;
+
+    // The system usually became operational partway through a day.  That
+    // day is fine to ask for.  We just won't get data from before then.
+    let operational_at = client.operational_at(system_id).await?;
+    let operational_local =
+        operational_at.with_timezone(&chrono::Local).date().naive_local();
+    if first_local < operational_local {
         bail!(
-            "expected exactly one system, but found {}",
-            response.systems.len()
+            "start date {} is before the system became operational ({}).  \
+            Use a later start date.",
+            first_local,
+            operational_at.with_timezone(&chrono::Local)
         );
     }
 
-    let system_id = response.systems[0].system_id;
-    let mut date = args.start_date.clone();
-    let last_date = chrono::NaiveDateTime::from_timestamp(
-        chrono::Utc::now().timestamp(),
-        0,
-    )
-    .date();
-    let mut writer = csv::Writer::from_writer(std::io::stdout());
+    // The first local day may start on the UTC day before the system became
+    // operational (east of UTC).  There's no data to fetch for that one.
+    let (first_date, last_date) = utc_dates_covering(&first_local, &last_local);
+    let first_date = first_date.max(operational_at.date().naive_utc());
+    eprintln!(
+        "fetching local days {} through {} (UTC days {} through {})",
+        first_local,
+        last_local,
+        first_date,
+        last_date.pred()
+    );
+    Ok((first_date, last_date))
+}
+
+/// Returns the first and last local days (inclusive) that the user asked us
+/// to fetch, given today's (local) date
+fn requested_dates(
+    args: &Args,
+    today: &chrono::NaiveDate,
+) -> Result<(chrono::NaiveDate, chrono::NaiveDate), anyhow::Error> {
+    let yesterday = today.pred();
+    let (first, last) = if args.last_week {
+        (yesterday - chrono::Duration::days(6), yesterday)
+    } else if args.last_month {
+        let this_month =
+            chrono::NaiveDate::from_ymd(today.year(), today.month(), 1);
+        let last = this_month.pred();
+        (chrono::NaiveDate::from_ymd(last.year(), last.month(), 1), last)
+    } else {
+        let last = args.end_date.unwrap_or(yesterday);
+        let first = match args.days {
+            Some(0) => bail!("--days must be at least 1"),
+            Some(ndays) => last - chrono::Duration::days(i64::from(ndays) - 1),
+            None => args
+                .start_date
+                .unwrap_or_else(|| chrono::NaiveDate::from_ymd(2021, 11, 6)),
+        };
+        (first, last)
+    };
+
+    if first > last {
+        bail!("start date {} is after end date {}", first, last);
+    }
+
+    Ok((first, last))
+}
+
+/// Returns the range of UTC days `[first, last)` that we need to fetch in
+/// order to cover the local days `first_local` through `last_local`
+///
+/// Enlighten gives us data one UTC day at a time, but reports are in local
+/// time.  Unless we're in UTC, each local day overlaps two UTC days.  We never
+/// go past today (in UTC).  If that means we fetch part of a UTC day that's
+/// not over yet, it'll be recorded as incomplete in the fetch log and
+/// refetched next time.
+fn utc_dates_covering(
+    first_local: &chrono::NaiveDate,
+    last_local: &chrono::NaiveDate,
+) -> (chrono::NaiveDate, chrono::NaiveDate) {
+    let first = local_midnight(first_local).date().naive_utc();
+    let last_instant =
+        local_midnight(&last_local.succ()) - chrono::Duration::seconds(1);
+    let last = last_instant.date().naive_utc().succ();
+    let today = Utc::today().naive_utc();
+    if last > today.succ() {
+        eprintln!("warn: not fetching data after today ({}, in UTC)", today);
+    }
+    (first, last.min(today.succ()))
+}
+
+/// Fails if the user asked to resume a run with different output than the
+/// run used
+fn check_resumed_output(
+    option: &str,
+    requested: &Option<PathBuf>,
+    previous: &Option<PathBuf>,
+) -> Result<(), anyhow::Error> {
+    match requested {
+        Some(requested) if Some(requested) != previous.as_ref() => bail!(
+            "cannot resume with {} {:?}: previous run used {:?}",
+            option,
+            requested.display(),
+            previous
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Fetch data for dates from the checkpoint up to (but not including)
+/// `last_date` and write it to the output file (or stdout)
+///
+/// When resuming, new data is appended to the output file.
+async fn fetch_to_file(
+    args: &Args,
+    fetcher: &mut Fetcher<'_>,
+    fetch_log: &mut FetchLog,
+    checkpoint: &mut Checkpoint,
+    last_date: chrono::NaiveDate,
+) -> Result<(), anyhow::Error> {
+    // When resuming, the previous run already wrote the header row (unless
+    // it never wrote anything).  For stdout, we assume the caller is
+    // appending to the same file.
+    let output_file = checkpoint.output.clone();
+    let (output, has_headers): (Box<dyn Write>, bool) = match &output_file {
+        None => (Box::new(std::io::stdout()), !args.resume),
+        Some(path) if args.resume => {
+            let file = fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)
+                .with_context(|| format!("open {:?}", path.display()))?;
+            let metadata = file
+                .metadata()
+                .with_context(|| format!("stat {:?}", path.display()))?;
+            (Box::new(file), metadata.len() == 0)
+        }
+        Some(path) => {
+            let file = fs::File::create(path)
+                .with_context(|| format!("create {:?}", path.display()))?;
+            (Box::new(file), true)
+        }
+    };
+    let mut writer =
+        csv::WriterBuilder::new().has_headers(has_headers).from_writer(output);
+
+    let mut date = checkpoint.next_date();
     while date < last_date {
-        eprintln!("{}: date: {}", chrono::Utc::now(), date);
         let next_date = date.succ();
-        let stats = enlighten::stats(
-            &enlighten_config,
-            user_id,
+        // Whatever we fetched for this day in earlier runs went to some other
+        // file (or stdout), not this one.  (When resuming, days this run
+        // already wrote are before the checkpoint.)  So we always fetch it.
+        if needs_fetch(args, fetch_log, output_file.as_deref(), &date, false) {
+            let (records, day_fetch) =
+                match fetcher.fetch_day(&date, output_file.as_deref()).await? {
+                    Some(result) => result,
+                    None => {
+                        writer.flush().context("flushing writer")?;
+                        checkpoint.save(&args.checkpoint)?;
+                        bail!("interrupted (rerun with --resume to continue)");
+                    }
+                };
+            for record in records {
+                writer.serialize(record).context("writing record")?;
+            }
+            writer.flush().context("flushing writer")?;
+            fetch_log.record(day_fetch)?;
+        }
+
+        checkpoint.last_completed = Some(date);
+        checkpoint.save(&args.checkpoint)?;
+        date = next_date;
+    }
+
+    writer.flush().context("flushing writer")?;
+
+    Ok(())
+}
+
+/// Describes how data is divided into files in `--output-dir` mode
+#[derive(Clone, Copy, Debug)]
+enum Split {
+    Day,
+    Month,
+}
+
+impl FromStr for Split {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Split::Day),
+            "month" => Ok(Split::Month),
+            _ => bail!("expected \"day\" or \"month\""),
+        }
+    }
+}
+
+impl Split {
+    /// Returns the first date after the file period containing `date`
+    fn next_period(&self, date: &chrono::NaiveDate) -> chrono::NaiveDate {
+        match self {
+            Split::Day => date.succ(),
+            Split::Month => {
+                let (year, month) = if date.month() == 12 {
+                    (date.year() + 1, 1)
+                } else {
+                    (date.year(), date.month() + 1)
+                };
+                chrono::NaiveDate::from_ymd(year, month, 1)
+            }
+        }
+    }
+
+    /// Returns the name of the file containing data for `date`
+    fn filename(&self, date: &chrono::NaiveDate) -> String {
+        match self {
+            Split::Day => format!("enphase-{}.csv", date.format("%Y-%m-%d")),
+            Split::Month => format!("enphase-{}.csv", date.format("%Y-%m")),
+        }
+    }
+}
+
+/// Fetch data for dates from the checkpoint up to (but not including)
+/// `last_date` and write it into one file per day or month in `output_dir`
+///
+/// Each file is written to a temporary file and then renamed into place so
+/// that a crash never leaves a truncated file behind.  Days that already have
+/// complete data in an existing file are not fetched again.
+async fn fetch_to_dir(
+    args: &Args,
+    output_dir: &Path,
+    fetcher: &mut Fetcher<'_>,
+    fetch_log: &mut FetchLog,
+    checkpoint: &mut Checkpoint,
+    last_date: chrono::NaiveDate,
+) -> Result<(), anyhow::Error> {
+    fs::create_dir_all(output_dir)
+        .with_context(|| format!("mkdir {:?}", output_dir.display()))?;
+
+    let mut period_start = checkpoint.next_date();
+    while period_start < last_date {
+        let period_end = args.split.next_period(&period_start).min(last_date);
+        let path = output_dir.join(args.split.filename(&period_start));
+        let file_exists = path.exists();
+        let mut dates = Vec::new();
+        let mut date = period_start;
+        while date < period_end {
+            if needs_fetch(args, fetch_log, Some(&path), &date, file_exists) {
+                dates.push(date);
+            }
+            date = date.succ();
+        }
+        period_start = period_end;
+
+        if dates.is_empty() {
+            checkpoint.last_completed = Some(period_end.pred());
+            checkpoint.save(&args.checkpoint)?;
+            continue;
+        }
+
+        // Keep whatever data we already have for the days that we're not
+        // refetching.
+        let mut records = Vec::new();
+        if file_exists {
+            let file = fs::File::open(&path)
+                .with_context(|| format!("open {:?}", path.display()))?;
+            for record in SolarProductionReader::new(file).records() {
+                let record = record
+                    .with_context(|| format!("read {:?}", path.display()))?;
+                if !dates.contains(&record.datetime_utc.date().naive_utc()) {
+                    records.push(record);
+                }
+            }
+        }
+
+        let mut day_fetches = Vec::new();
+        for date in &dates {
+            // If we're interrupted, we discard whatever we've fetched for this
+            // file.  The checkpoint still reflects the last file we wrote.
+            let (day_records, day_fetch) =
+                match fetcher.fetch_day(date, Some(&path)).await? {
+                    Some(result) => result,
+                    None => {
+                        checkpoint.save(&args.checkpoint)?;
+                        bail!("interrupted (rerun with --resume to continue)");
+                    }
+                };
+            records.extend(day_records);
+            day_fetches.push(day_fetch);
+        }
+
+        records.sort_by_key(|r| r.datetime_utc);
+        write_atomically(&path, &records)?;
+        eprintln!("wrote {:?}", path.display());
+
+        // Only record these fetches once the data is safely on disk.
+        for day_fetch in day_fetches {
+            fetch_log.record(day_fetch)?;
+        }
+        checkpoint.last_completed = Some(period_end.pred());
+        checkpoint.save(&args.checkpoint)?;
+    }
+
+    Ok(())
+}
+
+/// Writes `records` to a temporary file and then renames it to `path`
+fn write_atomically(
+    path: &Path,
+    records: &[EnergyProduced],
+) -> Result<(), anyhow::Error> {
+    // Use a dot-file that doesn't end in ".csv" so that "report" doesn't load
+    // it if we crash before renaming it.
+    let tmp_path = path.with_file_name(format!(
+        ".{}.tmp",
+        path.file_name().unwrap().to_string_lossy()
+    ));
+    let file = fs::File::create(&tmp_path)
+        .with_context(|| format!("create {:?}", tmp_path.display()))?;
+    let mut writer = csv::Writer::from_writer(file);
+    for record in records {
+        writer
+            .serialize(record)
+            .with_context(|| format!("write {:?}", tmp_path.display()))?;
+    }
+    let file = writer
+        .into_inner()
+        .map_err(|e| anyhow!("flush {:?}: {}", tmp_path.display(), e))?;
+    file.sync_all()
+        .with_context(|| format!("fsync {:?}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path).with_context(|| {
+        format!("rename {:?} to {:?}", tmp_path.display(), path.display())
+    })
+}
+
+/// Returns whether we need to fetch data for `date`, based on what we've
+/// already fetched
+///
+/// `output` is the file we're writing to (none for stdout).  `have_file`
+/// indicates whether the data we previously fetched into it is still around.
+fn needs_fetch(
+    args: &Args,
+    fetch_log: &FetchLog,
+    output: Option<&Path>,
+    date: &chrono::NaiveDate,
+    have_file: bool,
+) -> bool {
+    match fetch_log.get(output, date) {
+        Some(previous) if previous.complete && have_file && !args.refetch => {
+            eprintln!("{}: date: {} (already complete)", Utc::now(), date);
+            false
+        }
+        Some(previous) if !previous.complete => {
+            eprintln!(
+                "{}: date: {} (refetching: previous fetch at {} only had \
+                data through {})",
+                Utc::now(),
+                date,
+                previous.fetched_at,
+                previous.last_energy_at
+            );
+            true
+        }
+        _ => {
+            eprintln!("{}: date: {}", Utc::now(), date);
+            true
+        }
+    }
+}
+
+/// Fetches one day of data at a time, pacing requests to stay under the
+/// Enlighten rate limit
+struct Fetcher<'a> {
+    client: &'a enlighten::Api,
+    system_id: i32,
+    /// whether to pause between requests (and before retries)
+    pace: bool,
+    nrequests: usize,
+    /// completes when the user hits Ctrl-C
+    ///
+    /// We create this once up front so that an interrupt that arrives between
+    /// requests isn't lost.
+    interrupted: Pin<Box<dyn Future<Output = std::io::Result<()>>>>,
+}
+
+impl<'a> Fetcher<'a> {
+    fn new(
+        client: &'a enlighten::Api,
+        system_id: i32,
+        pace: bool,
+    ) -> Fetcher<'a> {
+        Fetcher {
+            client,
             system_id,
-            Some(date.and_hms(0, 0, 0).timestamp()),
-            Some(next_date.and_hms(0, 0, 0).timestamp()),
-        )
-        .await
-        .with_context(|| format!("fetch stats for {}", date))?;
+            pace,
+            nrequests: 0,
+            interrupted: Box::pin(tokio::signal::ctrl_c()),
+        }
+    }
+
+    /// Fetch the records for one (UTC) day that will be written to `output`
+    /// (none for stdout)
+    ///
+    /// The returned [`DayFetch`] should be recorded in the fetch log once the
+    /// records have been saved.  Returns `None` if the user hit Ctrl-C before
+    /// we finished.
+    async fn fetch_day(
+        &mut self,
+        date: &chrono::NaiveDate,
+        output: Option<&Path>,
+    ) -> Result<Option<(Vec<EnergyProduced>, DayFetch)>, anyhow::Error> {
+        /*
+         * We only get 10 requests per minute.  Sleep 6 seconds between
+         * requests.  (TODO We could do better here by parsing the error
+         * responses.)
+         */
+        let delay = self.pace && self.nrequests > 0;
+        self.nrequests += 1;
+        let client = self.client;
+        let system_id = self.system_id;
+        let pace = self.pace;
+        let fetch = async move {
+            if delay {
+                tokio::time::sleep(Duration::from_secs(7)).await;
+            }
+            fetch_stats(client, system_id, date, pace).await
+        };
+
+        let stats = tokio::select! {
+            stats = fetch => {
+                stats.with_context(|| format!("fetch stats for {}", date))?
+            }
+            _ = &mut self.interrupted => {
+                eprintln!("interrupted");
+                return Ok(None);
+            }
+        };
+
+        let day_fetch = DayFetch::new(
+            *date,
+            output,
+            Utc.timestamp(stats.meta.last_energy_at, 0),
+            stats.intervals.len(),
+        );
+        if !day_fetch.complete {
+            eprintln!(
+                "warn: data for {} is incomplete (Enlighten only has data \
+                through {}).  It will be refetched next time.",
+                date, day_fetch.last_energy_at
+            );
+        }
 
+        let mut records = Vec::with_capacity(stats.intervals.len());
         for data in stats.intervals {
             let data_end_time = chrono::Utc.timestamp(data.end_at, 0);
             let data_start_time = data_end_time
@@ -113,27 +696,73 @@ async fn fetch_data(args: &Args) -> Result<(), anyhow::Error> {
             let date_start_local =
                 data_start_time.with_timezone(&chrono::Local);
             let energy_wh = WattHours::try_from(data.enwh)?;
-            writer
-                .serialize(EnergyProduced {
-                    datetime_utc: data_start_time,
-                    datetime_local: date_start_local,
-                    energy_wh,
-                })
-                .context("writing record")?;
+            // If none of the microinverters reported, that's most likely
+            // because it's dark, not because of a problem.  (Whole-system
+            // outages show up in the fetch log instead.)
+            let quality = if data.devices_reporting > 0
+                && data.devices_reporting < stats.total_devices
+            {
+                Quality::Partial
+            } else {
+                Quality::Ok
+            };
+            records.push(EnergyProduced {
+                datetime_utc: data_start_time,
+                datetime_local: date_start_local,
+                energy_wh,
+                devices_reporting: Some(data.devices_reporting),
+                quality: Some(quality),
+            });
         }
 
-        writer.flush().context("flushing writer")?;
-        date = next_date;
-
-        /*
-         * We only get 10 requests per minute.  Sleep 6 seconds between
-         * requests.  (TODO We could do better here by parsing the error
-         * responses.)
-         */
-        tokio::time::sleep(Duration::from_secs(7)).await;
+        Ok(Some((records, day_fetch)))
     }
+}
 
-    writer.flush().context("flushing writer")?;
+/// Maximum number of times we'll retry a request that failed in a way that
+/// seems transient
+const MAX_RETRIES: usize = 5;
 
-    Ok(())
+/// Fetch stats for the given date, retrying after transient failures (like
+/// being rate-limited)
+///
+/// If `pace` is false, we retry immediately rather than waiting.
+async fn fetch_stats(
+    client: &enlighten::Api,
+    system_id: i32,
+    date: &chrono::NaiveDate,
+    pace: bool,
+) -> Result<openapi::models::StatsResponse, anyhow::Error> {
+    let mut nretries = 0;
+    loop {
+        let error = match client
+            .stats(system_id, date.and_hms(0, 0, 0).timestamp())
+            .await
+        {
+            Ok(stats) => return Ok(stats),
+            Err(error) => error,
+        };
+
+        let retry_after = match error.downcast_ref::<ApiError>() {
+            Some(api_error) if nretries < MAX_RETRIES => {
+                api_error.retry_after()
+            }
+            _ => None,
+        };
+        match retry_after {
+            None => return Err(error),
+            Some(delay) => {
+                nretries += 1;
+                eprintln!(
+                    "{}: {:#} (will retry in {:?})",
+                    chrono::Utc::now(),
+                    error,
+                    delay
+                );
+                if pace {
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
 }
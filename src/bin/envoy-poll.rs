@@ -0,0 +1,160 @@
+//! Sample production (and consumption) from an Envoy on the local network
+//!
+//! The Envoy only reports lifetime energy counters, so we poll it and emit the
+//! energy produced between consecutive samples.  Output uses the same CSV
+//! format as `enphase-fetch`, so the result can go straight into
+//! `local-data/production`.
+
+use anyhow::bail;
+use anyhow::Context;
+use home_energy_tools::envoy_local::CounterSample;
+use home_energy_tools::envoy_local::CounterTracker;
+use home_energy_tools::envoy_local::EnvoyClient;
+use home_energy_tools::envoy_local::Measurement;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "envoy-poll")]
+#[structopt(no_version)]
+#[structopt(
+    about = "sample production and consumption from an Envoy on the local \
+    network"
+)]
+struct Args {
+    /// base URL of the Envoy
+    #[structopt(default_value = "https://envoy.local", long)]
+    envoy_url: String,
+    /// file containing the access token (required by newer Envoy firmware)
+    #[structopt(long)]
+    token_file: Option<PathBuf>,
+    /// seconds between samples
+    #[structopt(default_value = "60", long)]
+    interval_secs: u64,
+    /// stop after this many samples (default: run until interrupted)
+    #[structopt(long)]
+    count: Option<u64>,
+    /// also record consumption (in the same format) to this file
+    #[structopt(long)]
+    consumption_output: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::from_args();
+    Ok(poll(&args).await?)
+}
+
+async fn poll(args: &Args) -> Result<(), anyhow::Error> {
+    if args.interval_secs == 0 {
+        bail!("--interval-secs must be positive");
+    }
+
+    let token = match &args.token_file {
+        Some(path) => Some(
+            std::fs::read_to_string(path)
+                .with_context(|| format!("read {:?}", path.display()))?
+                .trim()
+                .to_owned(),
+        ),
+        None => None,
+    };
+    let client = EnvoyClient::new(&args.envoy_url, token)?;
+
+    let mut production_writer = csv::Writer::from_writer(std::io::stdout());
+    let mut consumption_writer = match &args.consumption_output {
+        Some(path) => {
+            // Only write the CSV header if we're starting a new file.
+            let write_header = !path.exists();
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("open {:?}", path.display()))?;
+            Some(
+                csv::WriterBuilder::new()
+                    .has_headers(write_header)
+                    .from_writer(file),
+            )
+        }
+        None => None,
+    };
+
+    let mut production = CounterTracker::new();
+    let mut consumption = CounterTracker::new();
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(args.interval_secs));
+    let mut nsamples = 0;
+    loop {
+        if let Some(count) = args.count {
+            if nsamples >= count {
+                break;
+            }
+        }
+
+        interval.tick().await;
+        nsamples += 1;
+        let response = match client.production().await {
+            Ok(r) => r,
+            Err(error) => {
+                // Don't give up just because the Envoy is briefly unreachable.
+                // We'll pick up the missed energy on the next sample.
+                eprintln!("warn: {:#}", error);
+                continue;
+            }
+        };
+
+        match response.produced() {
+            Some(m) => sample(
+                "production",
+                &mut production,
+                m,
+                &mut production_writer,
+            )?,
+            None => eprintln!("warn: Envoy reported no production data"),
+        }
+
+        if let Some(writer) = &mut consumption_writer {
+            match response.consumed() {
+                Some(m) => sample("consumption", &mut consumption, m, writer)?,
+                None => eprintln!("warn: Envoy reported no consumption data"),
+            }
+        }
+    }
+
+    production_writer.flush().context("flushing production output")?;
+    if let Some(writer) = &mut consumption_writer {
+        writer.flush().context("flushing consumption output")?;
+    }
+    Ok(())
+}
+
+/// Writes a record for each interval reported by `tracker`, given the next
+/// reading of its counter
+fn sample<W: Write>(
+    label: &str,
+    tracker: &mut CounterTracker,
+    measurement: &Measurement,
+    writer: &mut csv::Writer<W>,
+) -> Result<(), anyhow::Error> {
+    match tracker.sample(measurement) {
+        CounterSample::First | CounterSample::Stale => Ok(()),
+        CounterSample::WentBackwards { start, from_wh, to_wh } => {
+            eprintln!(
+                "warn: {} counter went backwards (from {} Wh to {} Wh); \
+                skipping interval starting at {}",
+                label, from_wh, to_wh, start
+            );
+            Ok(())
+        }
+        CounterSample::Energy(record) => {
+            writer
+                .serialize(record)
+                .with_context(|| format!("writing {} record", label))?;
+            writer.flush().with_context(|| format!("flushing {} output", label))
+        }
+    }
+}
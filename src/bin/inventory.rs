@@ -0,0 +1,140 @@
+//! Save a snapshot of the equipment in the solar energy system
+//!
+//! Each snapshot is compared with the previous one and any differences are
+//! recorded so that we have an audit trail of equipment changes (e.g., for
+//! warranty claims).
+
+use anyhow::Context;
+use home_energy_tools::enlighten;
+use home_energy_tools::inventory::Inventory;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "inventory")]
+#[structopt(no_version)]
+#[structopt(
+    about = "save a snapshot of system equipment and report changes since the \
+    last snapshot"
+)]
+struct Args {
+    #[structopt(default_value = "enphase_creds.toml", long)]
+    creds_file: PathBuf,
+    #[structopt(default_value = "local-data/inventory", long)]
+    output_dir: PathBuf,
+}
+
+/// Name of the file (within the output directory) that records all changes
+const CHANGES_FILENAME: &str = "changes.csv";
+
+/// Describes a record in the changes file
+#[derive(serde::Serialize)]
+struct ChangeRecord {
+    date: chrono::NaiveDate,
+    change: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::from_args();
+    Ok(inventory(&args).await?)
+}
+
+async fn inventory(args: &Args) -> Result<(), anyhow::Error> {
+    let config = enlighten::Config::load(&args.creds_file)?;
+    let client = enlighten::Api::new(&config, &args.creds_file, None).await?;
+    let system_id = client.system_id().await?;
+    let inventory = client.inventory(system_id).await?;
+
+    let output_dir = &args.output_dir;
+    fs::create_dir_all(output_dir)
+        .with_context(|| format!("mkdir {:?}", output_dir.display()))?;
+    let previous = match latest_snapshot(output_dir)? {
+        Some(path) => {
+            eprintln!("comparing with previous snapshot {:?}", path.display());
+            let file = fs::File::open(&path)
+                .with_context(|| format!("open {:?}", path.display()))?;
+            Inventory::read_csv(file)
+                .with_context(|| format!("read {:?}", path.display()))?
+        }
+        None => {
+            eprintln!("no previous snapshot found");
+            Inventory::new()
+        }
+    };
+
+    for status_change in previous.status_changes(&inventory) {
+        eprintln!("{}", status_change);
+    }
+
+    let changes = previous.diff(&inventory);
+    if changes.is_empty() {
+        eprintln!("no changes since previous snapshot");
+        return Ok(());
+    }
+
+    // Record the changes before writing the new snapshot.  If writing the
+    // snapshot fails, the next run will find the same changes again, which is
+    // better than never recording them.
+    let today = chrono::Local::today().naive_local();
+    let changes_path = output_dir.join(CHANGES_FILENAME);
+    let write_header = !changes_path.exists();
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&changes_path)
+        .with_context(|| format!("open {:?}", changes_path.display()))?;
+    let mut writer =
+        csv::WriterBuilder::new().has_headers(write_header).from_writer(file);
+    let mut stdout = std::io::stdout();
+    for change in &changes {
+        writeln!(stdout, "{}", change).context("write to stdout")?;
+        writer
+            .serialize(ChangeRecord { date: today, change: change.to_string() })
+            .with_context(|| format!("write {:?}", changes_path.display()))?;
+    }
+    writer
+        .flush()
+        .with_context(|| format!("flush {:?}", changes_path.display()))?;
+
+    // If there was already a snapshot today, it's the one we just compared
+    // against, so it's safe to replace it.
+    let snapshot_path = output_dir.join(snapshot_filename(&today));
+    let file = fs::File::create(&snapshot_path)
+        .with_context(|| format!("create {:?}", snapshot_path.display()))?;
+    inventory
+        .write_csv(file)
+        .with_context(|| format!("write {:?}", snapshot_path.display()))?;
+    eprintln!("wrote {:?}", snapshot_path.display());
+    Ok(())
+}
+
+fn snapshot_filename(date: &chrono::NaiveDate) -> String {
+    format!("inventory-{}.csv", date.format("%Y-%m-%d"))
+}
+
+/// Returns the path to the most recent snapshot in `dir`, if any
+fn latest_snapshot(dir: &Path) -> Result<Option<PathBuf>, anyhow::Error> {
+    let mut latest = None;
+    let dirents = fs::read_dir(dir)
+        .with_context(|| format!("readdir {:?}", dir.display()))?;
+    for maybe_item in dirents {
+        let item = maybe_item
+            .with_context(|| format!("readdir {:?} entry", dir.display()))?;
+        let name = item.file_name().to_string_lossy().into_owned();
+        if !name.starts_with("inventory-") || !name.ends_with(".csv") {
+            continue;
+        }
+
+        // Snapshot names sort chronologically.
+        if latest.as_ref().map(|l| name > *l).unwrap_or(true) {
+            latest = Some(name);
+        }
+    }
+
+    Ok(latest.map(|name| dir.join(name)))
+}
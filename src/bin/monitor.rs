@@ -0,0 +1,242 @@
+//! Check on the health of the solar energy system and alert if there's trouble
+//!
+//! This is intended to be run periodically (e.g., from cron).  It exits
+//! non-zero if any problem was found.
+
+use anyhow::bail;
+use anyhow::Context;
+use chrono::TimeZone;
+use chrono::Timelike;
+use home_energy_tools::alert::Alert;
+use home_energy_tools::alert::AlertSink;
+use home_energy_tools::enlighten;
+use openapi::models::envoys_response_envoys;
+use openapi::models::Status;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "monitor")]
+#[structopt(no_version)]
+#[structopt(about = "check system status and today's production and alert if \
+    something looks wrong")]
+struct Args {
+    #[structopt(default_value = "enphase_creds.toml", long)]
+    creds_file: PathBuf,
+    /// where to deliver alerts: "stdout", "webhook=URL", or
+    /// "mail-command=COMMAND" (may be specified more than once)
+    #[structopt(long = "alert", number_of_values = 1)]
+    alerts: Vec<AlertSink>,
+    /// alert if the system has not reported in this many minutes
+    #[structopt(default_value = "120", long)]
+    max_report_age_minutes: i64,
+    /// number of recent days used to compute expected production
+    #[structopt(default_value = "7", long)]
+    history_days: i64,
+    /// expected production on a clear day, in Watt-hours (if not specified,
+    /// the median of recent days' production is used)
+    #[structopt(long)]
+    expected_daily_wh: Option<i64>,
+    /// alert if production so far today is less than this fraction of what's
+    /// expected by this time of day
+    #[structopt(default_value = "0.5", long)]
+    min_production_ratio: f64,
+    /// skip the production check if we expect less than this many Watt-hours
+    /// by this time of day (avoids false alarms early in the morning)
+    #[structopt(default_value = "500", long)]
+    min_expected_wh: i64,
+    /// local hour when production is expected to start
+    #[structopt(default_value = "6", long)]
+    daylight_start_hour: u32,
+    /// local hour when production is expected to end
+    #[structopt(default_value = "18", long)]
+    daylight_end_hour: u32,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::from_args();
+    Ok(monitor(&args).await?)
+}
+
+async fn monitor(args: &Args) -> Result<(), anyhow::Error> {
+    if args.daylight_start_hour >= args.daylight_end_hour
+        || args.daylight_end_hour > 24
+    {
+        bail!("daylight hours must be in order and between 0 and 24");
+    }
+
+    let config = enlighten::Config::load(&args.creds_file)?;
+    let client = enlighten::Api::new(&config, &args.creds_file, None).await?;
+    let system_id = client.system_id().await?;
+    let problems = check_system(args, &client, system_id).await?;
+    if problems.is_empty() {
+        eprintln!("no problems found");
+        return Ok(());
+    }
+
+    let nproblems = problems.len();
+    let alert = Alert {
+        summary: format!(
+            "solar system {}: found {} problem{}",
+            system_id,
+            nproblems,
+            if nproblems == 1 { "" } else { "s" }
+        ),
+        details: problems,
+    };
+
+    let default_sinks = [AlertSink::Stdout];
+    let sinks =
+        if args.alerts.is_empty() { &default_sinks[..] } else { &args.alerts };
+    for sink in sinks {
+        // If we fail to deliver the alert one way, try the others anyway.
+        if let Err(error) = sink.deliver(&alert).await {
+            eprintln!("warn: delivering alert to {:?}: {:#}", sink, error);
+        }
+    }
+
+    bail!("{}", alert.summary);
+}
+
+/// Returns a list of problems found with the system
+async fn check_system(
+    args: &Args,
+    client: &enlighten::Api,
+    system_id: i32,
+) -> Result<Vec<String>, anyhow::Error> {
+    let mut problems = Vec::new();
+    let now = chrono::Utc::now();
+    let max_report_age = chrono::Duration::minutes(args.max_report_age_minutes);
+
+    let summary =
+        client.summary(system_id).await.context("fetching system summary")?;
+    if summary.status != Status::Normal {
+        problems.push(format!(
+            "system status is {:?} ({})",
+            summary.status.to_string(),
+            status_description(summary.status)
+        ));
+    }
+    check_report_age(
+        &mut problems,
+        "system",
+        summary.last_report_at,
+        now,
+        max_report_age,
+    );
+
+    let envoys = client.envoys(system_id).await.context("fetching envoys")?;
+    for envoy in &envoys {
+        let label = format!("Envoy {:?} ({})", envoy.name, envoy.serial_number);
+        if envoy.status != envoys_response_envoys::Status::Normal {
+            problems.push(format!("{} is not communicating", label));
+        }
+        check_report_age(
+            &mut problems,
+            &label,
+            envoy.last_report_at,
+            now,
+            max_report_age,
+        );
+    }
+
+    let expected_daily_wh = match args.expected_daily_wh {
+        Some(wh) => wh,
+        None => recent_median_production(args, client, system_id).await?,
+    };
+    let now_local = now.with_timezone(&chrono::Local);
+    let expected_so_far_wh = (expected_daily_wh as f64)
+        * daylight_fraction(
+            now_local.hour() as f64 + (now_local.minute() as f64) / 60.0,
+            args.daylight_start_hour as f64,
+            args.daylight_end_hour as f64,
+        );
+    let produced_wh = summary.energy_today as f64;
+    eprintln!(
+        "production today: {} Wh (expected about {:.0} Wh by now)",
+        produced_wh, expected_so_far_wh
+    );
+    if expected_so_far_wh >= args.min_expected_wh as f64
+        && produced_wh < args.min_production_ratio * expected_so_far_wh
+    {
+        problems.push(format!(
+            "underperforming: produced {} Wh today, but expected about {:.0} \
+            Wh by now",
+            produced_wh, expected_so_far_wh
+        ));
+    }
+
+    Ok(problems)
+}
+
+fn check_report_age(
+    problems: &mut Vec<String>,
+    label: &str,
+    last_report_at: i64,
+    now: chrono::DateTime<chrono::Utc>,
+    max_report_age: chrono::Duration,
+) {
+    let last_report = chrono::Utc.timestamp(last_report_at, 0);
+    if now - last_report > max_report_age {
+        problems.push(format!(
+            "{} last reported at {} (more than {} minutes ago)",
+            label,
+            last_report,
+            max_report_age.num_minutes()
+        ));
+    }
+}
+
+/// Returns the median daily production over the last `args.history_days`
+/// complete (local) days
+async fn recent_median_production(
+    args: &Args,
+    client: &enlighten::Api,
+    system_id: i32,
+) -> Result<i64, anyhow::Error> {
+    let today = chrono::Local::today().naive_local();
+    let start_date = today - chrono::Duration::days(args.history_days);
+    let end_date = today.pred();
+    let mut production = client
+        .daily_production(system_id, &start_date, &end_date)
+        .await
+        .with_context(|| {
+            format!("fetching production from {} to {}", start_date, end_date)
+        })?;
+    if production.is_empty() {
+        bail!("no recent production data (try --expected-daily-wh)");
+    }
+    production.sort_unstable();
+    Ok(i64::from(production[production.len() / 2]))
+}
+
+/// Returns the fraction of a day's production that we'd expect to have
+/// happened by local hour `hour` (a fractional hour of the day)
+///
+/// This models the production curve on a clear day as half a sine wave between
+/// the start and end of daylight.  That's crude, but good enough to tell "a
+/// little cloudy" from "not producing at all".
+fn daylight_fraction(hour: f64, start_hour: f64, end_hour: f64) -> f64 {
+    if hour <= start_hour {
+        return 0.0;
+    }
+    if hour >= end_hour {
+        return 1.0;
+    }
+    let t = (hour - start_hour) / (end_hour - start_hour);
+    (1.0 - (std::f64::consts::PI * t).cos()) / 2.0
+}
+
+fn status_description(status: Status) -> &'static str {
+    match status {
+        Status::Comm => "one or more Envoys are not communicating",
+        Status::Power => "there is a production issue",
+        Status::Meter => "an Envoy cannot communicate with a meter",
+        Status::MeterIssue => "a meter is reporting unusual measurements",
+        Status::Micro => "an Envoy cannot communicate with microinverters",
+        Status::Battery => "an Envoy cannot communicate with a battery",
+        Status::StorageIdle => "a battery has not changed its charge in 72h",
+        Status::Normal => "normal",
+    }
+}
@@ -2,42 +2,308 @@
 
 use anyhow::bail;
 use anyhow::Context;
-use home_energy_tools::common::SolarProductionReader;
-use home_energy_tools::data_aggregator::DataIterator;
-use home_energy_tools::data_aggregator::{DataLoader, Source};
-use home_energy_tools::pge::ElectricityUsageReader;
+use home_energy_tools::anomaly;
+use home_energy_tools::base_load;
+use home_energy_tools::bucket;
+use home_energy_tools::channel;
+use home_energy_tools::channel::ChannelKind;
+use home_energy_tools::common::local_midnight;
+use home_energy_tools::data_aggregator::ConflictPolicy;
+use home_energy_tools::data_aggregator::DataLoader;
+use home_energy_tools::data_source::Registry;
+use home_energy_tools::disaggregation;
+use home_energy_tools::ev_charging;
+use home_energy_tools::forecast::CloudCover;
+use home_energy_tools::forecast::Forecaster;
+use home_energy_tools::load_profile::load_profile;
+use home_energy_tools::report_config::ReportConfig;
+use home_energy_tools::solar_model;
+use home_energy_tools::solar_model::IrradianceData;
+use home_energy_tools::tariff::Tariff;
+use home_energy_tools::weather;
+use home_energy_tools::year_over_year;
 use std::fs;
 use std::fs::OpenOptions;
 use std::path::Path;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "report")]
 #[structopt(no_version)]
 #[structopt(about = "summarize local data")]
-struct Args {}
+struct Args {
+    /// TOML file describing where the data is and what reports to generate
+    #[structopt(long)]
+    config: Option<PathBuf>,
+    /// directory containing data files (may be repeated; overrides config)
+    #[structopt(long = "data-dir")]
+    data_dirs: Vec<PathBuf>,
+    /// directory in which to write reports (overrides config)
+    #[structopt(long)]
+    output_dir: Option<PathBuf>,
+    /// time zone for reports, like "America/Los_Angeles" (overrides config)
+    #[structopt(long)]
+    timezone: Option<String>,
+    /// report to generate (may be repeated; overrides config)
+    #[structopt(long = "report")]
+    reports: Vec<String>,
+    /// what to do when two files disagree: "keep-first", "keep-last", or
+    /// "error" (overrides config)
+    #[structopt(long)]
+    conflicts: Option<ConflictPolicy>,
+    /// replace reports in an existing output directory
+    #[structopt(long)]
+    overwrite: bool,
+    /// first (local) day to include in reports (overrides config)
+    #[structopt(long)]
+    from: Option<chrono::NaiveDate>,
+    /// last (local) day to include in reports (overrides config)
+    #[structopt(long)]
+    to: Option<chrono::NaiveDate>,
+    /// day of the month on which the meter is read, for the "billing-cycles"
+    /// report (overrides config)
+    #[structopt(long)]
+    meter_read_day: Option<u32>,
+    /// any date on which the annual true-up happens, for the "true-up-years"
+    /// report (overrides config)
+    #[structopt(long)]
+    true_up_date: Option<chrono::NaiveDate>,
+    /// CSV file of daily temperatures, for the "weather-normalized" report
+    /// (overrides config)
+    #[structopt(long)]
+    temperature_file: Option<PathBuf>,
+    /// base temperature for heating and cooling degree days (overrides
+    /// config)
+    #[structopt(long)]
+    degree_day_base: Option<f64>,
+    /// CSV file of hourly irradiance, for the "performance" report (overrides
+    /// config)
+    #[structopt(long)]
+    irradiance_file: Option<PathBuf>,
+    /// CSV file of hourly cloud cover, for the "forecast" and
+    /// "forecast-backtest" reports (overrides config)
+    #[structopt(long)]
+    cloud_cover_file: Option<PathBuf>,
+    /// number of days to forecast, starting today (overrides config)
+    #[structopt(long)]
+    forecast_days: Option<u32>,
+    /// number of recent days to forecast when backtesting (overrides config)
+    #[structopt(long)]
+    backtest_days: Option<u32>,
+    /// price of electricity per kWh, for estimating the cost of anomalies and
+    /// EV charging (overrides config)
+    #[structopt(long)]
+    electricity_rate: Option<f64>,
+}
+
+/// Names of the reports that we know how to generate
+const REPORTS: &[&str] = &[
+    "yearly",
+    "monthly",
+    "weekly",
+    "seasonal",
+    "daily",
+    "hourly",
+    "billing-cycles",
+    "true-up-years",
+    "load-profile",
+    "monthly-yoy",
+    "daily-yoy",
+    "weather-normalized",
+    "performance",
+    "forecast",
+    "forecast-backtest",
+    "anomalies",
+    "base-load-daily",
+    "base-load-weekly",
+    "appliance-events",
+    "appliance-monthly",
+    "ev-daily",
+    "ev-monthly",
+    "channels-daily",
+    "channels-monthly",
+];
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::from_args();
     Ok(report(&args)?)
 }
 
-fn report(_: &Args) -> Result<(), anyhow::Error> {
+/// Returns the configuration from the config file (if any), with overrides
+/// from the command line applied
+fn load_config(args: &Args) -> Result<ReportConfig, anyhow::Error> {
+    let mut config = match &args.config {
+        Some(path) => ReportConfig::load(path)?,
+        None => ReportConfig::default(),
+    };
+    if !args.data_dirs.is_empty() {
+        config.data_dirs = args.data_dirs.clone();
+    }
+    if let Some(output_dir) = &args.output_dir {
+        config.output_dir = output_dir.clone();
+    }
+    if let Some(timezone) = &args.timezone {
+        config.timezone = Some(timezone.clone());
+    }
+    if !args.reports.is_empty() {
+        config.reports = args.reports.clone();
+    }
+    if let Some(conflicts) = args.conflicts {
+        config.conflicts = conflicts;
+    }
+    if args.overwrite {
+        config.overwrite = true;
+    }
+    if let Some(from) = args.from {
+        config.from = Some(from);
+    }
+    if let Some(to) = args.to {
+        config.to = Some(to);
+    }
+    if let Some(meter_read_day) = args.meter_read_day {
+        config.meter_read_day = Some(meter_read_day);
+    }
+    if let Some(true_up_date) = args.true_up_date {
+        config.true_up_date = Some(true_up_date);
+    }
+    if let Some(temperature_file) = &args.temperature_file {
+        config.temperature_file = Some(temperature_file.clone());
+    }
+    if let Some(degree_day_base) = args.degree_day_base {
+        config.degree_day_base = degree_day_base;
+    }
+    if let Some(irradiance_file) = &args.irradiance_file {
+        config.irradiance_file = Some(irradiance_file.clone());
+    }
+    if let Some(cloud_cover_file) = &args.cloud_cover_file {
+        config.cloud_cover_file = Some(cloud_cover_file.clone());
+    }
+    if let Some(forecast_days) = args.forecast_days {
+        config.forecast_days = forecast_days;
+    }
+    if let Some(backtest_days) = args.backtest_days {
+        config.backtest_days = backtest_days;
+    }
+    if let Some(electricity_rate) = args.electricity_rate {
+        config.electricity_rate = Some(electricity_rate);
+    }
+
+    if let (Some(from), Some(to)) = (config.from, config.to) {
+        if from > to {
+            bail!("\"from\" date {} is after \"to\" date {}", from, to);
+        }
+    }
+    if let Some(day) = config.meter_read_day {
+        if !(1..=31).contains(&day) {
+            bail!("meter read day must be between 1 and 31 (found {})", day);
+        }
+    }
+    if config.reports.iter().any(|r| r == "billing-cycles")
+        && config.meter_read_day.is_none()
+    {
+        bail!("\"billing-cycles\" report requires a meter read day");
+    }
+    if config.reports.iter().any(|r| r == "true-up-years")
+        && config.true_up_date.is_none()
+    {
+        bail!("\"true-up-years\" report requires a true-up date");
+    }
+    if config.reports.iter().any(|r| r == "weather-normalized")
+        && config.temperature_file.is_none()
+    {
+        bail!("\"weather-normalized\" report requires a temperature file");
+    }
+    if config.reports.iter().any(|r| r == "performance")
+        && config.array.is_none()
+    {
+        bail!("\"performance\" report requires an [array] section in config");
+    }
+    if config.reports.iter().any(|r| r.starts_with("appliance-"))
+        && config.appliances.is_empty()
+    {
+        bail!("\"appliance-*\" reports require [[appliances]] in config");
+    }
+    if let Some(tariff) = &config.tariff {
+        tariff.validate().context("checking tariff in config")?;
+    }
+
+    for name in &config.reports {
+        if !REPORTS.contains(&name.as_str()) {
+            bail!(
+                "unknown report {:?} (expected one of: {})",
+                name,
+                REPORTS.join(", ")
+            );
+        }
+    }
+
+    Ok(config)
+}
+
+/// Makes all local times (in reports) use the given time zone
+///
+/// We do this by setting "TZ" in the environment, which `chrono::Local`
+/// respects.  This must be done before we do anything with local times.
+fn set_timezone(timezone: &str) -> Result<(), anyhow::Error> {
+    // If the time zone is invalid, the C library silently uses UTC, so check
+    // it ourselves if we can.
+    let zoneinfo = Path::new("/usr/share/zoneinfo");
+    if zoneinfo.is_dir() && !zoneinfo.join(timezone).is_file() {
+        bail!("unknown time zone {:?}", timezone);
+    }
+    std::env::set_var("TZ", timezone);
+    Ok(())
+}
+
+fn report(args: &Args) -> Result<(), anyhow::Error> {
+    let config = load_config(args)?;
+    if let Some(timezone) = &config.timezone {
+        set_timezone(timezone)?;
+    }
+
+    let registry = Registry::new();
     let mut aggr = DataLoader::new();
-    load_production_data(&mut aggr, Path::new("local-data/production"))
-        .context("loading solar data")?;
-    load_pge_data(&mut aggr, Path::new("local-data/pge"))
-        .context("loading PG&E data")?;
+    aggr.conflict_policy = config.conflicts;
+    aggr.set_time_range(
+        config.from.as_ref().map(local_midnight),
+        config.to.map(|to| local_midnight(&to.succ())),
+    );
+    for data_dir in &config.data_dirs {
+        load_data_dir(&registry, &mut aggr, data_dir, None, None)
+            .with_context(|| {
+                format!("loading data from {:?}", data_dir.display())
+            })?;
+    }
+    for channel in &config.channels {
+        let data_dir = &channel.data_dir;
+        load_data_dir(
+            &registry,
+            &mut aggr,
+            data_dir,
+            channel.name.as_deref(),
+            channel.kind,
+        )
+        .with_context(|| {
+            format!("loading data from {:?}", data_dir.display())
+        })?;
+    }
 
     let nwarnings = aggr.nwarnings;
 
     eprintln!("warnings: {}", aggr.nwarnings);
-    eprintln!("production sources: {}", aggr.nprodsources);
-    eprintln!("production records: {}", aggr.nprodrecords);
-    eprintln!("production duplicate records skipped: {}", aggr.nproddupsok);
-    eprintln!("net usage  sources: {}", aggr.nusagesources);
-    eprintln!("net usage  records: {}", aggr.nusagerecords);
-    eprintln!("net usage  duplicate records skipped: {}", aggr.nusagedupsok);
+    for (channel, stats) in aggr.channels() {
+        let label = if channel.name == channel.kind.name() {
+            channel.name.clone()
+        } else {
+            format!("{} ({})", channel.name, channel.kind)
+        };
+        eprintln!("{} sources: {}", label, stats.nsources);
+        eprintln!("{} records: {}", label, stats.nrecords);
+        eprintln!("{} duplicate records skipped: {}", label, stats.ndupsok);
+        eprintln!("{} conflicting records: {}", label, stats.nconflicts);
+        eprintln!("{} partial hours: {}", label, stats.npartialhours);
+    }
 
     if nwarnings > 0 {
         bail!(
@@ -47,34 +313,272 @@ fn report(_: &Args) -> Result<(), anyhow::Error> {
         );
     }
 
-    let output_dir = Path::new("generated-reports");
-    fs::create_dir(output_dir)
-        .with_context(|| format!("mkdir {:?}", output_dir.display()))?;
-    make_report(output_dir, "yearly", aggr.years())
-        .context("creating yearly report")?;
-    make_report(output_dir, "monthly", aggr.months())
-        .context("creating monthly report")?;
-    make_report(output_dir, "daily", aggr.days())
-        .context("creating daily report")?;
-    make_report(output_dir, "hourly", aggr.hours())
-        .context("creating hourly report")?;
+    let output_dir = &config.output_dir;
+    if config.overwrite {
+        fs::create_dir_all(output_dir)
+    } else {
+        fs::create_dir(output_dir)
+    }
+    .with_context(|| format!("mkdir {:?}", output_dir.display()))?;
+    for name in &config.reports {
+        let iter = match name.as_str() {
+            "yearly" => aggr.years(),
+            "monthly" => aggr.months(),
+            "weekly" => aggr.weeks(),
+            "seasonal" => aggr.seasons(),
+            "daily" => aggr.days(),
+            "hourly" => aggr.hours(),
+            "billing-cycles" => {
+                aggr.billing_cycles(config.meter_read_day.unwrap())
+            }
+            "true-up-years" => aggr.true_up_years(config.true_up_date.unwrap()),
+            "load-profile" => {
+                let profile = load_profile(&aggr);
+                make_report(output_dir, name, profile, config.overwrite)
+                    .with_context(|| format!("creating {} report", name))?;
+                continue;
+            }
+            "monthly-yoy" | "daily-yoy" => {
+                let period = if name == "monthly-yoy" {
+                    year_over_year::Period::Month
+                } else {
+                    year_over_year::Period::Day
+                };
+                let comparisons = year_over_year::compare(&aggr, period);
+                make_report(output_dir, name, comparisons, config.overwrite)
+                    .with_context(|| format!("creating {} report", name))?;
+                continue;
+            }
+            "weather-normalized" => {
+                let path = config.temperature_file.as_ref().unwrap();
+                let temperatures = weather::load_daily_temperatures(path)?;
+                let (model, days) = weather::normalize(
+                    &aggr,
+                    &temperatures,
+                    config.degree_day_base,
+                )?;
+                eprintln!(
+                    "weather model: {:.0} Wh/day + {:.0} Wh/HDD + {:.0} Wh/CDD \
+                    (R^2 = {:.2}, {} days)",
+                    model.base_wh,
+                    model.wh_per_hdd,
+                    model.wh_per_cdd,
+                    model.r_squared,
+                    model.ndays
+                );
+                make_report(output_dir, name, days, config.overwrite)
+                    .with_context(|| format!("creating {} report", name))?;
+                continue;
+            }
+            "performance" => {
+                let irradiance = match &config.irradiance_file {
+                    Some(path) => Some(IrradianceData::load(
+                        path,
+                        config.irradiance_typical_year,
+                    )?),
+                    None => None,
+                };
+                let (days, summary) = solar_model::daily_performance(
+                    &aggr,
+                    config.array.as_ref().unwrap(),
+                    irradiance.as_ref(),
+                );
+                match summary.degradation_pct_per_year {
+                    Some(pct) => eprintln!(
+                        "estimated performance change: {:+.2}%/year \
+                        (from {} monthly comparisons)",
+                        pct, summary.ncomparisons
+                    ),
+                    None => eprintln!(
+                        "not enough data to estimate degradation \
+                        (need more than a year)"
+                    ),
+                }
+                let nunderperforming =
+                    days.iter().filter(|d| d.underperforming).count();
+                if nunderperforming > 0 {
+                    eprintln!(
+                        "warning: {} day{} underperforming (possible \
+                        soiling, shading, or equipment failure)",
+                        nunderperforming,
+                        if nunderperforming == 1 { "" } else { "s" }
+                    );
+                }
+                make_report(output_dir, name, days, config.overwrite)
+                    .with_context(|| format!("creating {} report", name))?;
+                continue;
+            }
+            "forecast" | "forecast-backtest" => {
+                let cloud_cover = match &config.cloud_cover_file {
+                    Some(path) => Some(CloudCover::load(path)?),
+                    None => None,
+                };
+                let forecaster = Forecaster::new(&aggr);
+                if name == "forecast" {
+                    let today = chrono::Local::today().naive_local();
+                    let hours = forecaster.forecast(
+                        &today,
+                        config.forecast_days,
+                        cloud_cover.as_ref(),
+                    );
+                    make_report(output_dir, name, hours, config.overwrite)
+                } else {
+                    let (days, summary) = forecaster
+                        .backtest(config.backtest_days, cloud_cover.as_ref());
+                    eprintln!(
+                        "backtest over {} days: mean absolute error {:.0} Wh/day \
+                        ({}), bias {:+.0} Wh/day, hourly RMS error {:.0} Wh \
+                        (persistence forecast: {})",
+                        summary.ndays,
+                        summary.mean_abs_error,
+                        summary
+                            .mean_abs_error_pct
+                            .map(|p| format!("{:.1}%", p))
+                            .unwrap_or_else(|| String::from("n/a")),
+                        summary.bias,
+                        summary.hourly_rms_error,
+                        summary
+                            .persistence_mean_abs_error
+                            .map(|e| format!("{:.0} Wh/day", e))
+                            .unwrap_or_else(|| String::from("n/a")),
+                    );
+                    make_report(output_dir, name, days, config.overwrite)
+                }
+                .with_context(|| format!("creating {} report", name))?;
+                continue;
+            }
+            "anomalies" => {
+                let anomalies =
+                    anomaly::find_anomalies(&aggr, config.electricity_rate);
+                eprintln!("found {} anomalies", anomalies.len());
+                make_report(output_dir, name, anomalies, config.overwrite)
+                    .with_context(|| format!("creating {} report", name))?;
+                continue;
+            }
+            "base-load-daily" | "base-load-weekly" => {
+                let annotations = &config.annotations;
+                let (base_loads, window) = if name == "base-load-daily" {
+                    (
+                        base_load::base_load(&aggr, bucket::Days, annotations),
+                        chrono::Duration::days(14),
+                    )
+                } else {
+                    (
+                        base_load::base_load(
+                            &aggr,
+                            bucket::IsoWeeks,
+                            annotations,
+                        ),
+                        chrono::Duration::weeks(8),
+                    )
+                };
+                for effect in base_load::annotation_effects(
+                    &base_loads,
+                    annotations,
+                    window,
+                ) {
+                    let watts = |w: Option<f64>| {
+                        w.map(|w| format!("{:.0} W", w))
+                            .unwrap_or_else(|| String::from("n/a"))
+                    };
+                    eprintln!(
+                        "{} ({}): base load {} before, {} after",
+                        effect.annotation.date,
+                        effect.annotation.note,
+                        watts(effect.before_w),
+                        watts(effect.after_w),
+                    );
+                }
+                make_report(output_dir, name, base_loads, config.overwrite)
+                    .with_context(|| format!("creating {} report", name))?;
+                continue;
+            }
+            "appliance-events" | "appliance-monthly" => {
+                let (events, breakdown) =
+                    disaggregation::disaggregate(&aggr, &config.appliances);
+                if name == "appliance-events" {
+                    make_report(output_dir, name, events, config.overwrite)
+                } else {
+                    make_report(output_dir, name, breakdown, config.overwrite)
+                }
+                .with_context(|| format!("creating {} report", name))?;
+                continue;
+            }
+            "ev-daily" | "ev-monthly" => {
+                if !aggr.has_kind(ChannelKind::EvCharging) {
+                    bail!(
+                        "\"{}\" report requires EV charging session data",
+                        name
+                    );
+                }
+                let tariff = config
+                    .tariff
+                    .clone()
+                    .or_else(|| config.electricity_rate.map(Tariff::flat));
+                let periods = if name == "ev-daily" {
+                    ev_charging::ev_charging(
+                        &aggr,
+                        bucket::Days,
+                        tariff.as_ref(),
+                    )
+                } else {
+                    ev_charging::ev_charging(
+                        &aggr,
+                        bucket::Months,
+                        tariff.as_ref(),
+                    )
+                };
+                let ev_kwh: f64 = periods.iter().map(|p| p.ev_kwh).sum();
+                let solar_kwh: f64 = periods.iter().map(|p| p.solar_kwh).sum();
+                eprintln!(
+                    "EV charging: {:.1} kWh, {:.1} kWh ({:.0}%) from solar",
+                    ev_kwh,
+                    solar_kwh,
+                    if ev_kwh > 0.0 { solar_kwh * 100.0 / ev_kwh } else { 0.0 }
+                );
+                make_report(output_dir, name, periods, config.overwrite)
+                    .with_context(|| format!("creating {} report", name))?;
+                continue;
+            }
+            "channels-daily" | "channels-monthly" => {
+                let totals = if name == "channels-daily" {
+                    channel::channel_totals(&aggr, bucket::Days)
+                } else {
+                    channel::channel_totals(&aggr, bucket::Months)
+                };
+                make_report(output_dir, name, totals, config.overwrite)
+                    .with_context(|| format!("creating {} report", name))?;
+                continue;
+            }
+            _ => unreachable!("report names are validated when loading config"),
+        };
+        make_report(output_dir, name, iter, config.overwrite)
+            .with_context(|| format!("creating {} report", name))?;
+    }
     Ok(())
 }
 
-fn make_report(
+fn make_report<I>(
     parent_dir: &Path,
     label: &str,
-    iter: DataIterator<'_>,
-) -> Result<(), anyhow::Error> {
+    records: I,
+    overwrite: bool,
+) -> Result<(), anyhow::Error>
+where
+    I: IntoIterator,
+    I::Item: serde::Serialize,
+{
     eprint!("creating {} report ... ", label);
     let filename = parent_dir.join(format!("{}.csv", label));
     let file = OpenOptions::new()
-        .create_new(true)
+        .create_new(!overwrite)
+        .create(overwrite)
+        .truncate(overwrite)
         .write(true)
         .open(&filename)
         .with_context(|| format!("create {:?}", filename.display()))?;
     let mut writer = csv::Writer::from_writer(file);
-    for record in iter {
+    for record in records {
         writer.serialize(record).context("write record")?;
     }
     writer.flush().context("flush")?;
@@ -82,61 +586,43 @@ fn make_report(
     Ok(())
 }
 
-fn load_production_data(
+/// Loads every file in `path` that's in a supported format
+///
+/// If given, `channel` and `kind` override the name and kind of the channel
+/// that the data goes into (see [`DataLoader::load_source_as()`]).
+fn load_data_dir(
+    registry: &Registry,
     aggr: &mut DataLoader,
     path: &Path,
+    channel: Option<&str>,
+    kind: Option<ChannelKind>,
 ) -> Result<(), anyhow::Error> {
-    eprintln!("loading production data from {:?}", path);
+    eprintln!("loading data from {:?}", path);
     let dirents = fs::read_dir(path)
         .with_context(|| format!("readdir {:?}", path.display()))?;
     for maybe_item in dirents {
         let item = maybe_item
             .with_context(|| format!("readdir {:?} entry", path.display()))?;
         let filepath = item.path();
-        eprintln!("loading production data from {:?}", filepath);
-        let file = fs::File::open(&filepath)
-            .with_context(|| format!("read {:?}", filepath))?;
-        let mut solar_reader = SolarProductionReader::new(file);
-        aggr.load_production(
-            Source::new(&filepath.display().to_string()),
-            solar_reader.records(),
-        )
-        .with_context(|| format!("loading data from {:?}", filepath))?;
-    }
-
-    Ok(())
-}
-
-// TODO-cleanup commonize with load_production_data
-fn load_pge_data(
-    aggr: &mut DataLoader,
-    path: &Path,
-) -> Result<(), anyhow::Error> {
-    eprintln!("loading PG&E data from {:?}", path);
-    let dirents = fs::read_dir(path)
-        .with_context(|| format!("readdir {:?}", path.display()))?;
-    for maybe_item in dirents {
-        let item = maybe_item
-            .with_context(|| format!("readdir {:?} entry", path.display()))?;
-        let filepath = item.path();
-        let name = item.file_name();
-        let name_str = name.to_string_lossy();
-        if !name_str.starts_with("pge_electric_interval_data_")
-            || !name_str.ends_with(".csv")
+        // Skip hidden files, including temporary files left behind by an
+        // interrupted "enphase-fetch --output-dir".
+        if item.file_name().to_string_lossy().starts_with('.')
+            || !filepath.is_file()
         {
             eprintln!("skipping {:?}", filepath);
             continue;
         }
-        eprintln!("loading PG&E data from {:?}", filepath);
-        let file = fs::File::open(&filepath)
-            .with_context(|| format!("read {:?}", filepath))?;
-        let mut data_reader = ElectricityUsageReader::new(file)
-            .with_context(|| format!("load initial {:?}", filepath))?;
-        aggr.load_net_usage(
-            Source::new(&filepath.display().to_string()),
-            data_reader.records(),
-        )
-        .with_context(|| format!("loading data from {:?}", filepath))?;
+        let mut source = match registry.open(&filepath)? {
+            Some(source) => source,
+            None => {
+                eprintln!("skipping {:?} (unrecognized format)", filepath);
+                continue;
+            }
+        };
+        let kind = kind.unwrap_or_else(|| source.kind());
+        eprintln!("loading {} data from {:?}", kind, filepath);
+        aggr.load_source_as(source.as_mut(), channel, Some(kind))
+            .with_context(|| format!("loading data from {:?}", filepath))?;
     }
 
     Ok(())
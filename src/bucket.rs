@@ -0,0 +1,575 @@
+//! Ways of grouping hours of data into buckets
+//!
+//! A [`Bucketer`] maps the start of each hour (in UTC) to the key of the
+//! bucket that the hour belongs in, or to `None` to leave that hour out
+//! entirely.  There are two ways to use one:
+//!
+//! * [`crate::data_aggregator::DataLoader::buckets()`] walks the hours in
+//!   order and emits one record for each run of consecutive hours that have the
+//!   same key.  This is what you want for periods of time (days, weeks,
+//!   billing cycles, etc.), where the key is the start of the period.
+//! * [`crate::data_aggregator::DataLoader::aggregate()`] totals up all of the
+//!   hours with each key, whether or not they're consecutive.  This is what you
+//!   want for categories like time-of-use periods or "weekday peak hours in
+//!   summer".
+//!
+//! Any closure taking `&DateTime<Utc>` and returning an `Option` is a
+//! `Bucketer`, so it's easy to define your own:
+//!
+//! ```
+//! use chrono::Datelike;
+//! use home_energy_tools::bucket::Season;
+//! use home_energy_tools::bucket::TouSchedule;
+//!
+//! let tou = TouSchedule::pge_e_tou_c();
+//! let summer_weekday_peak = move |t: &chrono::DateTime<chrono::Utc>| {
+//!     let local = t.with_timezone(&chrono::Local);
+//!     let weekday = local.weekday().number_from_monday() <= 5;
+//!     let summer = Season::of(&local.date().naive_local()) == Season::Summer;
+//!     let peak = tou.period_at(t) == "peak";
+//!     (weekday && summer && peak).then(|| ())
+//! };
+//! # let _ = summer_weekday_peak;
+//! ```
+//!
+//! All of the bucketers here work in local time.
+
+use chrono::Datelike;
+use chrono::NaiveDateTime;
+use chrono::Timelike;
+use serde::Deserialize;
+
+/// Assigns hours to buckets
+pub trait Bucketer {
+    /// identifies a bucket
+    type Key;
+
+    /// Returns the key of the bucket containing the hour starting at
+    /// `hour_start`, or `None` if the hour should be left out
+    fn bucket(
+        &self,
+        hour_start: &chrono::DateTime<chrono::Utc>,
+    ) -> Option<Self::Key>;
+}
+
+impl<F, K> Bucketer for F
+where
+    F: Fn(&chrono::DateTime<chrono::Utc>) -> Option<K>,
+{
+    type Key = K;
+
+    fn bucket(&self, hour_start: &chrono::DateTime<chrono::Utc>) -> Option<K> {
+        self(hour_start)
+    }
+}
+
+/// Returns the local date and time corresponding to `start_utc`
+fn local(start_utc: &chrono::DateTime<chrono::Utc>) -> NaiveDateTime {
+    start_utc.with_timezone(&chrono::Local).naive_local()
+}
+
+/// Calendar years
+pub struct Years;
+
+impl Bucketer for Years {
+    type Key = NaiveDateTime;
+
+    fn bucket(
+        &self,
+        hour_start: &chrono::DateTime<chrono::Utc>,
+    ) -> Option<NaiveDateTime> {
+        let local = local(hour_start);
+        Some(chrono::NaiveDate::from_ymd(local.year(), 1, 1).and_hms(0, 0, 0))
+    }
+}
+
+/// Calendar months
+pub struct Months;
+
+impl Bucketer for Months {
+    type Key = NaiveDateTime;
+
+    fn bucket(
+        &self,
+        hour_start: &chrono::DateTime<chrono::Utc>,
+    ) -> Option<NaiveDateTime> {
+        let local = local(hour_start);
+        Some(
+            chrono::NaiveDate::from_ymd(local.year(), local.month(), 1)
+                .and_hms(0, 0, 0),
+        )
+    }
+}
+
+/// ISO weeks (starting on Monday)
+pub struct IsoWeeks;
+
+impl Bucketer for IsoWeeks {
+    type Key = NaiveDateTime;
+
+    fn bucket(
+        &self,
+        hour_start: &chrono::DateTime<chrono::Utc>,
+    ) -> Option<NaiveDateTime> {
+        let date = local(hour_start).date();
+        let monday = date
+            - chrono::Duration::days(i64::from(
+                date.weekday().num_days_from_monday(),
+            ));
+        Some(monday.and_hms(0, 0, 0))
+    }
+}
+
+/// Calendar days
+pub struct Days;
+
+impl Bucketer for Days {
+    type Key = NaiveDateTime;
+
+    fn bucket(
+        &self,
+        hour_start: &chrono::DateTime<chrono::Utc>,
+    ) -> Option<NaiveDateTime> {
+        Some(local(hour_start).date().and_hms(0, 0, 0))
+    }
+}
+
+/// Individual hours
+pub struct Hours;
+
+impl Bucketer for Hours {
+    type Key = NaiveDateTime;
+
+    fn bucket(
+        &self,
+        hour_start: &chrono::DateTime<chrono::Utc>,
+    ) -> Option<NaiveDateTime> {
+        assert_eq!(hour_start.minute(), 0);
+        assert_eq!(hour_start.second(), 0);
+        assert_eq!(hour_start.nanosecond(), 0);
+        Some(local(hour_start))
+    }
+}
+
+/// Billing cycles, assuming the meter is read on day `read_day` of each month
+/// (see [`billing_cycle_start()`])
+pub struct BillingCycles {
+    pub read_day: u32,
+}
+
+impl Bucketer for BillingCycles {
+    type Key = NaiveDateTime;
+
+    fn bucket(
+        &self,
+        hour_start: &chrono::DateTime<chrono::Utc>,
+    ) -> Option<NaiveDateTime> {
+        let date = local(hour_start).date();
+        Some(billing_cycle_start(&date, self.read_day).and_hms(0, 0, 0))
+    }
+}
+
+/// True-up years (the annual periods over which net metering charges and
+/// credits are settled), which start each year on the anniversary of
+/// `true_up_date` (see [`true_up_year_start()`])
+pub struct TrueUpYears {
+    pub true_up_date: chrono::NaiveDate,
+}
+
+impl Bucketer for TrueUpYears {
+    type Key = NaiveDateTime;
+
+    fn bucket(
+        &self,
+        hour_start: &chrono::DateTime<chrono::Utc>,
+    ) -> Option<NaiveDateTime> {
+        let date = local(hour_start).date();
+        Some(true_up_year_start(&date, &self.true_up_date).and_hms(0, 0, 0))
+    }
+}
+
+/// Meteorological seasons, each of which starts on the first day of a month
+/// (see [`Season`])
+pub struct Seasons;
+
+impl Bucketer for Seasons {
+    type Key = NaiveDateTime;
+
+    fn bucket(
+        &self,
+        hour_start: &chrono::DateTime<chrono::Utc>,
+    ) -> Option<NaiveDateTime> {
+        let date = local(hour_start).date();
+        Some(Season::start(&date).and_hms(0, 0, 0))
+    }
+}
+
+/// Meteorological seasons (for the northern hemisphere)
+///
+/// Each season is three whole months: winter is December through February,
+/// spring is March through May, summer is June through August, and autumn is
+/// September through November.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Season {
+    Winter,
+    Spring,
+    Summer,
+    Autumn,
+}
+
+impl Season {
+    /// Returns the season containing `date`
+    pub fn of(date: &chrono::NaiveDate) -> Season {
+        match date.month() {
+            12 | 1 | 2 => Season::Winter,
+            3..=5 => Season::Spring,
+            6..=8 => Season::Summer,
+            _ => Season::Autumn,
+        }
+    }
+
+    /// Returns the first day of the season containing `date`
+    pub fn start(date: &chrono::NaiveDate) -> chrono::NaiveDate {
+        let (year, month) = match date.month() {
+            1 | 2 => (date.year() - 1, 12),
+            m => (date.year(), m - (m % 3)),
+        };
+        chrono::NaiveDate::from_ymd(year, month, 1)
+    }
+}
+
+/// Describes one time-of-use (TOU) period in a rate schedule
+#[derive(Clone, Debug, Deserialize)]
+pub struct TouPeriod {
+    /// name of the period (e.g., "peak")
+    pub name: String,
+    /// months (1-12) in which this period applies (all months, if empty)
+    #[serde(default)]
+    pub months: Vec<u32>,
+    /// whether this period only applies Monday through Friday
+    #[serde(default)]
+    pub weekdays_only: bool,
+    /// first hour (0-23, local time) of the period
+    pub start_hour: u32,
+    /// hour (1-24, local time) at which the period ends
+    pub end_hour: u32,
+}
+
+impl TouPeriod {
+    fn contains(&self, local: &NaiveDateTime) -> bool {
+        (self.months.is_empty() || self.months.contains(&local.month()))
+            && (!self.weekdays_only
+                || local.weekday().number_from_monday() <= 5)
+            && local.hour() >= self.start_hour
+            && local.hour() < self.end_hour
+    }
+}
+
+/// Describes a time-of-use rate schedule
+///
+/// This doesn't know about holidays, which most utilities treat as
+/// off-peak.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TouSchedule {
+    /// periods in order of precedence (the first one that contains an hour
+    /// wins)
+    pub periods: Vec<TouPeriod>,
+    /// name of the period for hours not in any of `periods`
+    pub default_period: String,
+}
+
+impl TouSchedule {
+    /// Returns PG&E's E-TOU-C schedule (peak from 4pm to 9pm every day)
+    pub fn pge_e_tou_c() -> TouSchedule {
+        TouSchedule {
+            periods: vec![TouPeriod {
+                name: String::from("peak"),
+                months: Vec::new(),
+                weekdays_only: false,
+                start_hour: 16,
+                end_hour: 21,
+            }],
+            default_period: String::from("off-peak"),
+        }
+    }
+
+    /// Returns the name of the period containing the hour starting at
+    /// `hour_start`
+    pub fn period_at(
+        &self,
+        hour_start: &chrono::DateTime<chrono::Utc>,
+    ) -> &str {
+        let local = local(hour_start);
+        self.periods
+            .iter()
+            .find(|p| p.contains(&local))
+            .map(|p| p.name.as_str())
+            .unwrap_or(&self.default_period)
+    }
+}
+
+impl Bucketer for TouSchedule {
+    type Key = String;
+
+    fn bucket(
+        &self,
+        hour_start: &chrono::DateTime<chrono::Utc>,
+    ) -> Option<String> {
+        Some(self.period_at(hour_start).to_owned())
+    }
+}
+
+/// Returns the date `day` of the given month, or the last day of the month
+/// if it has fewer than `day` days
+fn clamped_date(year: i32, month: u32, day: u32) -> chrono::NaiveDate {
+    (1..=day)
+        .rev()
+        .find_map(|d| chrono::NaiveDate::from_ymd_opt(year, month, d))
+        .unwrap()
+}
+
+/// Returns the first day of the billing cycle containing `date`, assuming
+/// that the meter is read on day `read_day` of each month
+///
+/// Each cycle starts on the read day.  For months with fewer than `read_day`
+/// days, the cycle starts on the last day of the month.
+pub fn billing_cycle_start(
+    date: &chrono::NaiveDate,
+    read_day: u32,
+) -> chrono::NaiveDate {
+    let this_month = clamped_date(date.year(), date.month(), read_day);
+    if *date >= this_month {
+        return this_month;
+    }
+
+    let (year, month) = if date.month() == 1 {
+        (date.year() - 1, 12)
+    } else {
+        (date.year(), date.month() - 1)
+    };
+    clamped_date(year, month, read_day)
+}
+
+/// Returns the first day of the true-up year containing `date`, given any
+/// true-up date (only its month and day are used)
+pub fn true_up_year_start(
+    date: &chrono::NaiveDate,
+    true_up_date: &chrono::NaiveDate,
+) -> chrono::NaiveDate {
+    let this_year =
+        clamped_date(date.year(), true_up_date.month(), true_up_date.day());
+    if *date >= this_year {
+        this_year
+    } else {
+        clamped_date(date.year() - 1, true_up_date.month(), true_up_date.day())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// Returns the start of the hour at local time `t` ("YYYY-MM-DD HH")
+    ///
+    /// The bucketers all work in local time, so the tests specify hours in
+    /// local time to work in any time zone.
+    fn at(t: &str) -> chrono::DateTime<chrono::Utc> {
+        let naive = NaiveDateTime::parse_from_str(
+            &format!("{}:00", t),
+            "%Y-%m-%d %H:%M",
+        )
+        .unwrap();
+        chrono::Local
+            .from_local_datetime(&naive)
+            .unwrap()
+            .with_timezone(&chrono::Utc)
+    }
+
+    fn date(t: &str) -> chrono::NaiveDate {
+        chrono::NaiveDate::parse_from_str(t, "%Y-%m-%d").unwrap()
+    }
+
+    fn midnight(t: &str) -> Option<NaiveDateTime> {
+        Some(date(t).and_hms(0, 0, 0))
+    }
+
+    #[test]
+    fn test_calendar_buckets() {
+        assert_eq!(Years.bucket(&at("2021-12-31 23")), midnight("2021-01-01"));
+        assert_eq!(Years.bucket(&at("2022-01-01 00")), midnight("2022-01-01"));
+        assert_eq!(Months.bucket(&at("2022-02-28 23")), midnight("2022-02-01"));
+        assert_eq!(Months.bucket(&at("2022-03-01 00")), midnight("2022-03-01"));
+        assert_eq!(Days.bucket(&at("2022-03-01 23")), midnight("2022-03-01"));
+
+        // 2022-01-02 was a Sunday, so it's in the week that started in 2021.
+        assert_eq!(
+            IsoWeeks.bucket(&at("2022-01-02 23")),
+            midnight("2021-12-27")
+        );
+        assert_eq!(
+            IsoWeeks.bucket(&at("2022-01-03 00")),
+            midnight("2022-01-03")
+        );
+    }
+
+    #[test]
+    fn test_seasons() {
+        assert_eq!(Season::of(&date("2022-02-28")), Season::Winter);
+        assert_eq!(Season::of(&date("2022-03-01")), Season::Spring);
+        assert_eq!(Season::of(&date("2022-05-31")), Season::Spring);
+        assert_eq!(Season::of(&date("2022-06-01")), Season::Summer);
+        assert_eq!(Season::of(&date("2022-08-31")), Season::Summer);
+        assert_eq!(Season::of(&date("2022-09-01")), Season::Autumn);
+        assert_eq!(Season::of(&date("2022-11-30")), Season::Autumn);
+        assert_eq!(Season::of(&date("2022-12-01")), Season::Winter);
+
+        // Winter starts in the previous year for January and February.
+        assert_eq!(Season::start(&date("2022-12-01")), date("2022-12-01"));
+        assert_eq!(Season::start(&date("2023-01-15")), date("2022-12-01"));
+        assert_eq!(Season::start(&date("2023-02-28")), date("2022-12-01"));
+        assert_eq!(Season::start(&date("2023-03-01")), date("2023-03-01"));
+        assert_eq!(Season::start(&date("2023-11-30")), date("2023-09-01"));
+
+        assert_eq!(
+            Seasons.bucket(&at("2023-02-28 23")),
+            midnight("2022-12-01")
+        );
+        assert_eq!(
+            Seasons.bucket(&at("2023-03-01 00")),
+            midnight("2023-03-01")
+        );
+    }
+
+    #[test]
+    fn test_billing_cycles() {
+        assert_eq!(
+            billing_cycle_start(&date("2022-03-14"), 15),
+            date("2022-02-15")
+        );
+        assert_eq!(
+            billing_cycle_start(&date("2022-03-15"), 15),
+            date("2022-03-15")
+        );
+        // Cycles that started in December of the previous year
+        assert_eq!(
+            billing_cycle_start(&date("2022-01-14"), 15),
+            date("2021-12-15")
+        );
+        // In months without the read day, the cycle starts on the last day.
+        assert_eq!(
+            billing_cycle_start(&date("2022-02-27"), 31),
+            date("2022-01-31")
+        );
+        assert_eq!(
+            billing_cycle_start(&date("2022-02-28"), 31),
+            date("2022-02-28")
+        );
+        assert_eq!(
+            billing_cycle_start(&date("2022-03-30"), 31),
+            date("2022-02-28")
+        );
+        assert_eq!(
+            billing_cycle_start(&date("2022-03-31"), 31),
+            date("2022-03-31")
+        );
+        assert_eq!(
+            billing_cycle_start(&date("2022-04-30"), 31),
+            date("2022-04-30")
+        );
+
+        let cycles = BillingCycles { read_day: 15 };
+        assert_eq!(cycles.bucket(&at("2022-03-14 23")), midnight("2022-02-15"));
+        assert_eq!(cycles.bucket(&at("2022-03-15 00")), midnight("2022-03-15"));
+    }
+
+    #[test]
+    fn test_true_up_years() {
+        let true_up = date("2021-06-15");
+        assert_eq!(
+            true_up_year_start(&date("2022-06-14"), &true_up),
+            date("2021-06-15")
+        );
+        assert_eq!(
+            true_up_year_start(&date("2022-06-15"), &true_up),
+            date("2022-06-15")
+        );
+
+        // A true-up on February 29 moves to February 28 in other years.
+        let leap = date("2020-02-29");
+        assert_eq!(
+            true_up_year_start(&date("2022-02-27"), &leap),
+            date("2021-02-28")
+        );
+        assert_eq!(
+            true_up_year_start(&date("2022-02-28"), &leap),
+            date("2022-02-28")
+        );
+        assert_eq!(
+            true_up_year_start(&date("2024-02-29"), &leap),
+            date("2024-02-29")
+        );
+
+        let years = TrueUpYears { true_up_date: true_up };
+        assert_eq!(years.bucket(&at("2022-06-14 23")), midnight("2021-06-15"));
+        assert_eq!(years.bucket(&at("2022-06-15 00")), midnight("2022-06-15"));
+    }
+
+    #[test]
+    fn test_tou_pge_e_tou_c() {
+        let tou = TouSchedule::pge_e_tou_c();
+        assert_eq!(tou.bucket(&at("2022-07-02 15")).unwrap(), "off-peak");
+        assert_eq!(tou.bucket(&at("2022-07-02 16")).unwrap(), "peak");
+        assert_eq!(tou.bucket(&at("2022-07-02 20")).unwrap(), "peak");
+        assert_eq!(tou.bucket(&at("2022-07-02 21")).unwrap(), "off-peak");
+        assert_eq!(tou.bucket(&at("2022-01-03 00")).unwrap(), "off-peak");
+    }
+
+    #[test]
+    fn test_tou_seasons_and_weekdays() {
+        let period =
+            |name: &str, months: &[u32], weekdays_only, start, end| TouPeriod {
+                name: name.to_owned(),
+                months: months.to_vec(),
+                weekdays_only,
+                start_hour: start,
+                end_hour: end,
+            };
+        // Summer weekday peak from 2pm to 8pm, with part-peak around it.  The
+        // peak period comes first, so it wins where they overlap.
+        let tou = TouSchedule {
+            periods: vec![
+                period("summer-peak", &[6, 7, 8, 9], true, 14, 20),
+                period("part-peak", &[], true, 12, 22),
+            ],
+            default_period: String::from("off-peak"),
+        };
+
+        // 2022-09-30 was a Friday.
+        assert_eq!(tou.period_at(&at("2022-09-30 11")), "off-peak");
+        assert_eq!(tou.period_at(&at("2022-09-30 12")), "part-peak");
+        assert_eq!(tou.period_at(&at("2022-09-30 14")), "summer-peak");
+        assert_eq!(tou.period_at(&at("2022-09-30 19")), "summer-peak");
+        assert_eq!(tou.period_at(&at("2022-09-30 20")), "part-peak");
+        assert_eq!(tou.period_at(&at("2022-09-30 22")), "off-peak");
+        // Weekends are off-peak all day.
+        assert_eq!(tou.period_at(&at("2022-10-01 14")), "off-peak");
+        // Outside the summer months, only part-peak applies.
+        assert_eq!(tou.period_at(&at("2022-10-03 14")), "part-peak");
+        assert_eq!(tou.period_at(&at("2022-05-31 14")), "part-peak");
+        assert_eq!(tou.period_at(&at("2022-06-01 14")), "summer-peak");
+    }
+
+    #[test]
+    fn test_closure_bucketer() {
+        let tou = TouSchedule::pge_e_tou_c();
+        let peak_days = move |t: &chrono::DateTime<chrono::Utc>| {
+            (tou.period_at(t) == "peak").then(|| Days.bucket(t).unwrap())
+        };
+        assert_eq!(peak_days.bucket(&at("2022-07-02 15")), None);
+        assert_eq!(
+            peak_days.bucket(&at("2022-07-02 16")),
+            midnight("2022-07-02")
+        );
+    }
+}
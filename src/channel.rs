@@ -0,0 +1,216 @@
+//! Channels of energy data
+//!
+//! [`crate::data_aggregator::DataLoader`] stores each kind of measurement in
+//! its own *channel*: production from an array, net usage from the utility
+//! meter, one circuit's sub-meter, and so on.  Each channel has a name (e.g.,
+//! "production" or "garage-array") and a [`ChannelKind`] describing what it
+//! measures, which determines how it contributes to the totals that the
+//! reports are built on:
+//!
+//! * energy produced is the sum of all "production" channels
+//! * energy net used is the sum of all "net-usage" channels
+//! * energy consumed is what's produced plus what's net used plus what's
+//!   discharged from batteries, minus what's used to charge them
+//!
+//! The other kinds ("consumption", "ev-charging", and "sub-meter") measure
+//! loads that are already part of consumption, so they don't change these
+//! totals.  Their energy is still available for each channel (see
+//! [`crate::data_aggregator::EnergyTotals::channels`] and
+//! [`channel_totals()`]), so supporting a new kind of meter only requires a
+//! [`crate::data_source::DataSource`] for its files.
+
+use crate::bucket::Bucketer;
+use crate::data_aggregator::DataLoader;
+use anyhow::bail;
+use chrono::NaiveDateTime;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Describes what a channel measures
+#[derive(
+    Clone, Copy, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord, Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChannelKind {
+    /// energy produced (e.g., by solar panels)
+    Production,
+    /// energy used from the grid, net of what was exported to it
+    NetUsage,
+    /// energy used by the whole house (e.g., from consumption meters)
+    Consumption,
+    /// energy used to charge a battery
+    BatteryCharge,
+    /// energy discharged from a battery
+    BatteryDischarge,
+    /// energy delivered by an EV charger
+    EvCharging,
+    /// energy used by one circuit or device
+    SubMeter,
+}
+
+impl ChannelKind {
+    /// Returns the name of this kind, which is also the default name for a
+    /// channel of this kind
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChannelKind::Production => "production",
+            ChannelKind::NetUsage => "net-usage",
+            ChannelKind::Consumption => "consumption",
+            ChannelKind::BatteryCharge => "battery-charge",
+            ChannelKind::BatteryDischarge => "battery-discharge",
+            ChannelKind::EvCharging => "ev-charging",
+            ChannelKind::SubMeter => "sub-meter",
+        }
+    }
+
+    /// Returns how energy in this kind of channel counts toward consumption
+    /// (1 if it adds to it, -1 if it subtracts from it, and 0 if it's
+    /// already included)
+    pub fn consumption_sign(&self) -> i64 {
+        match self {
+            ChannelKind::Production
+            | ChannelKind::NetUsage
+            | ChannelKind::BatteryDischarge => 1,
+            ChannelKind::BatteryCharge => -1,
+            ChannelKind::Consumption
+            | ChannelKind::EvCharging
+            | ChannelKind::SubMeter => 0,
+        }
+    }
+}
+
+impl std::fmt::Display for ChannelKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Describes one channel
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Channel {
+    pub name: String,
+    pub kind: ChannelKind,
+}
+
+/// Keeps track of the channels that have been loaded
+///
+/// Channels are identified by their index, which never changes once they've
+/// been added.
+#[derive(Debug, Default)]
+pub struct ChannelSchema {
+    channels: Vec<Channel>,
+}
+
+impl ChannelSchema {
+    /// Returns the index of `channel`, adding it if it's new
+    ///
+    /// Fails if there's already a channel with the same name but a different
+    /// kind.
+    pub fn add(&mut self, channel: &Channel) -> Result<usize, anyhow::Error> {
+        match self.index(&channel.name) {
+            Some(i) if self.channels[i].kind == channel.kind => Ok(i),
+            Some(i) => bail!(
+                "channel {:?} contains {} data, but found {} data for it",
+                channel.name,
+                self.channels[i].kind,
+                channel.kind
+            ),
+            None => {
+                self.channels.push(channel.clone());
+                Ok(self.channels.len() - 1)
+            }
+        }
+    }
+
+    /// Returns the index of the channel called `name`, if any
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.channels.iter().position(|c| c.name == name)
+    }
+
+    /// Returns the channel with index `index`
+    pub fn get(&self, index: usize) -> &Channel {
+        &self.channels[index]
+    }
+
+    /// Returns all of the channels, in the order they were added
+    pub fn channels(&self) -> &[Channel] {
+        &self.channels
+    }
+}
+
+/// Energy in one channel over one period
+#[derive(Debug, Serialize)]
+pub struct ChannelInterval {
+    pub interval_start: NaiveDateTime,
+    pub channel: String,
+    pub kind: ChannelKind,
+    pub energy_wh: i64,
+    /// number of hours for which this channel has data
+    pub nhours: usize,
+    /// number of hours for which this channel's data was reported as partial
+    pub partial_hours: usize,
+}
+
+/// Totals up each channel for each period chosen by `bucketer` (e.g.,
+/// [`crate::bucket::Days`])
+///
+/// Unlike the usual reports, this includes hours for which only channels that
+/// don't affect the totals (like sub-meters) have data.
+pub fn channel_totals<B>(aggr: &DataLoader, bucketer: B) -> Vec<ChannelInterval>
+where
+    B: Bucketer<Key = NaiveDateTime>,
+{
+    let schema = aggr.schema();
+    let mut rv = Vec::new();
+    for (interval_start, totals) in aggr.aggregate(bucketer) {
+        for channel in schema.channels() {
+            if let Some(c) = totals.channels.get(&channel.name) {
+                rv.push(ChannelInterval {
+                    interval_start,
+                    channel: channel.name.clone(),
+                    kind: channel.kind,
+                    energy_wh: c.energy.as_wh(),
+                    nhours: c.nhours,
+                    partial_hours: c.partial_hours,
+                });
+            }
+        }
+    }
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(name: &str, kind: ChannelKind) -> Channel {
+        Channel { name: name.to_owned(), kind }
+    }
+
+    #[test]
+    fn test_schema_add() {
+        let mut schema = ChannelSchema::default();
+        let roof = channel("roof", ChannelKind::Production);
+        assert_eq!(schema.add(&roof).unwrap(), 0);
+        assert_eq!(
+            schema.add(&channel("net-usage", ChannelKind::NetUsage)).unwrap(),
+            1
+        );
+        // Adding the same channel again returns the same index.
+        assert_eq!(schema.add(&roof).unwrap(), 0);
+        assert_eq!(schema.index("net-usage"), Some(1));
+        assert_eq!(schema.get(0), &roof);
+
+        // The same name with a different kind is an error.
+        let error = schema
+            .add(&channel("roof", ChannelKind::SubMeter))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "channel \"roof\" contains production data, but found sub-meter \
+            data for it"
+        );
+        assert_eq!(schema.channels().len(), 2);
+    }
+}
@@ -6,6 +6,7 @@ use std::ops::SubAssign;
 
 use anyhow::Context;
 use chrono::DateTime;
+use chrono::TimeZone;
 use chrono::Utc;
 use serde::Deserialize;
 use serde::Serialize;
@@ -42,7 +43,9 @@ pub struct NetEnergyUsed {
     pub net_used_wh: WattHours,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize,
+)]
 #[serde(transparent)]
 pub struct WattHours(i64);
 
@@ -54,6 +57,10 @@ impl WattHours {
     pub fn as_kwh(&self) -> f64 {
         (self.0 as f64) / 1000f64
     }
+
+    pub fn as_wh(&self) -> i64 {
+        self.0
+    }
 }
 
 impl From<i32> for WattHours {
@@ -75,11 +82,30 @@ impl SubAssign for WattHours {
 }
 
 /// Represents energy produced in a calendar hour
+//
+// `devices_reporting` and `quality` were added later.  Files written before
+// then don't have these columns, so they're optional when reading.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct EnergyProduced {
     pub datetime_utc: chrono::DateTime<chrono::Utc>,
     pub datetime_local: chrono::DateTime<chrono::Local>,
     pub energy_wh: WattHours,
+    /// number of microinverters that reported data for this interval
+    #[serde(default)]
+    pub devices_reporting: Option<i32>,
+    #[serde(default)]
+    pub quality: Option<Quality>,
+}
+
+/// Describes whether a production record reflects the whole system
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quality {
+    /// all of the system's microinverters reported data
+    Ok,
+    /// some (but not all) of the system's microinverters reported data, so
+    /// the energy produced is probably under-reported
+    Partial,
 }
 
 /// Represents an amount of energy over a given time period
@@ -91,20 +117,62 @@ pub struct EnergyProduced {
 pub struct Energy {
     pub datetime: chrono::DateTime<chrono::Utc>,
     pub energy_wh: WattHours,
+    /// whether the source reported that this record is probably incomplete
+    pub partial: bool,
+    /// channel for this record, for sources with more than one (e.g., one for
+    /// each circuit); otherwise, the record goes into the source's channel
+    pub channel: Option<String>,
 }
 
 impl From<EnergyProduced> for Energy {
     fn from(e: EnergyProduced) -> Self {
-        Energy { datetime: e.datetime_utc, energy_wh: e.energy_wh }
+        Energy {
+            datetime: e.datetime_utc,
+            energy_wh: e.energy_wh,
+            partial: e.quality == Some(Quality::Partial),
+            channel: None,
+        }
     }
 }
 
 impl From<NetEnergyUsed> for Energy {
     fn from(u: NetEnergyUsed) -> Self {
-        Energy { datetime: u.timestamp_start_utc, energy_wh: u.net_used_wh }
+        Energy {
+            datetime: u.timestamp_start_utc,
+            energy_wh: u.net_used_wh,
+            partial: false,
+            channel: None,
+        }
     }
 }
 
+/// Returns the start of the local day `date`
+pub fn local_midnight(date: &chrono::NaiveDate) -> chrono::DateTime<Utc> {
+    // Midnight may not exist on the day DST starts in some timezones.  In
+    // that case, the day starts at 1am.
+    chrono::Local
+        .from_local_datetime(&date.and_hms(0, 0, 0))
+        .earliest()
+        .or_else(|| {
+            chrono::Local.from_local_datetime(&date.and_hms(1, 0, 0)).earliest()
+        })
+        .expect("local day has no start")
+        .with_timezone(&Utc)
+}
+
+/// Returns the start (in UTC) of each hour in the given local day
+///
+/// There are 23 or 25 of these on days when daylight saving time starts or
+/// ends.
+pub fn local_day_hours(date: &chrono::NaiveDate) -> Vec<chrono::DateTime<Utc>> {
+    let end = local_midnight(&date.succ());
+    std::iter::successors(Some(local_midnight(date)), |h| {
+        Some(*h + chrono::Duration::hours(1))
+    })
+    .take_while(|h| *h < end)
+    .collect()
+}
+
 /// Reads our (custom) CSV format describing solar production
 pub struct SolarProductionReader<R> {
     csv_reader: csv::Reader<R>,
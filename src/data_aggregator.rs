@@ -1,17 +1,28 @@
-//! Data structure for storing net usage and production data
+//! Data structure for storing energy data (production, net usage, and any
+//! other channels -- see [`crate::channel`])
 // TODO There's probably a crate well-suited for this.
 
+use crate::bucket;
+use crate::bucket::Bucketer;
+use crate::channel::Channel;
+use crate::channel::ChannelKind;
+use crate::channel::ChannelSchema;
+use crate::common::local_midnight;
 use crate::common::Energy;
 use crate::common::EnergyProduced;
 use crate::common::NetEnergyUsed;
 use crate::common::WattHours;
+use crate::data_source::DataSource;
 use anyhow::bail;
-use chrono::Datelike;
 use chrono::NaiveDateTime;
 use chrono::Timelike;
+use serde::Deserialize;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::iter::Peekable;
+use std::ops::Bound;
 use std::rc::Rc;
+use std::str::FromStr;
 
 /// Label for a data source (will be a filename)
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq)]
@@ -28,37 +39,193 @@ impl Source {
 // don't have any gaps in net usage data)
 pub struct DataLoader {
     hourly_data: BTreeMap<chrono::DateTime<chrono::Utc>, HourlyData>,
+    /// hours to include when iterating the data (see `set_time_range()`)
+    time_range: (
+        Bound<chrono::DateTime<chrono::Utc>>,
+        Bound<chrono::DateTime<chrono::Utc>>,
+    ),
+    /// channels that have been loaded
+    schema: ChannelSchema,
+    /// statistics about what was loaded into each channel (indexed like the
+    /// channels in `schema`)
+    channel_stats: Vec<ChannelStats>,
     pub nerrors: usize,
     pub nwarnings: usize,
-    pub nproddupsok: usize,
-    pub nprodsources: usize,
-    pub nprodrecords: usize,
-    pub nprodconflicts: usize,
-    pub nusagedupsok: usize,
-    pub nusagesources: usize,
-    pub nusagerecords: usize,
+    /// what to do when two sources disagree about the same hour
+    pub conflict_policy: ConflictPolicy,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Describes what was loaded into one channel
+#[derive(Clone, Debug, Default)]
+pub struct ChannelStats {
+    pub nsources: usize,
+    pub nrecords: usize,
+    /// number of hours skipped because they duplicated data already loaded
+    pub ndupsok: usize,
+    /// number of hours for which two sources reported different data
+    pub nconflicts: usize,
+    /// number of hours whose data was reported as partial (e.g., not all
+    /// microinverters reported)
+    pub npartialhours: usize,
+}
+
+/// Describes what to do when two sources report different values for the
+/// same hour
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// print a message and use the value that was loaded first
+    KeepFirst,
+    /// print a message and use the value that was loaded last
+    KeepLast,
+    /// fail
+    Error,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep-first" => Ok(ConflictPolicy::KeepFirst),
+            "keep-last" => Ok(ConflictPolicy::KeepLast),
+            "error" => Ok(ConflictPolicy::Error),
+            _ => bail!("expected \"keep-first\", \"keep-last\", or \"error\""),
+        }
+    }
+}
+
+/// Data for one hour, indexed by channel (see [`ChannelSchema`])
+#[derive(Debug, Default, PartialEq, Eq)]
 struct HourlyData {
-    production: Option<(Rc<Source>, WattHours)>,
-    net_usage: Option<(Rc<Source>, WattHours)>,
+    channels: Vec<Option<Datum>>,
+}
+
+/// Energy for one channel in one hour
+#[derive(Debug, PartialEq, Eq)]
+struct Datum {
+    source: Rc<Source>,
+    energy: WattHours,
+    /// whether the data was reported as partial
+    partial: bool,
+}
+
+impl HourlyData {
+    fn get_mut(&mut self, channel: usize) -> &mut Option<Datum> {
+        if self.channels.len() <= channel {
+            self.channels.resize_with(channel + 1, || None);
+        }
+        &mut self.channels[channel]
+    }
+
+    /// Iterates the channels that have data for this hour
+    fn data(&self) -> impl Iterator<Item = (usize, &Datum)> {
+        self.channels
+            .iter()
+            .enumerate()
+            .filter_map(|(i, d)| d.as_ref().map(|d| (i, d)))
+    }
 }
 
 impl DataLoader {
     pub fn new() -> DataLoader {
         DataLoader {
             hourly_data: BTreeMap::new(),
+            time_range: (Bound::Unbounded, Bound::Unbounded),
+            schema: ChannelSchema::default(),
+            channel_stats: Vec::new(),
             nerrors: 0,
             nwarnings: 0,
-            nproddupsok: 0,
-            nprodsources: 0,
-            nprodrecords: 0,
-            nprodconflicts: 0,
-            nusagedupsok: 0,
-            nusagesources: 0,
-            nusagerecords: 0,
+            conflict_policy: ConflictPolicy::KeepFirst,
+        }
+    }
+
+    /// Returns the channels that have been loaded
+    pub fn schema(&self) -> &ChannelSchema {
+        &self.schema
+    }
+
+    /// Iterates the channels that have been loaded, with statistics about
+    /// what was loaded into each one
+    pub fn channels(&self) -> impl Iterator<Item = (&Channel, &ChannelStats)> {
+        self.schema.channels().iter().zip(&self.channel_stats)
+    }
+
+    /// Returns whether any channel of kind `kind` has been loaded
+    pub fn has_kind(&self, kind: ChannelKind) -> bool {
+        self.schema.channels().iter().any(|c| c.kind == kind)
+    }
+
+    /// Returns whether we have all of the production data for the (local)
+    /// day `date`
+    ///
+    /// Enlighten doesn't report intervals when the system isn't producing
+    /// (e.g., at night), so a complete day of production data doesn't have
+    /// data for every hour.  But `enphase-fetch` fetches data one UTC day at a
+    /// time, so if there's production data for each UTC day that overlaps
+    /// `date`, we have all of it.
+    pub fn has_full_day_of_production(&self, date: &chrono::NaiveDate) -> bool {
+        let first = local_midnight(date).date().naive_utc();
+        let last = (local_midnight(&date.succ())
+            - chrono::Duration::seconds(1))
+        .date()
+        .naive_utc();
+        let mut utc_date = first;
+        while utc_date <= last {
+            let start = chrono::DateTime::<chrono::Utc>::from_utc(
+                utc_date.and_hms(0, 0, 0),
+                chrono::Utc,
+            );
+            let end = start + chrono::Duration::days(1);
+            let has_production =
+                self.hourly_data.range(start..end).any(|(_, hourly)| {
+                    hourly.data().any(|(index, _)| {
+                        self.schema.get(index).kind == ChannelKind::Production
+                    })
+                });
+            if !has_production {
+                return false;
+            }
+            utc_date = utc_date.succ();
         }
+        true
+    }
+
+    /// Loads all of the records from `source` into its own channel(s)
+    pub fn load_source(
+        &mut self,
+        source: &mut dyn DataSource,
+    ) -> Result<(), anyhow::Error> {
+        self.load_source_as(source, None, None)
+    }
+
+    /// Loads all of the records from `source`, overriding the name and kind
+    /// of the channel that they go into
+    ///
+    /// If `name` is given, single-channel sources are loaded into the channel
+    /// called `name`, and each channel of a multi-channel source (like a file
+    /// of circuit-level data) is loaded into "`name`/circuit".  If `kind` is
+    /// given, it replaces the kind that the source reports.
+    pub fn load_source_as(
+        &mut self,
+        source: &mut dyn DataSource,
+        name: Option<&str>,
+        kind: Option<ChannelKind>,
+    ) -> Result<(), anyhow::Error> {
+        if source.interval() > chrono::Duration::hours(1) {
+            bail!(
+                "source {:?}: records covering more than an hour ({}) are \
+                not supported",
+                source.provenance(),
+                source.interval()
+            );
+        }
+        let channel = Channel {
+            name: name.unwrap_or_else(|| source.channel()).to_owned(),
+            kind: kind.unwrap_or_else(|| source.kind()),
+        };
+        let label = source.provenance().to_owned();
+        self.load_energy(&channel, name.is_some(), &label, source.records())
     }
 
     pub fn load_production<I>(
@@ -69,19 +236,16 @@ impl DataLoader {
     where
         I: Iterator<Item = Result<EnergyProduced, anyhow::Error>>,
     {
-        self.nprodsources += 1;
-        let (source_map, nrecords, nwarnings) =
-            load_records(iter.map(|r| r.map(Energy::from)));
-        self.nprodrecords += nrecords;
-        self.nwarnings += nwarnings;
-        let ndupsok = self.merge_source(source, source_map, |hourly| {
-            &mut hourly.production
-        })?;
-        self.nproddupsok += ndupsok;
-        Ok(())
+        let kind = ChannelKind::Production;
+        let channel = Channel { name: kind.name().to_owned(), kind };
+        self.load_energy(
+            &channel,
+            false,
+            &source.0,
+            iter.map(|r| r.map(Energy::from)),
+        )
     }
 
-    // TODO-cleanup commonize with load_production
     pub fn load_net_usage<I>(
         &mut self,
         source: Source,
@@ -90,38 +254,107 @@ impl DataLoader {
     where
         I: Iterator<Item = Result<NetEnergyUsed, anyhow::Error>>,
     {
-        self.nusagesources += 1;
-        let (source_map, nrecords, nwarnings) =
-            load_records(iter.map(|r| r.map(Energy::from)));
-        self.nusagerecords += nrecords;
-        self.nwarnings += nwarnings;
-        let ndupsok = self
-            .merge_source(source, source_map, |hourly| &mut hourly.net_usage)?;
-        self.nusagedupsok += ndupsok;
-        Ok(())
+        let kind = ChannelKind::NetUsage;
+        let channel = Channel { name: kind.name().to_owned(), kind };
+        self.load_energy(
+            &channel,
+            false,
+            &source.0,
+            iter.map(|r| r.map(Energy::from)),
+        )
     }
 
-    fn merge_source<F>(
+    /// Loads records into `channel` (or, for records that name their own
+    /// channel, into that one, prefixed with the name of `channel` if
+    /// `prefix` is set)
+    fn load_energy<I>(
         &mut self,
-        source: Source,
-        source_map: BTreeMap<chrono::DateTime<chrono::Utc>, WattHours>,
-        which: F,
-    ) -> Result<usize, anyhow::Error>
+        channel: &Channel,
+        prefix: bool,
+        label: &str,
+        iter: I,
+    ) -> Result<(), anyhow::Error>
     where
-        F: Fn(&mut HourlyData) -> &mut Option<(Rc<Source>, WattHours)>,
+        I: Iterator<Item = Result<Energy, anyhow::Error>>,
     {
+        let mut by_channel: BTreeMap<Option<String>, LoadedRecords> =
+            BTreeMap::new();
+        for record in iter {
+            match record {
+                Ok(r) => {
+                    by_channel.entry(r.channel.clone()).or_default().add(r)
+                }
+                Err(error) => {
+                    eprintln!("warn: {:#}", error);
+                    self.nwarnings += 1;
+                }
+            }
+        }
+        if by_channel.is_empty() {
+            by_channel.insert(None, LoadedRecords::default());
+        }
+
+        for (subchannel, loaded) in by_channel {
+            let channel = match subchannel {
+                None => channel.clone(),
+                Some(name) if prefix => Channel {
+                    name: format!("{}/{}", channel.name, name),
+                    kind: channel.kind,
+                },
+                Some(name) => Channel { name, kind: channel.kind },
+            };
+            let index = self.schema.add(&channel)?;
+            if self.channel_stats.len() <= index {
+                self.channel_stats.resize_with(index + 1, Default::default);
+            }
+            self.nwarnings += loaded.nwarnings;
+            let nrecords = loaded.nrecords;
+            let (ndupsok, nconflicts, npartial) =
+                self.merge_source(Source::new(label), index, loaded)?;
+            let stats = &mut self.channel_stats[index];
+            stats.nsources += 1;
+            stats.nrecords += nrecords;
+            stats.ndupsok += ndupsok;
+            stats.nconflicts += nconflicts;
+            stats.npartialhours =
+                (stats.npartialhours as isize + npartial) as usize;
+        }
+        Ok(())
+    }
+
+    /// Merges the records loaded from one source into channel `channel`
+    ///
+    /// Returns the number of duplicate hours skipped, the number of
+    /// conflicting hours, and the change in the number of partial hours.
+    fn merge_source(
+        &mut self,
+        source: Source,
+        channel: usize,
+        loaded: LoadedRecords,
+    ) -> Result<(usize, usize, isize), anyhow::Error> {
         let source = Rc::new(source);
         // TODO-optimization it might be slightly faster to walk both trees in
         // sorted order, instead of walking one and doing lookups in the other.
         let mut ndupsok = 0;
-        for (hour, energy_wh) in source_map.into_iter() {
-            let hourly = self
-                .hourly_data
-                .entry(hour)
-                .or_insert(HourlyData { production: None, net_usage: None });
-
-            let datum = which(hourly);
-            if let Some((ref other_source, other_energy_wh)) = datum {
+        let mut nconflicts = 0;
+        let mut npartial = 0;
+        for (hour, energy_wh) in loaded.energy_by_hour.into_iter() {
+            let hourly = self.hourly_data.entry(hour).or_default();
+            // Flag the partial hours, but only if we wind up using the partial
+            // data (rather than data for the same hour from some other source).
+            let new_datum = Datum {
+                source: source.clone(),
+                energy: energy_wh,
+                partial: loaded.partial_hours.contains(&hour),
+            };
+
+            let datum = hourly.get_mut(channel);
+            if let Some(Datum {
+                source: ref other_source,
+                energy: other_energy_wh,
+                partial: other_partial,
+            }) = datum
+            {
                 if *other_energy_wh == energy_wh {
                     // The user provided overlapping data that matches
                     // exactly.  No problem -- ignore the new data point.
@@ -133,89 +366,194 @@ impl DataLoader {
                 // This seems like a mistake and we don't want to just add it in
                 // and produced garbage data...but at the same time, we've seen
                 // this in real Enphase production data (results differing by
-                // 0.1% when fetched weeks later).
-                // TODO-dap record this in "nwarnings" or something
-                eprintln!(
+                // 0.1% when fetched weeks later).  The user gets to decide
+                // what to do about it.
+                nconflicts += 1;
+                let message = format!(
                     "found different data from two different sources for \
-                        the same time period (hour = {}, source {:?} reports \
-                        {:?} Wh, source {:?} reports {:?} Wh)",
+                        the same time period (channel = {:?}, hour = {}, \
+                        source {:?} reports {:?} Wh, source {:?} reports {:?} \
+                        Wh)",
+                    self.schema.get(channel).name,
                     hour,
                     other_source,
                     other_energy_wh,
                     source,
                     energy_wh
                 );
+                match self.conflict_policy {
+                    ConflictPolicy::Error => bail!("{}", message),
+                    ConflictPolicy::KeepFirst => {
+                        eprintln!("{} (using {:?})", message, other_source);
+                    }
+                    ConflictPolicy::KeepLast => {
+                        eprintln!("{} (using {:?})", message, source);
+                        npartial += isize::from(new_datum.partial)
+                            - isize::from(*other_partial);
+                        *datum = Some(new_datum);
+                    }
+                }
             } else {
-                *datum = Some((source.clone(), energy_wh));
+                npartial += isize::from(new_datum.partial);
+                *datum = Some(new_datum);
             }
         }
 
-        Ok(ndupsok)
+        Ok((ndupsok, nconflicts, npartial))
+    }
+
+    /// Limits the data produced by the iterators to hours starting at or
+    /// after `from` and before `to`
+    pub fn set_time_range(
+        &mut self,
+        from: Option<chrono::DateTime<chrono::Utc>>,
+        to: Option<chrono::DateTime<chrono::Utc>>,
+    ) {
+        self.time_range = (
+            from.map(Bound::Included).unwrap_or(Bound::Unbounded),
+            to.map(Bound::Excluded).unwrap_or(Bound::Unbounded),
+        );
+    }
+
+    /// Iterates the data in buckets chosen by `bucketer`
+    ///
+    /// This produces one record for each run of consecutive hours that
+    /// `bucketer` puts in the same bucket.  Hours that it leaves out are
+    /// skipped (and don't break up a run).  This is intended for bucketing by
+    /// periods of time, with each key being the start of the period.  See
+    /// [`DataLoader::aggregate()`] for totaling up hours that aren't
+    /// necessarily consecutive.
+    pub fn buckets<'a, B>(&'a self, bucketer: B) -> DataIterator<'a>
+    where
+        B: Bucketer<Key = NaiveDateTime> + 'a,
+    {
+        DataIterator::new(self, Box::new(bucketer))
+    }
+
+    /// Totals up the data for each key produced by `bucketer`
+    ///
+    /// Unlike [`DataLoader::buckets()`], hours with the same key need not be
+    /// consecutive.  Hours that `bucketer` leaves out are skipped.  Hours with
+    /// data only for channels that don't affect the totals (like sub-meters)
+    /// are included in the channel totals, but not in `nhours`, so a key
+    /// with `nhours == 0` has none of the data that the totals come from.
+    pub fn aggregate<B>(&self, bucketer: B) -> BTreeMap<B::Key, EnergyTotals>
+    where
+        B: Bucketer,
+        B::Key: Ord,
+    {
+        let mut rv = BTreeMap::new();
+        let hours = self.hourly_data.range(self.time_range);
+        for (hour_start, hourly_energy) in hours {
+            if let Some(key) = bucketer.bucket(hour_start) {
+                rv.entry(key)
+                    .or_insert_with(EnergyTotals::default)
+                    .add_hour(&self.schema, hourly_energy);
+            }
+        }
+        rv
     }
 
     pub fn years(&self) -> DataIterator<'_> {
-        DataIterator::new(&self, hour_bucket_local_year)
+        self.buckets(bucket::Years)
     }
 
     pub fn months(&self) -> DataIterator<'_> {
-        DataIterator::new(&self, hour_bucket_local_month)
+        self.buckets(bucket::Months)
+    }
+
+    /// Iterates ISO weeks (which start on Monday)
+    pub fn weeks(&self) -> DataIterator<'_> {
+        self.buckets(bucket::IsoWeeks)
+    }
+
+    /// Iterates meteorological seasons (see [`bucket::Season`])
+    pub fn seasons(&self) -> DataIterator<'_> {
+        self.buckets(bucket::Seasons)
     }
 
     pub fn days(&self) -> DataIterator<'_> {
-        DataIterator::new(&self, hour_bucket_local_day)
+        self.buckets(bucket::Days)
     }
 
     pub fn hours(&self) -> DataIterator<'_> {
-        DataIterator::new(&self, hour_bucket_local_hour)
+        self.buckets(bucket::Hours)
+    }
+
+    /// Iterates billing cycles, assuming the meter is read on day `read_day`
+    /// of each month (see [`bucket::billing_cycle_start()`])
+    pub fn billing_cycles(&self, read_day: u32) -> DataIterator<'_> {
+        self.buckets(bucket::BillingCycles { read_day })
+    }
+
+    /// Iterates true-up years (see [`bucket::TrueUpYears`])
+    pub fn true_up_years(
+        &self,
+        true_up_date: chrono::NaiveDate,
+    ) -> DataIterator<'_> {
+        self.buckets(bucket::TrueUpYears { true_up_date })
+    }
+}
+
+/// Describes the records loaded from one source by [`load_records()`]
+#[derive(Default)]
+pub struct LoadedRecords {
+    /// total energy for each hour
+    pub energy_by_hour: BTreeMap<chrono::DateTime<chrono::Utc>, WattHours>,
+    /// hours for which any record was reported as partial
+    pub partial_hours: BTreeSet<chrono::DateTime<chrono::Utc>>,
+    pub nrecords: usize,
+    pub nwarnings: usize,
+}
+
+impl LoadedRecords {
+    /// Adds one record to the total for its hour
+    pub fn add(&mut self, record: Energy) {
+        let start = &record.datetime;
+        let key_timestamp = start
+            .with_minute(0)
+            .unwrap()
+            .with_second(0)
+            .unwrap()
+            .with_nanosecond(0)
+            .unwrap();
+        // TODO-optimization In almost all cases, there will only ever be one
+        // record in the input for a given hour, and it comes in sorted.  (The
+        // only time we currently see a dup here is for the one hour per year
+        // when DST goes backwards.)  So we don't really need to build a whole
+        // map and then merge it into the canonical one.  We could merge
+        // entries one-by-one into the canonical one.  This approach ensures
+        // that we (1) correctly record the extra DST-backwards record, (2)
+        // correctly ignore totally duplicate data (e.g., when someone has
+        // provided CSVs with overlapping dates with the same data), and (3)
+        // correctly identify overlapping, non-identical data.
+        let hourly = self
+            .energy_by_hour
+            .entry(key_timestamp)
+            .or_insert_with(|| WattHours::from(0));
+        *hourly += record.energy_wh;
+        if record.partial {
+            self.partial_hours.insert(key_timestamp);
+        }
+        self.nrecords += 1;
     }
 }
 
-// TODO-cleanup use a struct here
-pub fn load_records<I>(
-    iter: I,
-) -> (BTreeMap<chrono::DateTime<chrono::Utc>, WattHours>, usize, usize)
+pub fn load_records<I>(iter: I) -> LoadedRecords
 where
     I: Iterator<Item = Result<Energy, anyhow::Error>>,
 {
-    let mut source_map = BTreeMap::new();
-    let mut nrecords = 0;
-    let mut nwarnings = 0;
+    let mut loaded = LoadedRecords::default();
     for record in iter {
         match record {
-            Ok(r) => {
-                let start = &r.datetime;
-                let key_timestamp = start
-                    .with_minute(0)
-                    .unwrap()
-                    .with_second(0)
-                    .unwrap()
-                    .with_nanosecond(0)
-                    .unwrap();
-                // TODO-optimization In almost all cases, there will only ever
-                // be one record in the input for a given hour, and it comes in
-                // sorted.  (The only time we currently see a dup here is for
-                // the one hour per year when DST goes backwards.)  So we don't
-                // really need to build a whole map and then merge it into the
-                // canonical one.  We could merge entries one-by-one into the
-                // canonical one.  This approach ensures that we (1) correctly
-                // record the extra DST-backwards record, (2) correctly ignore
-                // totally duplicate data (e.g., when someone has provided CSVs
-                // with overlapping dates with the same data), and (3) correctly
-                // identify overlapping, non-identical data.
-                let hourly = source_map
-                    .entry(key_timestamp)
-                    .or_insert_with(|| WattHours::from(0));
-                *hourly += r.energy_wh;
-                nrecords += 1;
-            }
+            Ok(r) => loaded.add(r),
             Err(error) => {
                 eprintln!("warn: {:#}", error);
-                nwarnings += 1;
+                loaded.nwarnings += 1;
             }
         }
     }
-
-    (source_map, nrecords, nwarnings)
+    loaded
 }
 
 #[derive(serde::Serialize)]
@@ -224,128 +562,429 @@ pub struct IntervalEnergy {
     pub produced: WattHours,
     pub net_used: WattHours,
     pub consumed: WattHours,
+    /// number of hours in this interval for which production data was
+    /// reported as partial (so `produced` is probably too low)
+    pub production_partial_hours: usize,
+    /// totals for each channel, by channel name (not included in reports,
+    /// since the channels vary)
+    #[serde(skip)]
+    pub channels: BTreeMap<String, ChannelTotals>,
+}
+
+/// Totals for one channel over a group of hours
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct ChannelTotals {
+    pub energy: WattHours,
+    /// number of hours for which this channel has data
+    pub nhours: usize,
+    /// number of hours for which this channel's data was reported as partial
+    pub partial_hours: usize,
+}
+
+/// Totals for a group of hours
+///
+/// The totals other than `channels` are derived from the channels according
+/// to their kinds (see [`crate::channel`]).
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct EnergyTotals {
+    pub produced: WattHours,
+    pub net_used: WattHours,
+    pub consumed: WattHours,
+    /// number of hours for which production data was reported as partial (so
+    /// `produced` is probably too low)
+    pub production_partial_hours: usize,
+    /// number of hours for which we have production data
+    pub production_hours: usize,
+    /// number of hours for which we have net usage data
+    pub net_usage_hours: usize,
+    /// energy delivered by EV chargers (already included in `consumed`)
+    pub ev_charged: WattHours,
+    /// number of hours for which we have EV charging data
+    pub ev_charging_hours: usize,
+    /// number of hours with data for any channel that affects the totals
+    /// (hours with only sub-meter or EV charging data aren't counted)
+    pub nhours: usize,
+    /// totals for each channel, by channel name
+    pub channels: BTreeMap<String, ChannelTotals>,
+}
+
+impl EnergyTotals {
+    fn add_hour(&mut self, schema: &ChannelSchema, hourly_energy: &HourlyData) {
+        let mut has_kind = BTreeSet::new();
+        let mut production_partial = false;
+        for (index, datum) in hourly_energy.data() {
+            let channel = schema.get(index);
+            let energy = datum.energy;
+            match channel.kind {
+                ChannelKind::Production => {
+                    self.produced += energy;
+                    production_partial |= datum.partial;
+                }
+                ChannelKind::NetUsage => self.net_used += energy,
+                ChannelKind::EvCharging => self.ev_charged += energy,
+                _ => (),
+            }
+            match channel.kind.consumption_sign() {
+                1 => self.consumed += energy,
+                -1 => self.consumed -= energy,
+                _ => (),
+            }
+            has_kind.insert(channel.kind);
+
+            if !self.channels.contains_key(&channel.name) {
+                self.channels
+                    .insert(channel.name.clone(), ChannelTotals::default());
+            }
+            let totals = self.channels.get_mut(&channel.name).unwrap();
+            totals.energy += energy;
+            totals.nhours += 1;
+            totals.partial_hours += usize::from(datum.partial);
+        }
+        let count = |kind| usize::from(has_kind.contains(&kind));
+        let affects_totals = has_kind.iter().any(|k| k.consumption_sign() != 0);
+        self.production_partial_hours += usize::from(production_partial);
+        self.production_hours += count(ChannelKind::Production);
+        self.net_usage_hours += count(ChannelKind::NetUsage);
+        self.ev_charging_hours += count(ChannelKind::EvCharging);
+        self.nhours += usize::from(affects_totals);
+    }
 }
 
 pub struct DataIterator<'a> {
     iter: Peekable<
-        std::collections::btree_map::Iter<
+        std::collections::btree_map::Range<
             'a,
             chrono::DateTime<chrono::Utc>,
             HourlyData,
         >,
     >,
-    bucket_time: fn(&chrono::DateTime<chrono::Utc>) -> NaiveDateTime,
+    schema: &'a ChannelSchema,
+    bucketer: Box<dyn Bucketer<Key = NaiveDateTime> + 'a>,
 }
 
 impl<'a> DataIterator<'a> {
     fn new(
         aggr: &'a DataLoader,
-        bucket_time: fn(&chrono::DateTime<chrono::Utc>) -> NaiveDateTime,
+        bucketer: Box<dyn Bucketer<Key = NaiveDateTime> + 'a>,
     ) -> DataIterator<'a> {
-        DataIterator { iter: aggr.hourly_data.iter().peekable(), bucket_time }
+        DataIterator {
+            iter: aggr.hourly_data.range(aggr.time_range).peekable(),
+            schema: &aggr.schema,
+            bucketer,
+        }
     }
 }
 
-// TODO-coverage write tests
-fn summarize_hourly_energy(
+/// Returns the bucket for an hour, or `None` if it should be skipped
+///
+/// Hours with data only for channels that don't affect the totals (like
+/// sub-meters) are skipped so that they don't show up as hours with no
+/// production or consumption.
+fn bucket_hour(
+    bucketer: &dyn Bucketer<Key = NaiveDateTime>,
+    schema: &ChannelSchema,
+    hour_start: &chrono::DateTime<chrono::Utc>,
     hourly_energy: &HourlyData,
-) -> (WattHours, WattHours) {
-    let produced = hourly_energy
-        .production
-        .as_ref()
-        .map(|(_, wh)| *wh)
-        .unwrap_or_else(|| WattHours::from(0i32));
-    let net_used = hourly_energy
-        .net_usage
-        .as_ref()
-        .map(|(_, wh)| *wh)
-        .unwrap_or_else(|| WattHours::from(0i32));
-    (produced, net_used)
-}
-
-// TODO-coverage write tests
-fn hour_bucket_local_year(
-    start_utc: &chrono::DateTime<chrono::Utc>,
-) -> NaiveDateTime {
-    start_utc
-        .with_timezone(&chrono::Local)
-        .naive_local()
-        .with_day(1)
-        .unwrap()
-        .with_hour(0)
-        .unwrap()
-        .with_month(1)
-        .unwrap()
-}
-
-// TODO-coverage write tests
-fn hour_bucket_local_month(
-    start_utc: &chrono::DateTime<chrono::Utc>,
-) -> NaiveDateTime {
-    start_utc
-        .with_timezone(&chrono::Local)
-        .naive_local()
-        .with_day(1)
-        .unwrap()
-        .with_hour(0)
-        .unwrap()
-}
-
-// TODO-coverage write tests
-fn hour_bucket_local_day(
-    start_utc: &chrono::DateTime<chrono::Utc>,
-) -> NaiveDateTime {
-    start_utc.with_timezone(&chrono::Local).naive_local().with_hour(0).unwrap()
-}
-
-// TODO-coverage write tests
-fn hour_bucket_local_hour(
-    start_utc: &chrono::DateTime<chrono::Utc>,
-) -> NaiveDateTime {
-    assert_eq!(start_utc.minute(), 0);
-    assert_eq!(start_utc.second(), 0);
-    assert_eq!(start_utc.nanosecond(), 0);
-    start_utc.with_timezone(&chrono::Local).naive_local()
+) -> Option<NaiveDateTime> {
+    if hourly_energy
+        .data()
+        .all(|(index, _)| schema.get(index).kind.consumption_sign() == 0)
+    {
+        return None;
+    }
+    bucketer.bucket(hour_start)
 }
 
 impl<'a> Iterator for DataIterator<'a> {
     type Item = IntervalEnergy;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let (hour_start, hourly_energy) = match self.iter.next() {
-            Some(n) => n,
-            None => return None,
+        let schema = self.schema;
+        let (start_bucket, mut totals) = loop {
+            let (hour_start, hourly_energy) = self.iter.next()?;
+            if let Some(bucket) =
+                bucket_hour(&*self.bucketer, schema, hour_start, hourly_energy)
+            {
+                let mut totals = EnergyTotals::default();
+                totals.add_hour(schema, hourly_energy);
+                break (bucket, totals);
+            }
         };
 
-        // TODO-cleanup this whole thing could be written much cleaner
-        let start_bucket = (self.bucket_time)(hour_start);
-        let (produced, net_used) = summarize_hourly_energy(hourly_energy);
-        let mut rv = IntervalEnergy {
+        while let Some((peek_start, energy)) = self.iter.peek() {
+            match bucket_hour(&*self.bucketer, schema, peek_start, energy) {
+                None => (),
+                Some(bucket) if bucket == start_bucket => {
+                    totals.add_hour(schema, energy)
+                }
+                Some(_) => break,
+            }
+            self.iter.next();
+        }
+
+        Some(IntervalEnergy {
             interval_start: start_bucket,
-            produced,
-            net_used,
-            consumed: WattHours::from(0i32),
-        };
+            produced: totals.produced,
+            net_used: totals.net_used,
+            consumed: totals.consumed,
+            production_partial_hours: totals.production_partial_hours,
+            channels: totals.channels,
+        })
+    }
+}
 
-        rv.produced += produced;
-        rv.net_used += net_used;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
 
-        while let Some((peek_start, _)) = self.iter.peek() {
-            let peek_start_bucket = (self.bucket_time)(*peek_start);
-            if peek_start_bucket != start_bucket {
-                break;
-            }
+    /// Returns the start of hour `h` (UTC) on an arbitrary day
+    fn hour(h: u32) -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc.ymd(2022, 6, 1).and_hms(h, 0, 0)
+    }
 
-            let (start, energy) = self.iter.next().unwrap();
-            assert_eq!((self.bucket_time)(start), start_bucket);
-            let (produced, net_used) = summarize_hourly_energy(energy);
-            rv.produced += produced;
-            rv.net_used += net_used;
-        }
+    /// Loads `(hour, Wh)` records into a channel called `name`
+    fn load(
+        aggr: &mut DataLoader,
+        name: &str,
+        kind: ChannelKind,
+        records: &[(u32, i32)],
+    ) {
+        let channel = Channel { name: name.to_owned(), kind };
+        let records = records.iter().map(|(h, wh)| {
+            Ok(Energy {
+                datetime: hour(*h),
+                energy_wh: WattHours::from(*wh),
+                partial: false,
+                channel: None,
+            })
+        });
+        aggr.load_energy(&channel, false, name, records).unwrap();
+    }
 
-        rv.consumed += rv.net_used;
-        rv.consumed += rv.produced;
+    /// Returns a bucketer that puts every hour into the same bucket
+    fn one_bucket(
+    ) -> impl Fn(&chrono::DateTime<chrono::Utc>) -> Option<NaiveDateTime> {
+        |_| Some(chrono::NaiveDate::from_ymd(2022, 6, 1).and_hms(0, 0, 0))
+    }
+
+    #[test]
+    fn test_production_channels_sum() {
+        let mut aggr = DataLoader::new();
+        let production = ChannelKind::Production;
+        load(&mut aggr, "roof", production, &[(10, 100), (11, 200)]);
+        load(&mut aggr, "garage", production, &[(11, 30), (12, 40)]);
+        load(&mut aggr, "net-usage", ChannelKind::NetUsage, &[(11, -50)]);
+
+        let produced: Vec<_> =
+            aggr.hours().map(|i| i.produced.as_wh()).collect();
+        assert_eq!(produced, vec![100, 230, 40]);
+
+        let interval = aggr.buckets(one_bucket()).next().unwrap();
+        assert_eq!(interval.produced.as_wh(), 370);
+        assert_eq!(interval.net_used.as_wh(), -50);
+        assert_eq!(interval.consumed.as_wh(), 320);
+        assert_eq!(interval.channels["roof"].energy.as_wh(), 300);
+        assert_eq!(interval.channels["garage"].energy.as_wh(), 70);
+        assert_eq!(interval.channels["garage"].nhours, 2);
+    }
+
+    #[test]
+    fn test_battery_consumption() {
+        let mut aggr = DataLoader::new();
+        load(&mut aggr, "p", ChannelKind::Production, &[(10, 500)]);
+        load(&mut aggr, "n", ChannelKind::NetUsage, &[(10, -100), (20, 300)]);
+        load(&mut aggr, "charge", ChannelKind::BatteryCharge, &[(10, 200)]);
+        load(
+            &mut aggr,
+            "discharge",
+            ChannelKind::BatteryDischarge,
+            &[(20, 150)],
+        );
+
+        // Charging the battery uses some of what was produced, and
+        // discharging it supplies some of what was consumed.
+        let hours: Vec<_> = aggr
+            .hours()
+            .map(|i| {
+                (i.produced.as_wh(), i.net_used.as_wh(), i.consumed.as_wh())
+            })
+            .collect();
+        assert_eq!(hours, vec![(500, -100, 200), (0, 300, 450)]);
+    }
+
+    #[test]
+    fn test_sub_meter_only_hours_skipped() {
+        let mut aggr = DataLoader::new();
+        let sub_meter = ChannelKind::SubMeter;
+        load(&mut aggr, "n", ChannelKind::NetUsage, &[(10, 400), (12, 500)]);
+        load(&mut aggr, "dryer", sub_meter, &[(10, 100), (11, 300), (12, 200)]);
+
+        // The hour with only sub-meter data doesn't show up as an hour with
+        // no consumption.
+        let hours: Vec<_> = aggr
+            .hours()
+            .map(|i| (i.consumed.as_wh(), i.channels["dryer"].energy.as_wh()))
+            .collect();
+        assert_eq!(hours, vec![(400, 100), (500, 200)]);
+
+        // It doesn't count toward any bucket, either.
+        let interval = aggr.buckets(one_bucket()).next().unwrap();
+        assert_eq!(interval.consumed.as_wh(), 900);
+        assert_eq!(interval.channels["dryer"].energy.as_wh(), 300);
+        assert_eq!(interval.channels["dryer"].nhours, 2);
+    }
+
+    #[test]
+    fn test_production_and_net_usage_totals() {
+        // With just production and net usage data, loaded the way that
+        // `report` always has, the totals are the same as they were before
+        // there were channels: consumption is production plus net usage.
+        let produced = [(9, 120, false), (10, 800, false), (11, 950, true)];
+        let net_used = [(8, 300), (9, 150), (10, -400), (11, -500), (12, 200)];
+        let mut aggr = DataLoader::new();
+        let records = produced.iter().map(|(h, wh, partial)| {
+            Ok(EnergyProduced {
+                datetime_utc: hour(*h),
+                datetime_local: hour(*h).with_timezone(&chrono::Local),
+                energy_wh: WattHours::from(*wh),
+                devices_reporting: None,
+                quality: Some(if *partial {
+                    crate::common::Quality::Partial
+                } else {
+                    crate::common::Quality::Ok
+                }),
+            })
+        });
+        aggr.load_production(Source::new("production.csv"), records).unwrap();
+        let records = net_used.iter().map(|(h, wh)| {
+            Ok(NetEnergyUsed {
+                timestamp_start_utc: hour(*h),
+                net_used_wh: WattHours::from(*wh),
+            })
+        });
+        aggr.load_net_usage(Source::new("pge.csv"), records).unwrap();
+
+        let hours: Vec<_> = aggr
+            .hours()
+            .map(|i| {
+                (
+                    i.produced.as_wh(),
+                    i.net_used.as_wh(),
+                    i.consumed.as_wh(),
+                    i.production_partial_hours,
+                )
+            })
+            .collect();
+        assert_eq!(
+            hours,
+            vec![
+                (0, 300, 300, 0),
+                (120, 150, 270, 0),
+                (800, -400, 400, 0),
+                (950, -500, 450, 1),
+                (0, 200, 200, 0),
+            ]
+        );
+
+        let interval = aggr.buckets(one_bucket()).next().unwrap();
+        assert_eq!(interval.produced.as_wh(), 1870);
+        assert_eq!(interval.net_used.as_wh(), -250);
+        assert_eq!(interval.consumed.as_wh(), 1620);
+        assert_eq!(interval.production_partial_hours, 1);
+
+        let totals = aggr.aggregate(one_bucket());
+        let totals = totals.values().next().unwrap();
+        assert_eq!(totals.consumed.as_wh(), 1620);
+        assert_eq!(totals.nhours, 5);
+        assert_eq!(totals.production_hours, 3);
+        assert_eq!(totals.net_usage_hours, 5);
+    }
+
+    #[test]
+    fn test_has_full_day_of_production() {
+        let noon = |d: u32| {
+            chrono::Local
+                .ymd(2022, 6, d)
+                .and_hms(12, 0, 0)
+                .with_timezone(&chrono::Utc)
+        };
+        let record = |t: chrono::DateTime<chrono::Utc>, wh: i32| {
+            Ok(Energy {
+                datetime: t,
+                energy_wh: WattHours::from(wh),
+                partial: false,
+                channel: None,
+            })
+        };
+        let mut aggr = DataLoader::new();
+        let production = Channel {
+            name: String::from("production"),
+            kind: ChannelKind::Production,
+        };
+        let records = [9, 10, 11].map(|d| record(noon(d), 100));
+        aggr.load_energy(&production, false, "p", records.into_iter()).unwrap();
+        let sub_meter = Channel {
+            name: String::from("dryer"),
+            kind: ChannelKind::SubMeter,
+        };
+        let records = [20, 21, 22].map(|d| record(noon(d), 100));
+        aggr.load_energy(&sub_meter, false, "s", records.into_iter()).unwrap();
+
+        // Whatever the time zone, the UTC days overlapping the 10th have
+        // production data (even though it's only for one hour of each).
+        let date = |d: u32| chrono::NaiveDate::from_ymd(2022, 6, d);
+        assert!(aggr.has_full_day_of_production(&date(10)));
+        // Data for other channels doesn't count.
+        assert!(!aggr.has_full_day_of_production(&date(21)));
+        assert!(!aggr.has_full_day_of_production(&date(15)));
+    }
+
+    #[test]
+    fn test_aggregate_sub_meter_only_hours() {
+        let mut aggr = DataLoader::new();
+        load(&mut aggr, "p", ChannelKind::Production, &[(10, 100)]);
+        load(&mut aggr, "ev", ChannelKind::EvCharging, &[(10, 50), (20, 70)]);
+
+        let totals =
+            aggr.aggregate(|_: &chrono::DateTime<chrono::Utc>| Some(()));
+        let totals = &totals[&()];
+        // The EV-only hour shows up in the EV totals, but not in `nhours`.
+        assert_eq!(totals.nhours, 1);
+        assert_eq!(totals.production_hours, 1);
+        assert_eq!(totals.ev_charging_hours, 2);
+        assert_eq!(totals.ev_charged.as_wh(), 120);
+        assert_eq!(totals.channels["ev"].nhours, 2);
+        assert_eq!(totals.consumed.as_wh(), 100);
+
+        // A key with only EV data has no hours.
+        let hourly =
+            aggr.aggregate(|t: &chrono::DateTime<chrono::Utc>| Some(*t));
+        assert_eq!(hourly[&hour(20)].nhours, 0);
+    }
 
-        Some(rv)
+    #[test]
+    fn test_buckets_count_each_hour_once() {
+        let mut aggr = DataLoader::new();
+        let production = ChannelKind::Production;
+        let net_usage = ChannelKind::NetUsage;
+        load(&mut aggr, "p", production, &[(10, 100), (11, 200), (12, 300)]);
+        load(&mut aggr, "n", net_usage, &[(10, -50), (11, -60), (12, -70)]);
+
+        // Put everything into one bucket.  (Older versions counted the first
+        // hour of each bucket twice.)
+        let start = chrono::NaiveDate::from_ymd(2022, 6, 1).and_hms(0, 0, 0);
+        let all = move |_: &chrono::DateTime<chrono::Utc>| Some(start);
+        let intervals: Vec<_> = aggr.buckets(all).collect();
+        assert_eq!(intervals.len(), 1);
+        assert_eq!(intervals[0].interval_start, start);
+        assert_eq!(intervals[0].produced.as_wh(), 600);
+        assert_eq!(intervals[0].net_used.as_wh(), -180);
+        assert_eq!(intervals[0].consumed.as_wh(), 420);
+
+        // Each hour is its own bucket.
+        let produced: Vec<_> =
+            aggr.hours().map(|i| i.produced.as_wh()).collect();
+        assert_eq!(produced, vec![100, 200, 300]);
     }
 }
@@ -0,0 +1,463 @@
+//! Generic interface to sources of energy data
+//!
+//! Each supported file format provides a [`DataSource`], which describes what
+//! the data measures and produces source-agnostic [`Energy`] records.  The
+//! [`Registry`] knows about all of the supported formats and can figure out
+//! which one a file uses by looking at its contents.  To support a new format,
+//! implement [`DataSource`] for it and add a [`Format`] to the registry.
+//!
+//! Each source's records go into a channel (see [`crate::channel`]) named
+//! after the kind of data it contains, unless the records name their own
+//! channels (as for files with one column per circuit).
+
+use crate::channel::ChannelKind;
+use crate::common::Energy;
+use crate::common::NetEnergyUsed;
+use crate::common::SolarProductionReader;
+use crate::common::WattHours;
+use crate::ev_charging::ChargingSession;
+use crate::pge::ElectricityUsageReader;
+use anyhow::Context;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+
+/// A source of energy records (usually a file)
+pub trait DataSource {
+    /// Returns what this source measures
+    fn kind(&self) -> ChannelKind;
+
+    /// Returns the name of the channel that this source's records go into
+    fn channel(&self) -> &str {
+        self.kind().name()
+    }
+
+    /// Returns where this data came from (e.g., a filename)
+    fn provenance(&self) -> &str;
+
+    /// Returns the width of the interval covered by each record
+    fn interval(&self) -> chrono::Duration;
+
+    /// Returns an iterator over the records from this source
+    fn records(
+        &mut self,
+    ) -> Box<dyn Iterator<Item = Result<Energy, anyhow::Error>> + '_>;
+}
+
+/// Production data in our own CSV format, as written by `enphase-fetch` and
+/// `envoy-poll`
+pub struct EnphaseCsvSource<R> {
+    provenance: String,
+    reader: SolarProductionReader<R>,
+}
+
+impl<R: Read> EnphaseCsvSource<R> {
+    pub fn new(provenance: &str, input: R) -> EnphaseCsvSource<R> {
+        EnphaseCsvSource {
+            provenance: provenance.to_owned(),
+            reader: SolarProductionReader::new(input),
+        }
+    }
+}
+
+impl<R: Read> DataSource for EnphaseCsvSource<R> {
+    fn kind(&self) -> ChannelKind {
+        ChannelKind::Production
+    }
+
+    fn provenance(&self) -> &str {
+        &self.provenance
+    }
+
+    fn interval(&self) -> chrono::Duration {
+        chrono::Duration::minutes(5)
+    }
+
+    fn records(
+        &mut self,
+    ) -> Box<dyn Iterator<Item = Result<Energy, anyhow::Error>> + '_> {
+        Box::new(self.reader.records().map(|r| r.map(Energy::from)))
+    }
+}
+
+/// Net usage data from PG&E's "Green Button" CSV download
+pub struct PgeCsvSource<R> {
+    provenance: String,
+    reader: ElectricityUsageReader<R>,
+}
+
+impl<R: Read> PgeCsvSource<R> {
+    pub fn new(
+        provenance: &str,
+        input: R,
+    ) -> Result<PgeCsvSource<R>, anyhow::Error> {
+        Ok(PgeCsvSource {
+            provenance: provenance.to_owned(),
+            reader: ElectricityUsageReader::new(input)?,
+        })
+    }
+}
+
+impl<R: Read> DataSource for PgeCsvSource<R> {
+    fn kind(&self) -> ChannelKind {
+        ChannelKind::NetUsage
+    }
+
+    fn provenance(&self) -> &str {
+        &self.provenance
+    }
+
+    fn interval(&self) -> chrono::Duration {
+        chrono::Duration::hours(1)
+    }
+
+    fn records(
+        &mut self,
+    ) -> Box<dyn Iterator<Item = Result<Energy, anyhow::Error>> + '_> {
+        Box::new(self.reader.records().map(|r| r.map(Energy::from)))
+    }
+}
+
+/// Net usage data in our own CSV format, as written by `pge-munge`
+pub struct NetUsageCsvSource<R> {
+    provenance: String,
+    reader: csv::Reader<R>,
+}
+
+impl<R: Read> NetUsageCsvSource<R> {
+    pub fn new(provenance: &str, input: R) -> NetUsageCsvSource<R> {
+        NetUsageCsvSource {
+            provenance: provenance.to_owned(),
+            reader: csv::Reader::from_reader(input),
+        }
+    }
+}
+
+impl<R: Read> DataSource for NetUsageCsvSource<R> {
+    fn kind(&self) -> ChannelKind {
+        ChannelKind::NetUsage
+    }
+
+    fn provenance(&self) -> &str {
+        &self.provenance
+    }
+
+    fn interval(&self) -> chrono::Duration {
+        chrono::Duration::hours(1)
+    }
+
+    fn records(
+        &mut self,
+    ) -> Box<dyn Iterator<Item = Result<Energy, anyhow::Error>> + '_> {
+        Box::new(self.reader.deserialize::<NetEnergyUsed>().map(|r| {
+            r.map(Energy::from).context("reading record from net usage file")
+        }))
+    }
+}
+
+/// EV charging sessions in a generic CSV format (see
+/// [`crate::ev_charging`])
+///
+/// Each session becomes one record for each hour that it covers.
+pub struct EvSessionCsvSource<R> {
+    provenance: String,
+    reader: csv::Reader<R>,
+}
+
+impl<R: Read> EvSessionCsvSource<R> {
+    pub fn new(provenance: &str, input: R) -> EvSessionCsvSource<R> {
+        EvSessionCsvSource {
+            provenance: provenance.to_owned(),
+            reader: csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(input),
+        }
+    }
+}
+
+impl<R: Read> DataSource for EvSessionCsvSource<R> {
+    fn kind(&self) -> ChannelKind {
+        ChannelKind::EvCharging
+    }
+
+    fn provenance(&self) -> &str {
+        &self.provenance
+    }
+
+    fn interval(&self) -> chrono::Duration {
+        chrono::Duration::hours(1)
+    }
+
+    fn records(
+        &mut self,
+    ) -> Box<dyn Iterator<Item = Result<Energy, anyhow::Error>> + '_> {
+        Box::new(self.reader.deserialize::<ChargingSession>().flat_map(|r| {
+            match r
+                .context("reading record from EV charging session file")
+                .and_then(|session| session.hourly_energy())
+            {
+                Ok(records) => records.into_iter().map(Ok).collect(),
+                Err(error) => vec![Err(error)],
+            }
+        }))
+    }
+}
+
+/// Circuit-level sub-meter data in a generic CSV format
+///
+/// The first column is "datetime_utc" (the start of each interval, in RFC 3339
+/// format), and each of the others is a circuit, with a header like "Dryer
+/// (kWh)".  Each row covers at most an hour.  Empty cells are treated as
+/// missing data.  Exports from circuit-level monitors (like Emporia Vue or
+/// Sense) can be converted to this format by renaming their columns.  Each
+/// circuit becomes its own channel.
+pub struct SubMeterCsvSource<R> {
+    provenance: String,
+    reader: csv::Reader<R>,
+    /// circuit names, in the order of the columns after the first
+    circuits: Vec<String>,
+}
+
+/// Suffix on the header of each circuit's column in [`SubMeterCsvSource`]
+/// files
+const SUBMETER_COLUMN_SUFFIX: &str = "(kWh)";
+
+impl<R: Read> SubMeterCsvSource<R> {
+    pub fn new(
+        provenance: &str,
+        input: R,
+    ) -> Result<SubMeterCsvSource<R>, anyhow::Error> {
+        let mut reader =
+            csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(input);
+        let circuits = reader
+            .headers()
+            .context("reading header")?
+            .iter()
+            .skip(1)
+            .map(|h| {
+                h.trim_end_matches(SUBMETER_COLUMN_SUFFIX).trim().to_owned()
+            })
+            .collect();
+        Ok(SubMeterCsvSource {
+            provenance: provenance.to_owned(),
+            reader,
+            circuits,
+        })
+    }
+}
+
+impl<R: Read> DataSource for SubMeterCsvSource<R> {
+    fn kind(&self) -> ChannelKind {
+        ChannelKind::SubMeter
+    }
+
+    fn provenance(&self) -> &str {
+        &self.provenance
+    }
+
+    fn interval(&self) -> chrono::Duration {
+        chrono::Duration::hours(1)
+    }
+
+    fn records(
+        &mut self,
+    ) -> Box<dyn Iterator<Item = Result<Energy, anyhow::Error>> + '_> {
+        let circuits = &self.circuits;
+        Box::new(self.reader.records().flat_map(move |r| {
+            match r.context("reading record from sub-meter file") {
+                Ok(row) => submeter_records(circuits, &row),
+                Err(error) => vec![Err(error)],
+            }
+        }))
+    }
+}
+
+/// Returns one record for each circuit with data in a row of a
+/// [`SubMeterCsvSource`] file
+fn submeter_records(
+    circuits: &[String],
+    row: &csv::StringRecord,
+) -> Vec<Result<Energy, anyhow::Error>> {
+    let datetime = match row
+        .get(0)
+        .unwrap_or("")
+        .parse::<chrono::DateTime<chrono::Utc>>()
+        .with_context(|| format!("parsing datetime_utc in row {:?}", row))
+    {
+        Ok(datetime) => datetime,
+        Err(error) => return vec![Err(error)],
+    };
+    circuits
+        .iter()
+        .zip(row.iter().skip(1))
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(circuit, value)| {
+            let kwh: f64 = value.parse().with_context(|| {
+                format!("parsing energy for {:?} at {}", circuit, datetime)
+            })?;
+            Ok(Energy {
+                datetime,
+                energy_wh: WattHours::from_kwh(kwh),
+                partial: false,
+                channel: Some(circuit.clone()),
+            })
+        })
+        .collect()
+}
+
+/// Describes a supported file format
+pub struct Format {
+    /// short name for the format (used in messages)
+    pub name: &'static str,
+    /// returns whether the beginning of a file looks like this format
+    pub sniff: fn(&str) -> bool,
+    /// returns a [`DataSource`] for a file in this format
+    pub open: OpenFn,
+}
+
+/// Function that opens a file (described by `provenance`) as a [`DataSource`]
+pub type OpenFn = fn(
+    provenance: &str,
+    file: std::fs::File,
+) -> Result<Box<dyn DataSource>, anyhow::Error>;
+
+/// Number of bytes at the start of a file that we look at to figure out its
+/// format
+const SNIFF_LEN: u64 = 4096;
+
+/// Knows about all supported file formats
+pub struct Registry {
+    formats: Vec<Format>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+impl Registry {
+    /// Returns a registry of all the built-in formats
+    pub fn new() -> Registry {
+        let mut registry = Registry::empty();
+        registry.register(Format {
+            name: "enphase-csv",
+            sniff: |head| {
+                first_line(head)
+                    .starts_with("datetime_utc,datetime_local,energy_wh")
+            },
+            open: |provenance, file| {
+                Ok(Box::new(EnphaseCsvSource::new(provenance, file)))
+            },
+        });
+        registry.register(Format {
+            name: "pge-csv",
+            sniff: |head| {
+                // PG&E files start with a few lines describing the account.
+                head.lines().take(10).any(|line| {
+                    line.trim_start_matches('\u{feff}')
+                        .starts_with("TYPE,DATE,START TIME,END TIME,USAGE")
+                })
+            },
+            open: |provenance, file| {
+                Ok(Box::new(PgeCsvSource::new(provenance, file)?))
+            },
+        });
+        registry.register(Format {
+            name: "net-usage-csv",
+            sniff: |head| {
+                first_line(head).starts_with("timestamp_start_utc,net_used_wh")
+            },
+            open: |provenance, file| {
+                Ok(Box::new(NetUsageCsvSource::new(provenance, file)))
+            },
+        });
+        registry.register(Format {
+            name: "ev-sessions-csv",
+            sniff: |head| {
+                let columns: Vec<&str> = first_line(head)
+                    .split(',')
+                    .map(|c| c.trim().trim_matches('"'))
+                    .collect();
+                ["start", "end", "energy_kwh"]
+                    .iter()
+                    .all(|c| columns.contains(c))
+            },
+            open: |provenance, file| {
+                Ok(Box::new(EvSessionCsvSource::new(provenance, file)))
+            },
+        });
+        registry.register(Format {
+            name: "submeter-csv",
+            sniff: |head| {
+                let columns: Vec<&str> = first_line(head)
+                    .split(',')
+                    .map(|c| c.trim().trim_matches('"'))
+                    .collect();
+                columns.len() > 1
+                    && columns[0] == "datetime_utc"
+                    && columns[1..]
+                        .iter()
+                        .all(|c| c.ends_with(SUBMETER_COLUMN_SUFFIX))
+            },
+            open: |provenance, file| {
+                Ok(Box::new(SubMeterCsvSource::new(provenance, file)?))
+            },
+        });
+        registry
+    }
+
+    /// Returns a registry with no formats
+    pub fn empty() -> Registry {
+        Registry { formats: Vec::new() }
+    }
+
+    /// Adds support for a format
+    ///
+    /// Formats are tried in the order they were registered.
+    pub fn register(&mut self, format: Format) {
+        self.formats.push(format);
+    }
+
+    /// Returns the format whose sniffer accepts `head` (the beginning of a
+    /// file), if any
+    pub fn sniff(&self, head: &str) -> Option<&Format> {
+        self.formats.iter().find(|f| (f.sniff)(head))
+    }
+
+    /// Opens the file at `path` as a [`DataSource`], based on its contents
+    ///
+    /// Returns `None` if the file isn't in any supported format.
+    pub fn open(
+        &self,
+        path: &Path,
+    ) -> Result<Option<Box<dyn DataSource>>, anyhow::Error> {
+        let mut file = std::fs::File::open(path)
+            .with_context(|| format!("open {:?}", path.display()))?;
+        let mut head = Vec::new();
+        (&mut file)
+            .take(SNIFF_LEN)
+            .read_to_end(&mut head)
+            .with_context(|| format!("read {:?}", path.display()))?;
+        file.seek(SeekFrom::Start(0))
+            .with_context(|| format!("seek {:?}", path.display()))?;
+
+        let head = String::from_utf8_lossy(&head);
+        match self.sniff(&head) {
+            None => Ok(None),
+            Some(format) => {
+                let source = (format.open)(&path.display().to_string(), file)
+                    .with_context(|| {
+                    format!("open {:?} (as {})", path.display(), format.name)
+                })?;
+                Ok(Some(source))
+            }
+        }
+    }
+}
+
+/// Returns the first line of `head`, minus any byte order mark
+fn first_line(head: &str) -> &str {
+    head.lines().next().unwrap_or("").trim_start_matches('\u{feff}')
+}
@@ -0,0 +1,461 @@
+//! Attributing consumption to known appliances
+//!
+//! Each appliance is described by a signature: roughly how much power it draws
+//! and for how long it runs (e.g., an EV charger drawing 7.2 kW for at least
+//! two hours).  To find when an appliance ran, we estimate what the house would
+//! have used in each hour without it (the median for that hour of the day over
+//! the surrounding two weeks, since big appliances don't usually run at the
+//! same time every day) and look for runs of hours where the excess over that
+//! matches the signature.  The first and last hours of a run may be partial,
+//! since appliances don't start and stop on the hour.
+//!
+//! Signatures are matched in order of decreasing power, and energy attributed
+//! to one appliance isn't available to the others.  Since the data is hourly,
+//! appliances that run for much less than an hour, or that draw much less than
+//! the house's normal hour-to-hour variation, can't be detected.  This is
+//! rough, but it's enough to tell how much of the bill is (say) the EV.
+
+use crate::data_aggregator::DataLoader;
+use crate::stats;
+use anyhow::bail;
+use chrono::Datelike;
+use chrono::Timelike;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Days on either side of an hour used to estimate consumption without the
+/// appliance
+const BASELINE_WINDOW_DAYS: i64 = 7;
+
+/// Fraction of an appliance's power that counts as it running for part of an
+/// hour (at the start or end of a run)
+const PARTIAL_HOUR_FRACTION: f64 = 0.25;
+
+/// Describes how to recognize an appliance
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApplianceSignature {
+    /// name of the appliance (e.g., "EV charger")
+    pub name: String,
+    /// power drawn while running, in kilowatts
+    pub power_kw: f64,
+    /// how far the power can be from `power_kw`, as a percentage
+    #[serde(default = "default_tolerance_pct")]
+    pub tolerance_pct: f64,
+    /// minimum length of a run, in hours
+    #[serde(default = "default_min_hours")]
+    pub min_hours: f64,
+    /// maximum length of a run, in hours
+    #[serde(default)]
+    pub max_hours: Option<f64>,
+    /// months (1-12) in which the appliance runs (all months, if empty)
+    #[serde(default)]
+    pub months: Vec<u32>,
+}
+
+/// Checks that each signature makes sense and that no two have the same name
+pub fn validate_signatures(
+    signatures: &[ApplianceSignature],
+) -> Result<(), anyhow::Error> {
+    for (i, signature) in signatures.iter().enumerate() {
+        let name = &signature.name;
+        if signatures[..i].iter().any(|s| s.name == *name) {
+            bail!("appliance {:?} appears more than once", name);
+        }
+        if !(signature.power_kw > 0.0 && signature.power_kw.is_finite()) {
+            bail!(
+                "appliance {:?}: power_kw must be positive (got {})",
+                name,
+                signature.power_kw
+            );
+        }
+        if !(0.0..100.0).contains(&signature.tolerance_pct) {
+            bail!(
+                "appliance {:?}: tolerance_pct must be at least 0 and less \
+                 than 100 (got {})",
+                name,
+                signature.tolerance_pct
+            );
+        }
+        if let Some(month) =
+            signature.months.iter().find(|m| !(1..=12).contains(*m))
+        {
+            bail!(
+                "appliance {:?}: months must be from 1 to 12 (got {})",
+                name,
+                month
+            );
+        }
+    }
+    Ok(())
+}
+
+fn default_tolerance_pct() -> f64 {
+    25.0
+}
+
+fn default_min_hours() -> f64 {
+    1.0
+}
+
+/// Describes one time an appliance (probably) ran
+#[derive(Debug, Serialize)]
+pub struct ApplianceEvent {
+    pub appliance: String,
+    /// local time at the start of the first hour of the run
+    pub start: chrono::NaiveDateTime,
+    /// local time at the end of the last hour of the run
+    pub end: chrono::NaiveDateTime,
+    /// estimated running time, in hours (counting partial hours as fractions)
+    pub hours: f64,
+    pub energy_kwh: f64,
+    /// energy (in watt-hours) attributed to the appliance in each month that
+    /// the run overlaps
+    #[serde(skip)]
+    energy_by_month: BTreeMap<chrono::NaiveDate, f64>,
+}
+
+/// Energy attributed to one appliance (or to everything else) in one month
+#[derive(Debug, Serialize)]
+pub struct MonthlyBreakdown {
+    /// first day of the month
+    pub month: chrono::NaiveDate,
+    /// appliance name, or "other" for consumption not attributed to any
+    /// appliance
+    pub appliance: String,
+    pub nevents: usize,
+    pub energy_kwh: f64,
+    /// percentage of the month's total consumption
+    pub share_pct: f64,
+}
+
+/// One hour of consumption data
+struct Hour {
+    start_utc: chrono::DateTime<chrono::Utc>,
+    local: chrono::NaiveDateTime,
+    consumed: f64,
+    /// consumption not (yet) explained by the baseline or an appliance
+    excess: f64,
+}
+
+/// Finds likely appliance events in the loaded data and breaks down each
+/// month's consumption by appliance
+///
+/// The signatures should have been checked with [`validate_signatures()`].
+pub fn disaggregate(
+    aggr: &DataLoader,
+    signatures: &[ApplianceSignature],
+) -> (Vec<ApplianceEvent>, Vec<MonthlyBreakdown>) {
+    let mut hours: Vec<Hour> = aggr
+        .aggregate(|t: &chrono::DateTime<chrono::Utc>| Some(*t))
+        .into_iter()
+        .filter(|(_, totals)| {
+            totals.net_usage_hours > 0 && totals.production_partial_hours == 0
+        })
+        .map(|(start_utc, totals)| Hour {
+            start_utc,
+            local: start_utc.with_timezone(&chrono::Local).naive_local(),
+            consumed: totals.consumed.as_wh() as f64,
+            excess: 0.0,
+        })
+        .collect();
+
+    let by_local_hour: BTreeMap<(chrono::NaiveDate, u32), f64> = hours
+        .iter()
+        .map(|h| ((h.local.date(), h.local.hour()), h.consumed))
+        .collect();
+    for hour in &mut hours {
+        let date = hour.local.date();
+        let window = -BASELINE_WINDOW_DAYS..=BASELINE_WINDOW_DAYS;
+        let mut nearby: Vec<f64> = window
+            .filter(|d| *d != 0)
+            .filter_map(|d| {
+                let key = (date + chrono::Duration::days(d), hour.local.hour());
+                by_local_hour.get(&key).copied()
+            })
+            .collect();
+        stats::sort(&mut nearby);
+        let baseline = stats::percentile(&nearby, 50.0);
+        hour.excess = (hour.consumed - baseline).max(0.0);
+    }
+
+    let mut sorted: Vec<&ApplianceSignature> = signatures.iter().collect();
+    sorted.sort_by(|a, b| b.power_kw.total_cmp(&a.power_kw));
+    let mut events = Vec::new();
+    for signature in sorted {
+        find_events(&mut hours, signature, &mut events);
+    }
+    events.sort_by_key(|e| e.start);
+
+    let breakdown = monthly_breakdown(&hours, &events, signatures);
+    (events, breakdown)
+}
+
+/// Finds runs of hours matching `signature`, removes the energy attributed to
+/// it from the hours' excess, and appends the runs to `events`
+fn find_events(
+    hours: &mut [Hour],
+    signature: &ApplianceSignature,
+    events: &mut Vec<ApplianceEvent>,
+) {
+    let full_wh = signature.power_kw * 1000.0;
+    let tolerance = signature.tolerance_pct / 100.0;
+    let in_season = |h: &Hour| {
+        signature.months.is_empty()
+            || signature.months.contains(&h.local.month())
+    };
+    let is_full =
+        |h: &Hour| in_season(h) && h.excess >= full_wh * (1.0 - tolerance);
+    let is_partial =
+        |h: &Hour| in_season(h) && h.excess >= full_wh * PARTIAL_HOUR_FRACTION;
+    let follows = |a: &Hour, b: &Hour| {
+        b.start_utc - a.start_utc == chrono::Duration::hours(1)
+    };
+
+    let mut i = 0;
+    while i < hours.len() {
+        if !is_full(&hours[i]) {
+            i += 1;
+            continue;
+        }
+
+        // Find the run of full-power hours, then extend it by a partial hour
+        // on either end.
+        let mut first = i;
+        let mut last = i;
+        while last + 1 < hours.len()
+            && follows(&hours[last], &hours[last + 1])
+            && is_full(&hours[last + 1])
+        {
+            last += 1;
+        }
+        if first > 0
+            && follows(&hours[first - 1], &hours[first])
+            && is_partial(&hours[first - 1])
+        {
+            first -= 1;
+        }
+        if last + 1 < hours.len()
+            && follows(&hours[last], &hours[last + 1])
+            && is_partial(&hours[last + 1])
+        {
+            last += 1;
+        }
+
+        let max_wh = full_wh * (1.0 + tolerance);
+        let energy: Vec<f64> =
+            hours[first..=last].iter().map(|h| h.excess.min(max_wh)).collect();
+        let total: f64 = energy.iter().sum();
+        let running_hours = total / full_wh;
+        let long_enough = running_hours >= signature.min_hours;
+        let short_enough =
+            !matches!(signature.max_hours, Some(max) if running_hours > max);
+        if long_enough && short_enough {
+            let mut energy_by_month = BTreeMap::new();
+            for (hour, wh) in hours[first..=last].iter_mut().zip(&energy) {
+                hour.excess -= wh;
+                *energy_by_month.entry(month_of(&hour.local)).or_default() +=
+                    wh;
+            }
+            events.push(ApplianceEvent {
+                appliance: signature.name.clone(),
+                start: hours[first].local,
+                end: (hours[last].start_utc + chrono::Duration::hours(1))
+                    .with_timezone(&chrono::Local)
+                    .naive_local(),
+                hours: round(running_hours, 1),
+                energy_kwh: round(total / 1000.0, 2),
+                energy_by_month,
+            });
+        }
+        i = last + 1;
+    }
+}
+
+/// Returns the first day of the month containing `t`
+fn month_of(t: &chrono::NaiveDateTime) -> chrono::NaiveDate {
+    t.date().with_day(1).unwrap()
+}
+
+/// Totals up the energy attributed to each appliance in each month
+///
+/// The energy for a run that spans two months is split between them by hour.
+/// The run counts as an event in the month in which it started.
+fn monthly_breakdown(
+    hours: &[Hour],
+    events: &[ApplianceEvent],
+    signatures: &[ApplianceSignature],
+) -> Vec<MonthlyBreakdown> {
+    let mut totals: BTreeMap<chrono::NaiveDate, f64> = BTreeMap::new();
+    for hour in hours {
+        *totals.entry(month_of(&hour.local)).or_default() += hour.consumed;
+    }
+    let mut attributed: BTreeMap<(chrono::NaiveDate, &str), (usize, f64)> =
+        BTreeMap::new();
+    for event in events {
+        let appliance = event.appliance.as_str();
+        attributed.entry((month_of(&event.start), appliance)).or_default().0 +=
+            1;
+        for (month, wh) in &event.energy_by_month {
+            attributed.entry((*month, appliance)).or_default().1 += wh;
+        }
+    }
+
+    let mut rv = Vec::new();
+    for (month, total) in totals {
+        let mut other = total;
+        let mut row = |appliance: &str, nevents: usize, wh: f64| {
+            rv.push(MonthlyBreakdown {
+                month,
+                appliance: appliance.to_owned(),
+                nevents,
+                energy_kwh: round(wh / 1000.0, 2),
+                share_pct: if total > 0.0 {
+                    round(wh * 100.0 / total, 1)
+                } else {
+                    0.0
+                },
+            })
+        };
+        for signature in signatures {
+            let (nevents, wh) = attributed
+                .get(&(month, signature.name.as_str()))
+                .copied()
+                .unwrap_or((0, 0.0));
+            other -= wh;
+            row(&signature.name, nevents, wh);
+        }
+        row("other", 0, other);
+    }
+    rv
+}
+
+/// Rounds `value` to `places` decimal places
+fn round(value: f64, places: i32) -> f64 {
+    let scale = 10f64.powi(places);
+    (value * scale).round() / scale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::local_day_hours;
+    use crate::common::NetEnergyUsed;
+    use crate::common::WattHours;
+    use crate::data_aggregator::Source;
+
+    fn signature(name: &str, power_kw: f64) -> ApplianceSignature {
+        ApplianceSignature {
+            name: String::from(name),
+            power_kw,
+            tolerance_pct: default_tolerance_pct(),
+            min_hours: 2.0,
+            max_hours: None,
+            months: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_disaggregate() {
+        // The house uses 500 Wh every hour from May 20 through June 10.  An EV
+        // charger drawing 7.2 kW runs from 10pm on May 31 to 1am on June 1.
+        let first = chrono::NaiveDate::from_ymd(2022, 5, 20);
+        let start = chrono::NaiveDate::from_ymd(2022, 5, 31);
+        let mut records = Vec::new();
+        for n in 0..22 {
+            let date = first + chrono::Duration::days(n);
+            for (i, hour_start) in
+                local_day_hours(&date).into_iter().enumerate()
+            {
+                let charging = (date == start && i >= 22)
+                    || (date == start.succ() && i == 0);
+                let wh = if charging { 7700 } else { 500 };
+                // Data is kept by UTC hour.  In time zones that aren't a
+                // whole number of hours from UTC, use the UTC hour that
+                // starts during this local hour.
+                records.push(NetEnergyUsed {
+                    timestamp_start_utc: hour_start
+                        + chrono::Duration::minutes(59),
+                    net_used_wh: WattHours::from(wh),
+                });
+            }
+        }
+        let mut aggr = DataLoader::new();
+        aggr.load_net_usage(Source::new("test"), records.into_iter().map(Ok))
+            .unwrap();
+
+        let signatures =
+            vec![signature("EV charger", 7.2), signature("dryer", 3.0)];
+        let (events, breakdown) = disaggregate(&aggr, &signatures);
+
+        // Without the charger, each of those hours would have used 500 Wh
+        // (the median for the same hour on the surrounding days).
+        assert_eq!(events.len(), 1, "{:?}", events);
+        let event = &events[0];
+        assert_eq!(event.appliance, "EV charger");
+        assert_eq!(event.start.date(), start);
+        assert_eq!(event.start.hour(), 22);
+        assert_eq!(event.end - event.start, chrono::Duration::hours(3));
+        assert_eq!(event.hours, 3.0);
+        assert_eq!(event.energy_kwh, 21.6);
+
+        // Two of the three hours were in May.  May 20-31 used 12 * 24 * 500
+        // Wh plus 2 * 7200 Wh for the charger, or 158.4 kWh; June 1-10 used
+        // 10 * 24 * 500 Wh plus 7200 Wh, or 127.2 kWh.
+        let rows: Vec<(u32, &str, usize, f64, f64)> = breakdown
+            .iter()
+            .map(|r| {
+                (
+                    r.month.month(),
+                    r.appliance.as_str(),
+                    r.nevents,
+                    r.energy_kwh,
+                    r.share_pct,
+                )
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                (5, "EV charger", 1, 14.4, 9.1),
+                (5, "dryer", 0, 0.0, 0.0),
+                (5, "other", 0, 144.0, 90.9),
+                (6, "EV charger", 0, 7.2, 5.7),
+                (6, "dryer", 0, 0.0, 0.0),
+                (6, "other", 0, 120.0, 94.3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_signatures() {
+        let check = |signature: ApplianceSignature| {
+            validate_signatures(&[signature]).map_err(|e| e.to_string())
+        };
+        assert!(check(signature("EV charger", 7.2)).is_ok());
+
+        let error = check(signature("EV charger", 0.0)).unwrap_err();
+        assert!(error.contains("power_kw must be positive"), "{}", error);
+        let error = check(signature("EV charger", f64::NAN)).unwrap_err();
+        assert!(error.contains("power_kw must be positive"), "{}", error);
+
+        let mut bad = signature("EV charger", 7.2);
+        bad.tolerance_pct = 100.0;
+        let error = check(bad).unwrap_err();
+        assert!(error.contains("tolerance_pct"), "{}", error);
+
+        let mut bad = signature("EV charger", 7.2);
+        bad.months = vec![6, 13];
+        let error = check(bad).unwrap_err();
+        assert!(error.contains("months must be from 1 to 12"), "{}", error);
+
+        let error = validate_signatures(&[
+            signature("EV charger", 7.2),
+            signature("EV charger", 11.0),
+        ])
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("appears more than once"), "{}", error);
+    }
+}
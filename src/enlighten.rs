@@ -0,0 +1,440 @@
+//! Facilities for talking to the Enphase Enlighten API
+
+use crate::enlighten_error::ApiError;
+use crate::enlighten_v4;
+use crate::inventory::Device;
+use crate::inventory::DeviceKind;
+use crate::inventory::Inventory;
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use chrono::TimeZone;
+use openapi::apis::configuration::ApiKey;
+use openapi::apis::configuration::Configuration;
+use openapi::apis::default_api as enlighten;
+use openapi::models::envoys_response_envoys;
+use openapi::models::EnvoysResponseEnvoys;
+use openapi::models::SummaryResponse;
+use std::path::Path;
+
+/// Describes the credentials used to talk to the Enlighten API
+///
+/// This is loaded from a TOML file (usually "enphase_creds.toml").  The v2 API
+/// requires `enlighten_user_id`.  The v4 API requires an `[oauth]` table
+/// instead.
+#[derive(serde::Deserialize)]
+pub struct Config {
+    pub enlighten_key: String,
+    pub enlighten_user_id: Option<String>,
+    pub oauth: Option<enlighten_v4::OAuthConfig>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, anyhow::Error> {
+        toml::from_str(
+            &std::fs::read_to_string(path).with_context(|| {
+                format!("open creds file {:?}", path.display())
+            })?,
+        )
+        .with_context(|| format!("parsing creds file {:?}", path.display()))
+    }
+}
+
+/// Bundles up what's needed to make v2 API requests for a particular user
+pub struct Client {
+    pub config: Configuration,
+    pub user_id: String,
+}
+
+impl Client {
+    pub fn new(config: &Config) -> Result<Client, anyhow::Error> {
+        let user_id = config.enlighten_user_id.clone().ok_or_else(|| {
+            anyhow!("creds file is missing \"enlighten_user_id\" (for v2 API)")
+        })?;
+        Ok(Client {
+            config: Configuration {
+                base_path: String::from("https://api.enphaseenergy.com/api/v2"),
+                user_agent: None,
+                client: reqwest::Client::new(),
+                basic_auth: None,
+                oauth_access_token: None,
+                bearer_access_token: None,
+                api_key: Some(ApiKey {
+                    prefix: None,
+                    key: config.enlighten_key.clone(),
+                }),
+            },
+            user_id,
+        })
+    }
+
+    /// Returns the id of the one system that this user has access to
+    ///
+    /// Fails if there is not exactly one system.
+    pub async fn system_id(&self) -> Result<i32, anyhow::Error> {
+        let response = enlighten::systems(
+            &self.config,
+            &self.user_id,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .await
+        .map_err(ApiError::from)
+        .context("listing systems")?;
+
+        if response.systems.len() != 1 {
+            bail!(
+                "expected exactly one system, but found {}",
+                response.systems.len()
+            );
+        }
+
+        Ok(response.systems[0].system_id)
+    }
+}
+
+/// Provides the subset of functionality that we support with both the v2 and
+/// v4 APIs
+pub enum Api {
+    V2(Client),
+    V4(enlighten_v4::Client),
+}
+
+impl Api {
+    /// Returns a client for whichever API version `config` is set up for
+    ///
+    /// If `config` has OAuth configuration, we use v4.  In that case, if
+    /// `oauth_code` is provided, it's exchanged for new tokens.  Otherwise, we
+    /// use tokens saved by a previous run.
+    pub async fn new(
+        config: &Config,
+        creds_file: &Path,
+        oauth_code: Option<&str>,
+    ) -> Result<Api, anyhow::Error> {
+        let oauth = match &config.oauth {
+            None => {
+                if oauth_code.is_some() {
+                    bail!("creds file has no [oauth] configuration");
+                }
+                return Ok(Api::V2(Client::new(config)?));
+            }
+            Some(oauth) => oauth,
+        };
+
+        let tokens_path = enlighten_v4::tokens_path(creds_file);
+        let client = match oauth_code {
+            Some(code) => {
+                enlighten_v4::Client::authorize(
+                    &config.enlighten_key,
+                    oauth,
+                    &tokens_path,
+                    code,
+                )
+                .await?
+            }
+            None => enlighten_v4::Client::new(
+                &config.enlighten_key,
+                oauth,
+                &tokens_path,
+            )?,
+        };
+        Ok(Api::V4(client))
+    }
+
+    /// Returns a client for whichever API version `config` is set up for
+    /// that makes requests to `base_url` without authenticating
+    ///
+    /// This is used to replay recorded responses (see
+    /// [`crate::enlighten_replay`]).
+    pub fn for_replay(
+        config: &Config,
+        base_url: &str,
+    ) -> Result<Api, anyhow::Error> {
+        match &config.oauth {
+            None => {
+                let mut api = Api::V2(Client::new(config)?);
+                api.set_base_url(base_url);
+                Ok(api)
+            }
+            Some(oauth) => Ok(Api::V4(enlighten_v4::Client::for_replay(
+                &config.enlighten_key,
+                oauth,
+                base_url,
+            ))),
+        }
+    }
+
+    /// Sends API requests to `base_url` instead of the real Enlighten API
+    /// (e.g., "http://127.0.0.1:12345")
+    ///
+    /// This is used to record responses (see [`crate::enlighten_replay`]).
+    pub fn set_base_url(&mut self, base_url: &str) {
+        match self {
+            Api::V2(client) => {
+                client.config.base_path = format!("{}/api/v2", base_url)
+            }
+            Api::V4(client) => client.set_base_url(base_url),
+        }
+    }
+
+    /// Returns the id of the one system that this user has access to
+    ///
+    /// Fails if there is not exactly one system.
+    pub async fn system_id(&self) -> Result<i32, anyhow::Error> {
+        match self {
+            Api::V2(client) => client.system_id().await,
+            Api::V4(client) => client.system_id().await,
+        }
+    }
+
+    /// Fetch summary information about the system (status, production
+    /// today, when it last reported, etc.)
+    pub async fn summary(
+        &self,
+        system_id: i32,
+    ) -> Result<SummaryResponse, anyhow::Error> {
+        match self {
+            Api::V2(client) => Ok(enlighten::summary(
+                &client.config,
+                &client.user_id,
+                system_id,
+                None,
+            )
+            .await
+            .map_err(ApiError::from)?),
+            Api::V4(client) => client.summary(system_id).await,
+        }
+    }
+
+    /// Returns when the system became operational (i.e., the earliest time
+    /// for which there can be production data)
+    pub async fn operational_at(
+        &self,
+        system_id: i32,
+    ) -> Result<chrono::DateTime<chrono::Utc>, anyhow::Error> {
+        let summary = self.summary(system_id).await?;
+        Ok(chrono::Utc.timestamp(summary.operational_at, 0))
+    }
+
+    /// Returns the production (in Watt-hours) for each day from `start_date`
+    /// through `end_date`
+    pub async fn daily_production(
+        &self,
+        system_id: i32,
+        start_date: &chrono::NaiveDate,
+        end_date: &chrono::NaiveDate,
+    ) -> Result<Vec<i32>, anyhow::Error> {
+        let response = match self {
+            Api::V2(client) => enlighten::energy_lifetime(
+                &client.config,
+                &client.user_id,
+                system_id,
+                Some(start_date.format("%Y-%m-%d").to_string()),
+                Some(end_date.format("%Y-%m-%d").to_string()),
+                None,
+            )
+            .await
+            .map_err(ApiError::from)?,
+            Api::V4(client) => {
+                client.energy_lifetime(system_id, start_date, end_date).await?
+            }
+        };
+        Ok(response.production)
+    }
+
+    /// Returns the system's Envoys, including their status
+    ///
+    /// v4 reports more kinds of status than v2.  Anything other than
+    /// "normal" is reported as a communication problem.
+    pub async fn envoys(
+        &self,
+        system_id: i32,
+    ) -> Result<Vec<EnvoysResponseEnvoys>, anyhow::Error> {
+        match self {
+            Api::V2(client) => Ok(enlighten::envoys(
+                &client.config,
+                &client.user_id,
+                system_id,
+            )
+            .await
+            .map_err(ApiError::from)?
+            .envoys),
+            Api::V4(client) => Ok(client
+                .devices(system_id)
+                .await?
+                .gateways
+                .into_iter()
+                .map(|g| {
+                    let status = if g.status == "normal" {
+                        envoys_response_envoys::Status::Normal
+                    } else {
+                        envoys_response_envoys::Status::Comm
+                    };
+                    EnvoysResponseEnvoys::new(
+                        g.id,
+                        g.last_report_at,
+                        g.name,
+                        g.part_number,
+                        g.serial_number,
+                        status,
+                    )
+                })
+                .collect()),
+        }
+    }
+
+    /// Returns the equipment (Envoys, microinverters, and meters) in the
+    /// system
+    pub async fn inventory(
+        &self,
+        system_id: i32,
+    ) -> Result<Inventory, anyhow::Error> {
+        match self {
+            Api::V2(client) => inventory_v2(client, system_id).await,
+            Api::V4(client) => {
+                let devices = client.devices(system_id).await?;
+                let mut inventory = Inventory::new();
+                let all_devices = devices
+                    .micros
+                    .into_iter()
+                    .map(|d| (DeviceKind::Inverter, d))
+                    .chain(
+                        devices
+                            .meters
+                            .into_iter()
+                            .map(|d| (DeviceKind::Meter, d)),
+                    )
+                    .chain(
+                        devices
+                            .gateways
+                            .into_iter()
+                            .map(|d| (DeviceKind::Envoy, d)),
+                    );
+                for (kind, d) in all_devices {
+                    // As with v2, only Envoys' names and statuses are
+                    // interesting.
+                    let is_envoy = kind == DeviceKind::Envoy;
+                    inventory.add(Device {
+                        kind,
+                        serial_number: d.serial_number,
+                        model: d.model,
+                        sku: d.sku,
+                        part_number: d.part_number,
+                        manufacturer: String::new(),
+                        name: if is_envoy { d.name } else { String::new() },
+                        status: if is_envoy { d.status } else { String::new() },
+                    });
+                }
+                Ok(inventory)
+            }
+        }
+    }
+
+    /// Fetch production in 5-minute intervals for the day starting at
+    /// `start_at` (a Unix timestamp)
+    pub async fn stats(
+        &self,
+        system_id: i32,
+        start_at: i64,
+    ) -> Result<openapi::models::StatsResponse, anyhow::Error> {
+        match self {
+            Api::V2(client) => Ok(enlighten::stats(
+                &client.config,
+                &client.user_id,
+                system_id,
+                Some(start_at),
+                Some(start_at + 86400),
+            )
+            .await
+            .map_err(ApiError::from)?),
+            Api::V4(client) => {
+                client.production_micro(system_id, start_at).await
+            }
+        }
+    }
+}
+
+async fn inventory_v2(
+    client: &Client,
+    system_id: i32,
+) -> Result<Inventory, anyhow::Error> {
+    let mut inventory = Inventory::new();
+    let response =
+        enlighten::inventory(&client.config, &client.user_id, system_id)
+            .await
+            .map_err(ApiError::from)
+            .context("fetching inventory")?;
+    let simple_devices = response
+        .envoys
+        .unwrap_or_default()
+        .into_iter()
+        .map(|e| (DeviceKind::Envoy, e))
+        .chain(
+            response.inverters.into_iter().map(|i| (DeviceKind::Inverter, i)),
+        );
+    for (kind, d) in simple_devices {
+        inventory.add(Device {
+            kind,
+            serial_number: d.sn,
+            model: d.model,
+            sku: d.sku,
+            part_number: String::new(),
+            manufacturer: String::new(),
+            name: String::new(),
+            status: String::new(),
+        });
+    }
+    for m in response.meters {
+        inventory.add(Device {
+            kind: DeviceKind::Meter,
+            serial_number: m.sn,
+            model: m.model,
+            sku: String::new(),
+            part_number: String::new(),
+            manufacturer: m.manufacturer,
+            name: String::new(),
+            status: String::new(),
+        });
+    }
+
+    // The "envoys" endpoint provides more information about the Envoys,
+    // including their status.
+    let response =
+        enlighten::envoys(&client.config, &client.user_id, system_id)
+            .await
+            .map_err(ApiError::from)
+            .context("fetching envoys")?;
+    for e in response.envoys {
+        inventory.add(Device {
+            kind: DeviceKind::Envoy,
+            serial_number: e.serial_number,
+            model: String::new(),
+            sku: String::new(),
+            part_number: e.part_number,
+            manufacturer: String::new(),
+            name: e.name,
+            status: envoy_status_label(&e.status),
+        });
+    }
+
+    Ok(inventory)
+}
+
+fn envoy_status_label(status: &envoys_response_envoys::Status) -> String {
+    match status {
+        envoys_response_envoys::Status::Normal => String::from("normal"),
+        envoys_response_envoys::Status::Comm => String::from("comm"),
+    }
+}
@@ -0,0 +1,141 @@
+//! Classification of errors from the Enlighten API
+//!
+//! The generated client reports every unsuccessful response as
+//! `openapi::apis::Error::ResponseError` with the raw body.  It does try to
+//! parse the body into a per-endpoint error enum, but those enums are untagged
+//! and the error bodies overlap (e.g., a rate-limiting body also parses as a
+//! `ClientError`), so the parsed entity doesn't tell you what happened.  Here,
+//! we classify errors by HTTP status code instead, parsing the body only for
+//! details.
+
+use chrono::TimeZone;
+use openapi::models::conflict_error::Period;
+use openapi::models::ClientError;
+use openapi::models::ConflictError;
+use std::time::Duration;
+
+/// Describes a failed request to the Enlighten API
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    /// We've made too many requests (409 for v2, 429 for v4)
+    #[error("rate limited: {message}")]
+    RateLimited {
+        message: String,
+        /// maximum number of requests allowed per `period`
+        limit: Option<i32>,
+        period: Option<Period>,
+        /// when the current rate-limiting period ends
+        period_end: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    /// The API key or access token is invalid or lacks access (401 or 403)
+    #[error("authentication failed: {0}")]
+    Auth(String),
+    /// The requested system (or other resource) doesn't exist (404)
+    #[error("not found: {0}")]
+    NotFound(String),
+    /// The request itself is invalid, e.g., a date range that's too long or
+    /// starts before the system was operational (422)
+    #[error("unprocessable request: {0}")]
+    Unprocessable(String),
+    /// Enlighten had a problem processing the request (5xx)
+    #[error("server error ({status}): {message}")]
+    Server { status: reqwest::StatusCode, message: String },
+    /// Some other unexpected response
+    #[error("unexpected response ({status}): {message}")]
+    Other { status: reqwest::StatusCode, message: String },
+    /// We failed to make the request or read the response
+    #[error("transport error")]
+    Transport(#[source] reqwest::Error),
+    /// We got a successful response that we couldn't parse
+    #[error("parsing response")]
+    Parse(#[source] serde_json::Error),
+    #[error("I/O error")]
+    Io(#[source] std::io::Error),
+}
+
+impl ApiError {
+    /// Classifies an unsuccessful response based on its status code and body
+    pub fn from_response(status: reqwest::StatusCode, content: &str) -> Self {
+        use reqwest::StatusCode;
+
+        match status {
+            StatusCode::CONFLICT | StatusCode::TOO_MANY_REQUESTS => {
+                match serde_json::from_str::<ConflictError>(content) {
+                    Ok(c) => ApiError::RateLimited {
+                        message: c.message.join("; "),
+                        limit: Some(c.limit),
+                        period: Some(c.period),
+                        period_end: Some(
+                            chrono::Utc.timestamp(i64::from(c.period_end), 0),
+                        ),
+                    },
+                    Err(_) => ApiError::RateLimited {
+                        message: error_message(content),
+                        limit: None,
+                        period: None,
+                        period_end: None,
+                    },
+                }
+            }
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                ApiError::Auth(error_message(content))
+            }
+            StatusCode::NOT_FOUND => ApiError::NotFound(error_message(content)),
+            StatusCode::UNPROCESSABLE_ENTITY => {
+                ApiError::Unprocessable(error_message(content))
+            }
+            s if s.is_server_error() => {
+                ApiError::Server { status, message: error_message(content) }
+            }
+            _ => ApiError::Other { status, message: error_message(content) },
+        }
+    }
+
+    /// Returns how long to wait before retrying the request, if it's worth
+    /// retrying at all
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ApiError::RateLimited { period_end: Some(end), .. } => {
+                // Wait until the end of the current period (plus a little
+                // slop for clock skew), but not too long.
+                let wait = (*end - chrono::Utc::now()).num_seconds().max(0) + 5;
+                Some(Duration::from_secs(wait.min(3600) as u64))
+            }
+            ApiError::RateLimited { period: Some(Period::Hour), .. } => {
+                Some(Duration::from_secs(3600))
+            }
+            ApiError::RateLimited { .. } => Some(Duration::from_secs(60)),
+            ApiError::Server { .. } | ApiError::Transport(_) => {
+                Some(Duration::from_secs(30))
+            }
+            ApiError::Auth(_)
+            | ApiError::NotFound(_)
+            | ApiError::Unprocessable(_)
+            | ApiError::Other { .. }
+            | ApiError::Parse(_)
+            | ApiError::Io(_) => None,
+        }
+    }
+}
+
+impl<T> From<openapi::apis::Error<T>> for ApiError {
+    fn from(error: openapi::apis::Error<T>) -> Self {
+        match error {
+            openapi::apis::Error::Reqwest(e) => ApiError::Transport(e),
+            openapi::apis::Error::Serde(e) => ApiError::Parse(e),
+            openapi::apis::Error::Io(e) => ApiError::Io(e),
+            openapi::apis::Error::ResponseError(r) => {
+                ApiError::from_response(r.status, &r.content)
+            }
+        }
+    }
+}
+
+/// Extracts a human-readable message from an error response body
+fn error_message(content: &str) -> String {
+    match serde_json::from_str::<ClientError>(content) {
+        Ok(e) if !e.message.is_empty() => e.message.join("; "),
+        Ok(e) => e.reason,
+        Err(_) => content.to_owned(),
+    }
+}
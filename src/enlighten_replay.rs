@@ -0,0 +1,275 @@
+//! Recording and replaying Enlighten API responses
+//!
+//! To exercise the fetch path without live credentials, we run a small HTTP
+//! server on localhost that stands in for the Enlighten API and point the
+//! client at it (see [`crate::enlighten::Api::set_base_url()`]).  Everything
+//! else (the generated client, error classification, retries, parsing) runs
+//! exactly as it would against the real API.
+//!
+//! In record mode, the stand-in forwards each request to the real API and
+//! saves the response (status and raw body) to a fixture file.  In replay
+//! mode, it serves responses from previously-saved fixture files instead.
+//!
+//! Fixture files are named after the request path and query string, minus the
+//! API key and user id so that they can be shared.  If the same request is
+//! made more than once (e.g., when retrying after being rate-limited), the
+//! second response is saved as `NAME.2.json`, the third as `NAME.3.json`, and
+//! so on.  When replaying, if there's no fixture for the Nth request, we serve
+//! the last one there is.  Fixtures are plain JSON so that they can be
+//! written by hand, too (e.g., to simulate errors).
+
+use anyhow::bail;
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// Base URL of the real Enlighten API
+pub const ENLIGHTEN_URL: &str = "https://api.enphaseenergy.com";
+
+/// Query parameters that identify the user and must not appear in fixture
+/// names
+const SECRET_PARAMS: &[&str] = &["key", "user_id"];
+
+/// Describes one saved response
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Fixture {
+    /// request that produced this response (minus secrets), for reference
+    pub request: String,
+    /// HTTP status code
+    pub status: u16,
+    /// raw response body
+    pub body: String,
+}
+
+/// Whether we're recording responses from the real API or replaying them
+pub enum Mode {
+    /// Forward requests to `upstream` (e.g., "https://api.enphaseenergy.com")
+    /// and save the responses in `dir`
+    Record { dir: PathBuf, upstream: String },
+    /// Serve saved responses from `dir`
+    Replay { dir: PathBuf },
+}
+
+/// Handle to a running stand-in server
+///
+/// The server runs until the process exits.
+pub struct StandIn {
+    base_url: String,
+}
+
+impl StandIn {
+    /// Starts a stand-in server on a local ephemeral port
+    pub async fn start(mode: Mode) -> Result<StandIn, anyhow::Error> {
+        match &mode {
+            Mode::Record { dir, .. } => std::fs::create_dir_all(dir)
+                .with_context(|| format!("mkdir {:?}", dir.display()))?,
+            Mode::Replay { dir } => {
+                if !dir.is_dir() {
+                    bail!(
+                        "replay directory {:?} does not exist",
+                        dir.display()
+                    );
+                }
+            }
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .context("binding stand-in server")?;
+        let base_url = format!("http://{}", listener.local_addr()?);
+        let server = Arc::new(Server {
+            mode,
+            http: reqwest::Client::new(),
+            counts: Mutex::new(BTreeMap::new()),
+        });
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(error) => {
+                        eprintln!("stand-in server: accept: {}", error);
+                        continue;
+                    }
+                };
+                let server = Arc::clone(&server);
+                tokio::spawn(async move {
+                    if let Err(error) = server.handle(stream).await {
+                        eprintln!("stand-in server: {:#}", error);
+                    }
+                });
+            }
+        });
+
+        Ok(StandIn { base_url })
+    }
+
+    /// Returns the URL of the server (e.g., "http://127.0.0.1:12345")
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+}
+
+struct Server {
+    mode: Mode,
+    http: reqwest::Client,
+    /// number of times we've seen each request (by fixture name)
+    counts: Mutex<BTreeMap<String, usize>>,
+}
+
+impl Server {
+    async fn handle(
+        &self,
+        stream: tokio::net::TcpStream,
+    ) -> Result<(), anyhow::Error> {
+        let mut stream = tokio::io::BufReader::new(stream);
+
+        // We only need to support the GET requests that our clients make, so
+        // we only look at the request line and the "Authorization" header.
+        let mut request_line = String::new();
+        stream.read_line(&mut request_line).await?;
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => (method, target.to_owned()),
+            _ => bail!("bad request line: {:?}", request_line),
+        };
+        if method != "GET" {
+            bail!("unsupported method: {:?}", method);
+        }
+        let mut authorization = None;
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await? == 0 || line.trim().is_empty()
+            {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("authorization") {
+                    authorization = Some(value.trim().to_owned());
+                }
+            }
+        }
+
+        let (name, description) = fixture_name(&target);
+        let count = {
+            let mut counts = self.counts.lock().await;
+            let count = counts.entry(name.clone()).or_insert(0);
+            *count += 1;
+            *count
+        };
+
+        let fixture = match &self.mode {
+            Mode::Record { dir, upstream } => {
+                let mut request =
+                    self.http.get(format!("{}{}", upstream, target));
+                if let Some(authorization) = authorization {
+                    request = request.header("Authorization", authorization);
+                }
+                let response = request
+                    .send()
+                    .await
+                    .with_context(|| format!("GET {}", description))?;
+                let status = response.status().as_u16();
+                let body = response.text().await.with_context(|| {
+                    format!("reading response from {}", description)
+                })?;
+                let fixture = Fixture { request: description, status, body };
+                save_fixture(&fixture_path(dir, &name, count), &fixture)?;
+                fixture
+            }
+            Mode::Replay { dir } => match find_fixture(dir, &name, count) {
+                Some(path) => load_fixture(&path)?,
+                None => Fixture {
+                    request: description.clone(),
+                    status: 404,
+                    body: serde_json::json!({
+                        "reason": "no fixture",
+                        "message": [format!(
+                            "no fixture for {} in {:?}",
+                            description,
+                            dir.display()
+                        )],
+                    })
+                    .to_string(),
+                },
+            },
+        };
+
+        let status = reqwest::StatusCode::from_u16(fixture.status)
+            .with_context(|| format!("bad status in fixture for {}", name))?;
+        let response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\n\
+            Content-Length: {}\r\nConnection: close\r\n\r\n",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or(""),
+            fixture.body.len()
+        );
+        let stream = stream.get_mut();
+        stream.write_all(response.as_bytes()).await?;
+        stream.write_all(fixture.body.as_bytes()).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// Returns the fixture name for a request target (path and query string),
+/// along with a description of the request that omits secrets
+fn fixture_name(target: &str) -> (String, String) {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params: Vec<&str> = query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .filter(|p| {
+            let name = p.split_once('=').map(|(n, _)| n).unwrap_or(p);
+            !SECRET_PARAMS.contains(&name)
+        })
+        .collect();
+    let description = if params.is_empty() {
+        path.to_owned()
+    } else {
+        format!("{}?{}", path, params.join("&"))
+    };
+    let name = description
+        .trim_start_matches('/')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    (name, description)
+}
+
+/// Returns the path of the fixture for the `count`th request with this name
+fn fixture_path(dir: &Path, name: &str, count: usize) -> PathBuf {
+    if count == 1 {
+        dir.join(format!("{}.json", name))
+    } else {
+        dir.join(format!("{}.{}.json", name, count))
+    }
+}
+
+/// Returns the fixture to serve for the `count`th request with this name
+fn find_fixture(dir: &Path, name: &str, count: usize) -> Option<PathBuf> {
+    (1..=count)
+        .rev()
+        .map(|c| fixture_path(dir, name, c))
+        .find(|path| path.exists())
+}
+
+fn load_fixture(path: &Path) -> Result<Fixture, anyhow::Error> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("read fixture {:?}", path.display()))?;
+    serde_json::from_str(&contents)
+        .with_context(|| format!("parsing fixture {:?}", path.display()))
+}
+
+fn save_fixture(path: &Path, fixture: &Fixture) -> Result<(), anyhow::Error> {
+    let contents =
+        serde_json::to_string_pretty(fixture).context("serializing fixture")?;
+    std::fs::write(path, contents)
+        .with_context(|| format!("write fixture {:?}", path.display()))
+}
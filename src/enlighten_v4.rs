@@ -0,0 +1,446 @@
+//! Client for version 4 of the Enphase Enlighten API
+//!
+//! Enphase has deprecated the v2 API that the generated `openapi` client
+//! targets.  v4 uses OAuth2: the user authorizes our application once (in a
+//! browser), which gives us a short-lived access token and a long-lived refresh
+//! token.  We store both in a file so that subsequent runs can refresh the
+//! access token without involving the user again.
+//!
+//! This only implements the endpoints we use.  Where the v4 response bodies
+//! match the v2 ones, we reuse the generated v2 models.
+
+use crate::enlighten_error::ApiError;
+use anyhow::bail;
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::Path;
+use std::path::PathBuf;
+use tokio::sync::Mutex;
+
+const API_BASE: &str = "https://api.enphaseenergy.com/api/v4";
+const OAUTH_AUTHORIZE_URL: &str =
+    "https://api.enphaseenergy.com/oauth/authorize";
+const OAUTH_TOKEN_URL: &str = "https://api.enphaseenergy.com/oauth/token";
+/// Default OAuth2 redirect URI for applications that don't have their own web
+/// server to receive the authorization code.  Enphase displays the code so
+/// that the user can paste it in.
+const DEFAULT_REDIRECT_URI: &str =
+    "https://api.enphaseenergy.com/oauth/redirect_uri";
+
+/// Describes the OAuth2 application registered with Enphase
+///
+/// This appears as an `[oauth]` table in the creds file.
+#[derive(Clone, Deserialize)]
+pub struct OAuthConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default = "default_redirect_uri")]
+    pub redirect_uri: String,
+}
+
+fn default_redirect_uri() -> String {
+    String::from(DEFAULT_REDIRECT_URI)
+}
+
+impl OAuthConfig {
+    /// Returns the URL that the user should visit to authorize us
+    pub fn authorize_url(&self) -> reqwest::Url {
+        reqwest::Url::parse_with_params(
+            OAUTH_AUTHORIZE_URL,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.client_id),
+                ("redirect_uri", &self.redirect_uri),
+            ],
+        )
+        .unwrap()
+    }
+
+    /// Returns a message telling the user how to (re)authorize us
+    fn authorize_hint(&self) -> String {
+        format!(
+            "you may need to authorize this application by visiting {} and \
+            then running with --oauth-code",
+            self.authorize_url()
+        )
+    }
+}
+
+/// OAuth2 tokens, as persisted in the tokens file
+#[derive(Debug, Deserialize, Serialize)]
+struct Tokens {
+    access_token: String,
+    refresh_token: String,
+    expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Response body from the OAuth2 token endpoint
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: String,
+    /// seconds until `access_token` expires
+    expires_in: i64,
+}
+
+impl From<TokenResponse> for Tokens {
+    fn from(t: TokenResponse) -> Self {
+        Tokens {
+            access_token: t.access_token,
+            refresh_token: t.refresh_token,
+            expires_at: chrono::Utc::now()
+                + chrono::Duration::seconds(t.expires_in),
+        }
+    }
+}
+
+/// Returns the path to the tokens file that goes with the given creds file
+///
+/// The tokens live next to the creds file, but in a separate file because we
+/// rewrite it every time the tokens are refreshed.
+pub fn tokens_path(creds_file: &Path) -> PathBuf {
+    creds_file.with_file_name("enphase_tokens.toml")
+}
+
+/// Makes requests to the v4 API, refreshing the access token as needed
+pub struct Client {
+    http: reqwest::Client,
+    api_base: String,
+    api_key: String,
+    oauth: OAuthConfig,
+    /// where to save refreshed tokens (none if we're not really
+    /// authenticating, as when replaying recorded responses)
+    tokens_path: Option<PathBuf>,
+    tokens: Mutex<Tokens>,
+}
+
+impl Client {
+    /// Returns a client using tokens previously saved to `tokens_path`
+    pub fn new(
+        api_key: &str,
+        oauth: &OAuthConfig,
+        tokens_path: &Path,
+    ) -> Result<Client, anyhow::Error> {
+        let contents =
+            std::fs::read_to_string(tokens_path).with_context(|| {
+                format!(
+                    "read tokens file {:?} ({})",
+                    tokens_path.display(),
+                    oauth.authorize_hint()
+                )
+            })?;
+        let tokens = toml::from_str(&contents).with_context(|| {
+            format!("parsing tokens file {:?}", tokens_path.display())
+        })?;
+        Ok(Client {
+            http: reqwest::Client::new(),
+            api_base: String::from(API_BASE),
+            api_key: api_key.to_owned(),
+            oauth: oauth.clone(),
+            tokens_path: Some(tokens_path.to_owned()),
+            tokens: Mutex::new(tokens),
+        })
+    }
+
+    /// Returns a client that makes requests to `base_url` (see
+    /// [`crate::enlighten_replay`]) without any real tokens
+    pub fn for_replay(
+        api_key: &str,
+        oauth: &OAuthConfig,
+        base_url: &str,
+    ) -> Client {
+        Client {
+            http: reqwest::Client::new(),
+            api_base: format!("{}/api/v4", base_url),
+            api_key: api_key.to_owned(),
+            oauth: oauth.clone(),
+            tokens_path: None,
+            tokens: Mutex::new(Tokens {
+                access_token: String::from("replay"),
+                refresh_token: String::from("replay"),
+                expires_at: chrono::MAX_DATETIME,
+            }),
+        }
+    }
+
+    /// Sends API requests to `base_url` instead of the real Enlighten API
+    /// (see [`crate::enlighten_replay`])
+    ///
+    /// OAuth requests still go to Enphase.
+    pub fn set_base_url(&mut self, base_url: &str) {
+        self.api_base = format!("{}/api/v4", base_url);
+    }
+
+    /// Exchanges an authorization code (obtained by the user after visiting
+    /// [`OAuthConfig::authorize_url()`]) for tokens, saves them to
+    /// `tokens_path`, and returns a client that uses them
+    pub async fn authorize(
+        api_key: &str,
+        oauth: &OAuthConfig,
+        tokens_path: &Path,
+        code: &str,
+    ) -> Result<Client, anyhow::Error> {
+        let http = reqwest::Client::new();
+        let tokens = request_tokens(
+            &http,
+            oauth,
+            &[
+                ("grant_type", "authorization_code"),
+                ("redirect_uri", &oauth.redirect_uri),
+                ("code", code),
+            ],
+        )
+        .await
+        .context("exchanging authorization code for tokens")?;
+        save_tokens(tokens_path, &tokens)?;
+        Ok(Client {
+            http,
+            api_base: String::from(API_BASE),
+            api_key: api_key.to_owned(),
+            oauth: oauth.clone(),
+            tokens_path: Some(tokens_path.to_owned()),
+            tokens: Mutex::new(tokens),
+        })
+    }
+
+    /// Returns the id of the one system that this user has access to
+    ///
+    /// Fails if there is not exactly one system.
+    pub async fn system_id(&self) -> Result<i32, anyhow::Error> {
+        #[derive(Deserialize)]
+        struct SystemsResponse {
+            systems: Vec<System>,
+        }
+        #[derive(Deserialize)]
+        struct System {
+            system_id: i32,
+        }
+
+        let response: SystemsResponse =
+            self.get("systems", &[]).await.context("listing systems")?;
+        if response.systems.len() != 1 {
+            bail!(
+                "expected exactly one system, but found {}",
+                response.systems.len()
+            );
+        }
+
+        Ok(response.systems[0].system_id)
+    }
+
+    /// Fetch production (as measured by the microinverters) in 5-minute
+    /// intervals for the day starting at `start_at`
+    ///
+    /// This is the v4 analog of the v2 "stats" endpoint and the response has
+    /// the same structure.
+    pub async fn production_micro(
+        &self,
+        system_id: i32,
+        start_at: i64,
+    ) -> Result<openapi::models::StatsResponse, anyhow::Error> {
+        self.get(
+            &format!("systems/{}/telemetry/production_micro", system_id),
+            &[
+                ("start_at", start_at.to_string()),
+                ("granularity", String::from("day")),
+            ],
+        )
+        .await
+    }
+
+    /// Fetch summary information about the system, including when it became
+    /// operational
+    pub async fn summary(
+        &self,
+        system_id: i32,
+    ) -> Result<openapi::models::SummaryResponse, anyhow::Error> {
+        self.get(&format!("systems/{}/summary", system_id), &[]).await
+    }
+
+    /// Fetch daily production for each day from `start_date` through
+    /// `end_date`
+    pub async fn energy_lifetime(
+        &self,
+        system_id: i32,
+        start_date: &chrono::NaiveDate,
+        end_date: &chrono::NaiveDate,
+    ) -> Result<openapi::models::EnergyLifetimeResponse, anyhow::Error> {
+        self.get(
+            &format!("systems/{}/energy_lifetime", system_id),
+            &[
+                ("start_date", start_date.format("%Y-%m-%d").to_string()),
+                ("end_date", end_date.format("%Y-%m-%d").to_string()),
+            ],
+        )
+        .await
+    }
+
+    /// Fetch the list of devices (microinverters, meters, and gateways) in
+    /// the system
+    ///
+    /// This replaces the v2 "inventory" and "envoys" endpoints.
+    pub async fn devices(
+        &self,
+        system_id: i32,
+    ) -> Result<Devices, anyhow::Error> {
+        #[derive(Deserialize)]
+        struct DevicesResponse {
+            devices: Devices,
+        }
+
+        let response: DevicesResponse =
+            self.get(&format!("systems/{}/devices", system_id), &[]).await?;
+        Ok(response.devices)
+    }
+
+    async fn get<T>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> Result<T, anyhow::Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let url = format!("{}/{}", self.api_base, path);
+        let mut tokens = self.tokens.lock().await;
+        if tokens.expires_at <= chrono::Utc::now() {
+            self.refresh(&mut tokens).await?;
+        }
+
+        let mut refreshed = false;
+        loop {
+            let response = self
+                .http
+                .get(&url)
+                .query(&[("key", &self.api_key)])
+                .query(query)
+                .bearer_auth(&tokens.access_token)
+                .send()
+                .await
+                .map_err(ApiError::Transport)
+                .with_context(|| format!("GET {}", url))?;
+
+            // The access token may have been revoked or expired early.  Try
+            // refreshing it once.
+            if response.status() == reqwest::StatusCode::UNAUTHORIZED
+                && !refreshed
+            {
+                self.refresh(&mut tokens).await?;
+                refreshed = true;
+                continue;
+            }
+
+            let status = response.status();
+            let body = response
+                .text()
+                .await
+                .map_err(ApiError::Transport)
+                .with_context(|| format!("reading response from {}", url))?;
+            if !status.is_success() {
+                return Err(ApiError::from_response(status, &body))
+                    .with_context(|| format!("GET {}", url));
+            }
+            return serde_json::from_str(&body)
+                .map_err(ApiError::Parse)
+                .with_context(|| format!("parsing response from {}", url));
+        }
+    }
+
+    async fn refresh(&self, tokens: &mut Tokens) -> Result<(), anyhow::Error> {
+        let tokens_path = match &self.tokens_path {
+            Some(tokens_path) => tokens_path,
+            None => bail!("cannot refresh access token while replaying"),
+        };
+        eprintln!("refreshing OAuth access token");
+        let new_tokens = request_tokens(
+            &self.http,
+            &self.oauth,
+            &[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", &tokens.refresh_token),
+            ],
+        )
+        .await
+        .with_context(|| {
+            format!("refreshing access token ({})", self.oauth.authorize_hint())
+        })?;
+        save_tokens(tokens_path, &new_tokens)?;
+        *tokens = new_tokens;
+        Ok(())
+    }
+}
+
+/// Devices in a system, as reported by the "devices" endpoint
+#[derive(Debug, Default, Deserialize)]
+pub struct Devices {
+    #[serde(default)]
+    pub micros: Vec<Device>,
+    #[serde(default)]
+    pub meters: Vec<Device>,
+    /// Envoys (which Enphase now calls IQ Gateways)
+    #[serde(default)]
+    pub gateways: Vec<Device>,
+}
+
+/// One device from the "devices" endpoint
+///
+/// Not every kind of device has every field, so missing fields are empty.
+#[derive(Debug, Deserialize)]
+pub struct Device {
+    #[serde(default)]
+    pub id: i32,
+    pub serial_number: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub model: String,
+    #[serde(default)]
+    pub sku: String,
+    #[serde(default)]
+    pub part_number: String,
+    /// e.g., "normal" or "comm"
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub last_report_at: i64,
+}
+
+async fn request_tokens(
+    http: &reqwest::Client,
+    oauth: &OAuthConfig,
+    params: &[(&str, &str)],
+) -> Result<Tokens, anyhow::Error> {
+    let response = http
+        .post(OAUTH_TOKEN_URL)
+        .query(params)
+        .basic_auth(&oauth.client_id, Some(&oauth.client_secret))
+        .send()
+        .await
+        .with_context(|| format!("POST {}", OAUTH_TOKEN_URL))?;
+    let status = response.status();
+    let body = response.text().await.with_context(|| {
+        format!("reading response from {}", OAUTH_TOKEN_URL)
+    })?;
+    if !status.is_success() {
+        bail!("POST {}: {}: {}", OAUTH_TOKEN_URL, status, body);
+    }
+    let token_response: TokenResponse = serde_json::from_str(&body)
+        .with_context(|| {
+            format!("parsing response from {}", OAUTH_TOKEN_URL)
+        })?;
+    Ok(Tokens::from(token_response))
+}
+
+/// Saves tokens to `path`, replacing its contents atomically (so that a crash
+/// can't leave us without a usable refresh token)
+fn save_tokens(path: &Path, tokens: &Tokens) -> Result<(), anyhow::Error> {
+    let contents =
+        toml::to_string(tokens).context("serializing OAuth tokens")?;
+    let tmp_path = path.with_extension("toml.tmp");
+    std::fs::write(&tmp_path, contents)
+        .with_context(|| format!("write {:?}", tmp_path.display()))?;
+    std::fs::rename(&tmp_path, path).with_context(|| {
+        format!("rename {:?} to {:?}", tmp_path.display(), path.display())
+    })
+}
@@ -0,0 +1,257 @@
+//! Client for the API served by an Envoy (IQ Gateway) on the local network
+//!
+//! This is an alternative to the Enlighten cloud API.  It's not rate-limited
+//! and it reflects what the Envoy knows right now, rather than what it last
+//! uploaded to Enlighten.  However, the Envoy only reports current power and
+//! lifetime energy counters, so to get energy over time, you have to poll it
+//! (see the `envoy-poll` tool).
+//!
+//! None of this is officially documented.  The structures here reflect what
+//! we've observed from Envoys running various firmware versions.  Newer
+//! firmware (7.x and later) requires a token (a JWT obtained from Enphase for
+//! this specific Envoy) and serves HTTPS with a self-signed certificate.
+
+use crate::common::EnergyProduced;
+use crate::common::WattHours;
+use anyhow::Context;
+use chrono::TimeZone;
+use serde::Deserialize;
+
+/// Makes requests to an Envoy on the local network
+pub struct EnvoyClient {
+    base_url: reqwest::Url,
+    token: Option<String>,
+    client: reqwest::Client,
+}
+
+impl EnvoyClient {
+    /// Returns a client for the Envoy at `base_url` (e.g.,
+    /// "https://envoy.local")
+    ///
+    /// `token` is required for newer firmware.
+    pub fn new(
+        base_url: &str,
+        token: Option<String>,
+    ) -> Result<EnvoyClient, anyhow::Error> {
+        let base_url = reqwest::Url::parse(base_url)
+            .with_context(|| format!("parsing Envoy URL {:?}", base_url))?;
+        // The Envoy uses a self-signed certificate.  There's not much we can
+        // do to validate it.
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .context("creating HTTP client")?;
+        Ok(EnvoyClient { base_url, token, client })
+    }
+
+    /// Fetch "/production.json": current power and lifetime energy for
+    /// production and consumption
+    pub async fn production(
+        &self,
+    ) -> Result<ProductionResponse, anyhow::Error> {
+        self.get("production.json").await
+    }
+
+    /// Fetch "/api/v1/production/inverters": latest report from each
+    /// microinverter
+    pub async fn inverters(
+        &self,
+    ) -> Result<Vec<InverterReading>, anyhow::Error> {
+        self.get("api/v1/production/inverters").await
+    }
+
+    /// Fetch "/ivp/meters/readings": latest readings from each meter
+    pub async fn meter_readings(
+        &self,
+    ) -> Result<Vec<MeterReading>, anyhow::Error> {
+        self.get("ivp/meters/readings").await
+    }
+
+    async fn get<T>(&self, path: &str) -> Result<T, anyhow::Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let url = self
+            .base_url
+            .join(path)
+            .with_context(|| format!("building URL for {:?}", path))?;
+        let mut request = self.client.get(url.clone());
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        request
+            .send()
+            .await
+            .with_context(|| format!("GET {}", url))?
+            .error_for_status()
+            .with_context(|| format!("GET {}", url))?
+            .json()
+            .await
+            .with_context(|| format!("parsing response from {}", url))
+    }
+}
+
+/// Response body for "/production.json"
+#[derive(Debug, Deserialize)]
+pub struct ProductionResponse {
+    pub production: Vec<Measurement>,
+    /// only present when consumption meters are installed
+    #[serde(default)]
+    pub consumption: Vec<Measurement>,
+}
+
+impl ProductionResponse {
+    /// Returns the best available measurement of production
+    ///
+    /// If there's a production meter ("eim"), we use that.  Otherwise, we use
+    /// the total reported by the microinverters.  (Envoys without meters
+    /// still report a production meter, but with no active meters.)
+    pub fn produced(&self) -> Option<&Measurement> {
+        self.production
+            .iter()
+            .find(|m| m.is_active_meter("production"))
+            .or_else(|| self.production.iter().find(|m| m.kind == "inverters"))
+    }
+
+    /// Returns the measurement of total consumption, if there's a consumption
+    /// meter
+    pub fn consumed(&self) -> Option<&Measurement> {
+        self.consumption.iter().find(|m| m.is_active_meter("total-consumption"))
+    }
+}
+
+/// One measurement from "/production.json"
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Measurement {
+    /// "inverters" or "eim" (a meter)
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// for meters: "production", "total-consumption", or "net-consumption"
+    pub measurement_type: Option<String>,
+    /// for inverters: the number of microinverters reporting
+    pub active_count: Option<i64>,
+    /// Unix timestamp when this measurement was taken
+    pub reading_time: i64,
+    /// current power, in Watts
+    pub w_now: f64,
+    /// lifetime energy, in Watt-hours
+    pub wh_lifetime: f64,
+}
+
+impl Measurement {
+    /// Returns whether this is from a meter that's installed and measuring
+    /// `measurement_type`
+    fn is_active_meter(&self, measurement_type: &str) -> bool {
+        self.kind == "eim"
+            && self.measurement_type.as_deref() == Some(measurement_type)
+            && self.active_count != Some(0)
+    }
+}
+
+/// One element of the response body for "/api/v1/production/inverters"
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InverterReading {
+    pub serial_number: String,
+    /// Unix timestamp of this microinverter's latest report
+    pub last_report_date: i64,
+    pub dev_type: i64,
+    pub last_report_watts: i64,
+    pub max_report_watts: i64,
+}
+
+/// One element of the response body for "/ivp/meters/readings"
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MeterReading {
+    /// identifies the meter (see "/ivp/meters")
+    pub eid: i64,
+    /// Unix timestamp of this reading
+    pub timestamp: i64,
+    /// lifetime energy delivered, in Watt-hours
+    pub act_energy_dlvd: f64,
+    /// lifetime energy received, in Watt-hours
+    pub act_energy_rcvd: f64,
+    /// current power, in Watts
+    pub active_power: f64,
+}
+
+/// Turns successive readings of a lifetime energy counter into records
+/// describing the energy between readings
+pub struct CounterTracker {
+    last: Option<(chrono::DateTime<chrono::Utc>, i64)>,
+}
+
+/// Describes what happened with one reading given to
+/// [`CounterTracker::sample()`]
+#[derive(Debug)]
+pub enum CounterSample {
+    /// this was the first reading, so there's no interval to report yet
+    First,
+    /// the Envoy hasn't taken a new reading since the last sample
+    Stale,
+    /// the counter went backwards (e.g., because the Envoy was reset), so
+    /// the interval starting at `start` was skipped
+    WentBackwards {
+        start: chrono::DateTime<chrono::Utc>,
+        from_wh: i64,
+        to_wh: i64,
+    },
+    /// energy between the previous reading and this one
+    Energy(EnergyProduced),
+}
+
+impl CounterTracker {
+    pub fn new() -> CounterTracker {
+        CounterTracker { last: None }
+    }
+
+    /// Processes the next reading of the counter
+    pub fn sample(&mut self, measurement: &Measurement) -> CounterSample {
+        let when = if measurement.reading_time > 0 {
+            chrono::Utc.timestamp(measurement.reading_time, 0)
+        } else {
+            chrono::Utc::now()
+        };
+        // We round the lifetime counter (rather than the difference) so that
+        // rounding errors don't accumulate over many samples.
+        let wh_lifetime = measurement.wh_lifetime.round() as i64;
+        let (last_when, last_wh_lifetime) = match self.last {
+            Some(l) => l,
+            None => {
+                self.last = Some((when, wh_lifetime));
+                return CounterSample::First;
+            }
+        };
+
+        if when <= last_when {
+            return CounterSample::Stale;
+        }
+
+        self.last = Some((when, wh_lifetime));
+        if wh_lifetime < last_wh_lifetime {
+            return CounterSample::WentBackwards {
+                start: last_when,
+                from_wh: last_wh_lifetime,
+                to_wh: wh_lifetime,
+            };
+        }
+
+        CounterSample::Energy(EnergyProduced {
+            datetime_utc: last_when,
+            datetime_local: last_when.with_timezone(&chrono::Local),
+            energy_wh: WattHours::from_kwh(
+                (wh_lifetime - last_wh_lifetime) as f64 / 1000f64,
+            ),
+            devices_reporting: None,
+            quality: None,
+        })
+    }
+}
+
+impl Default for CounterTracker {
+    fn default() -> Self {
+        CounterTracker::new()
+    }
+}
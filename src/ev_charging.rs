@@ -0,0 +1,349 @@
+//! EV charging sessions
+//!
+//! Many EV chargers can export a log of charging sessions.  We read these from
+//! a generic CSV format with columns "start", "end", and "energy_kwh" (the
+//! energy delivered during the session).  Other columns (e.g., the vehicle or
+//! the charger's idea of the cost) are ignored, so an export can usually be
+//! used as-is after renaming its columns.  Times may be in RFC 3339 format
+//! (e.g., "2022-01-04T22:15:00-08:00") or local time without an offset (e.g.,
+//! "2022-01-04 22:15:00"), which is interpreted in the report's time zone.
+//!
+//! Chargers draw roughly constant power for most of a session, so we spread
+//! each session's energy evenly over the time it covers and load the result
+//! as a sub-metered channel alongside production and net usage.  Since the
+//! charger is behind the meter, its energy is already part of consumption.
+//! Like other sources, two files shouldn't report different energy for the
+//! same hour, so sessions from overlapping exports should be deduplicated
+//! first.
+//!
+//! [`ev_charging()`] reports how much energy went to the EV in each period,
+//! how much of that was covered by solar, and what it cost.  Solar production
+//! in each hour is shared among everything running in the house in proportion
+//! to how much each used, so an EV charging at noon on a sunny day is mostly
+//! (but not necessarily entirely) solar-powered.
+
+use crate::bucket::Bucketer;
+use crate::common::Energy;
+use crate::common::WattHours;
+use crate::data_aggregator::DataLoader;
+use crate::tariff::Tariff;
+use anyhow::bail;
+use chrono::NaiveDateTime;
+use chrono::TimeZone;
+use chrono::Timelike;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Formats accepted for local times without an offset
+const LOCAL_TIME_FORMATS: &[&str] =
+    &["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"];
+
+/// One charging session
+#[derive(Debug, Deserialize)]
+pub struct ChargingSession {
+    #[serde(deserialize_with = "deserialize_time")]
+    pub start: chrono::DateTime<chrono::Utc>,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub end: chrono::DateTime<chrono::Utc>,
+    pub energy_kwh: f64,
+}
+
+/// Custom deserializer for times in either RFC 3339 format or local time
+/// without an offset
+fn deserialize_time<'de, D>(
+    deserializer: D,
+) -> Result<chrono::DateTime<chrono::Utc>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let time_string: String = Deserialize::deserialize(deserializer)?;
+    let time_string = time_string.trim();
+    if let Ok(t) = chrono::DateTime::parse_from_rfc3339(time_string) {
+        return Ok(t.with_timezone(&chrono::Utc));
+    }
+    let local = LOCAL_TIME_FORMATS
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(time_string, f).ok())
+        .ok_or_else(|| {
+            serde::de::Error::custom(format!(
+                "unsupported time format: {:?}",
+                time_string
+            ))
+        })?;
+    // When the clock goes back, the repeated local times are ambiguous.  We
+    // pick the first one, which at worst puts an hour of charging in the
+    // wrong hour.
+    chrono::Local
+        .from_local_datetime(&local)
+        .earliest()
+        .map(|t| t.with_timezone(&chrono::Utc))
+        .ok_or_else(|| {
+            serde::de::Error::custom(format!(
+                "local time does not exist: {:?}",
+                time_string
+            ))
+        })
+}
+
+impl ChargingSession {
+    /// Spreads the session's energy evenly over the time it covers, returning
+    /// one record for each (UTC) hour
+    ///
+    /// The records add up to exactly the session's energy.
+    pub fn hourly_energy(&self) -> Result<Vec<Energy>, anyhow::Error> {
+        if self.end <= self.start {
+            bail!(
+                "charging session ends ({}) before it starts ({})",
+                self.end,
+                self.start
+            );
+        }
+        if self.energy_kwh < 0.0 {
+            bail!(
+                "charging session starting {} has negative energy ({} kWh)",
+                self.start,
+                self.energy_kwh
+            );
+        }
+
+        let duration = (self.end - self.start).num_seconds() as f64;
+        let mut records = Vec::new();
+        let mut assigned = WattHours::from(0);
+        let mut hour_start = self
+            .start
+            .with_minute(0)
+            .and_then(|t| t.with_second(0))
+            .and_then(|t| t.with_nanosecond(0))
+            .unwrap();
+        while hour_start < self.end {
+            let hour_end = hour_start + chrono::Duration::hours(1);
+            let elapsed = (hour_end.min(self.end) - self.start).num_seconds();
+            let cumulative = WattHours::from_kwh(
+                self.energy_kwh * (elapsed as f64) / duration,
+            );
+            let mut energy_wh = cumulative;
+            energy_wh -= assigned;
+            assigned = cumulative;
+            records.push(Energy {
+                datetime: hour_start,
+                energy_wh,
+                partial: false,
+                channel: None,
+            });
+            hour_start = hour_end;
+        }
+        Ok(records)
+    }
+}
+
+/// EV charging in one period
+///
+/// The share of consumption only counts hours with net usage data, and the
+/// solar and cost figures only count hours that also have complete production
+/// data.  If `hours_missing_net_usage` or `hours_missing_production` is
+/// nonzero, those figures don't cover all of the EV's energy for the period.
+#[derive(Debug, Serialize)]
+pub struct EvCharging {
+    pub period_start: NaiveDateTime,
+    /// number of hours in which the EV charged
+    pub charging_hours: usize,
+    /// number of charging hours without net usage data
+    pub hours_missing_net_usage: usize,
+    /// number of charging hours with net usage data but without complete
+    /// production data
+    pub hours_missing_production: usize,
+    pub ev_kwh: f64,
+    /// percentage of the house's consumption that went to the EV
+    pub share_of_consumption_pct: Option<f64>,
+    /// EV energy covered by solar production in the same hour
+    pub solar_kwh: f64,
+    /// percentage of EV energy covered by solar production
+    pub solar_pct: Option<f64>,
+    /// cost of the EV energy that came from the grid, if we know the tariff
+    pub cost: Option<f64>,
+    /// what the EV energy would have cost without solar, if we know the
+    /// tariff
+    pub cost_without_solar: Option<f64>,
+}
+
+/// Running totals for one period (in watt-hours)
+#[derive(Default)]
+struct Totals {
+    charging_hours: usize,
+    hours_missing_net_usage: usize,
+    hours_missing_production: usize,
+    ev: f64,
+    /// EV energy in hours with net usage data
+    ev_with_net_usage: f64,
+    /// consumption in hours with net usage data
+    consumed: f64,
+    /// EV energy in hours with net usage and complete production data
+    ev_with_production: f64,
+    solar: f64,
+    cost: f64,
+    cost_without_solar: f64,
+}
+
+/// Summarizes EV charging for each period chosen by `bucketer` (e.g.,
+/// [`crate::bucket::Months`])
+///
+/// Periods from the first one with EV charging data through the last one are
+/// reported (including any in between with no charging).  If `tariff` is
+/// given, it's used to price the energy.
+pub fn ev_charging<B>(
+    aggr: &DataLoader,
+    bucketer: B,
+    tariff: Option<&Tariff>,
+) -> Vec<EvCharging>
+where
+    B: Bucketer<Key = NaiveDateTime>,
+{
+    let mut periods: BTreeMap<NaiveDateTime, Totals> = BTreeMap::new();
+    let hours = aggr.aggregate(|t: &chrono::DateTime<chrono::Utc>| Some(*t));
+    for (hour_start, totals) in hours {
+        let period_start = match bucketer.bucket(&hour_start) {
+            Some(period_start) => period_start,
+            None => continue,
+        };
+        let entry = periods.entry(period_start).or_default();
+        let ev = totals.ev_charged.as_wh() as f64;
+        let charging = ev > 0.0;
+        entry.charging_hours += usize::from(charging);
+        entry.ev += ev;
+
+        // Without net usage data, we don't know what the house consumed.
+        if totals.net_usage_hours == 0 {
+            entry.hours_missing_net_usage += usize::from(charging);
+            continue;
+        }
+        // If the meter doesn't agree with the charger, the house used at least
+        // what the EV did.
+        let consumed = (totals.consumed.as_wh() as f64).max(ev);
+        entry.ev_with_net_usage += ev;
+        entry.consumed += consumed;
+
+        // Without complete production data, we don't know how much of that
+        // was solar.
+        if totals.production_hours == 0 || totals.production_partial_hours > 0 {
+            entry.hours_missing_production += usize::from(charging);
+            continue;
+        }
+        let produced = totals.produced.as_wh() as f64;
+        let self_consumed = produced.max(0.0).min(consumed);
+        let solar =
+            if consumed > 0.0 { ev * self_consumed / consumed } else { 0.0 };
+        entry.ev_with_production += ev;
+        entry.solar += solar;
+        if let Some(tariff) = tariff {
+            let rate = tariff.rate_at(&hour_start);
+            entry.cost += (ev - solar) / 1000.0 * rate;
+            entry.cost_without_solar += ev / 1000.0 * rate;
+        }
+    }
+
+    let first = periods.iter().find(|(_, t)| t.ev > 0.0).map(|(k, _)| *k);
+    let last = periods.iter().rev().find(|(_, t)| t.ev > 0.0).map(|(k, _)| *k);
+    let (first, last) = match (first, last) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Vec::new(),
+    };
+    let kwh = |wh: f64| (wh / 10.0).round() / 100.0;
+    let pct = |part: f64, whole: f64| {
+        if whole > 0.0 {
+            Some((part * 1000.0 / whole).round() / 10.0)
+        } else {
+            None
+        }
+    };
+    let money = |value: f64| tariff.map(|_| (value * 100.0).round() / 100.0);
+    periods
+        .range(first..=last)
+        .map(|(period_start, t)| EvCharging {
+            period_start: *period_start,
+            charging_hours: t.charging_hours,
+            hours_missing_net_usage: t.hours_missing_net_usage,
+            hours_missing_production: t.hours_missing_production,
+            ev_kwh: kwh(t.ev),
+            share_of_consumption_pct: pct(t.ev_with_net_usage, t.consumed),
+            solar_kwh: kwh(t.solar),
+            solar_pct: pct(t.solar, t.ev_with_production),
+            cost: money(t.cost),
+            cost_without_solar: money(t.cost_without_solar),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket;
+    use crate::common::EnergyProduced;
+    use crate::common::NetEnergyUsed;
+    use crate::common::Quality;
+    use crate::data_aggregator::Source;
+    use crate::data_source::EvSessionCsvSource;
+    use chrono::Datelike;
+
+    #[test]
+    fn test_ev_charging_skips_hours_missing_data() {
+        // The EV charges for one hour at noon UTC on each of four days in
+        // June.  On the 15th, we have all the data.  On the 16th, there's no
+        // production data; on the 17th, no net usage data; and on the 18th,
+        // the production data is partial.
+        let hour = |d: u32| chrono::Utc.ymd(2022, 6, d).and_hms(12, 0, 0);
+        let sessions = "start,end,energy_kwh\n\
+            2022-06-15T12:00:00Z,2022-06-15T13:00:00Z,2\n\
+            2022-06-16T12:00:00Z,2022-06-16T13:00:00Z,1\n\
+            2022-06-17T12:00:00Z,2022-06-17T13:00:00Z,1\n\
+            2022-06-18T12:00:00Z,2022-06-18T13:00:00Z,1\n";
+        let production = [
+            (15, 1000, Quality::Ok),
+            (17, 500, Quality::Ok),
+            (18, 500, Quality::Partial),
+        ]
+        .map(|(d, wh, quality)| {
+            Ok(EnergyProduced {
+                datetime_utc: hour(d),
+                datetime_local: hour(d).with_timezone(&chrono::Local),
+                energy_wh: WattHours::from(wh),
+                devices_reporting: None,
+                quality: Some(quality),
+            })
+        });
+        let net_usage = [(15, 3000), (16, 2000), (18, 500)].map(|(d, wh)| {
+            Ok(NetEnergyUsed {
+                timestamp_start_utc: hour(d),
+                net_used_wh: WattHours::from(wh),
+            })
+        });
+        let mut aggr = DataLoader::new();
+        aggr.load_production(Source::new("p"), production.into_iter()).unwrap();
+        aggr.load_net_usage(Source::new("n"), net_usage.into_iter()).unwrap();
+        aggr.load_source(&mut EvSessionCsvSource::new(
+            "ev",
+            sessions.as_bytes(),
+        ))
+        .unwrap();
+
+        let tariff = Tariff::flat(0.30);
+        let months = ev_charging(&aggr, bucket::Months, Some(&tariff));
+        assert_eq!(months.len(), 1);
+        let june = &months[0];
+        assert_eq!(june.period_start.month(), 6);
+        assert_eq!(june.charging_hours, 4);
+        assert_eq!(june.hours_missing_net_usage, 1);
+        assert_eq!(june.hours_missing_production, 2);
+        assert_eq!(june.ev_kwh, 5.0);
+        // Leaving out the 17th, the EV used 4 kWh of the 7 kWh consumed (4
+        // kWh on the 15th, 2 kWh on the 16th, and 1 kWh on the 18th).
+        assert_eq!(june.share_of_consumption_pct, Some(57.1));
+        // Only the 15th counts for solar.  A quarter of that hour's
+        // consumption came from solar, so a quarter of the EV's 2 kWh did.
+        assert_eq!(june.solar_kwh, 0.5);
+        assert_eq!(june.solar_pct, Some(25.0));
+        assert_eq!(june.cost, Some(0.45));
+        assert_eq!(june.cost_without_solar, Some(0.6));
+    }
+}
@@ -0,0 +1,81 @@
+//! Checkpoint for resuming an interrupted `enphase-fetch` run
+//!
+//! Fetching months of data takes hours because of the Enlighten rate limit.
+//! As `enphase-fetch` finishes writing each day's data, it records how far it
+//! got in a small TOML file.  If the run fails or is interrupted, rerunning
+//! with `--resume` picks up after the last day that was written out.  The
+//! checkpoint records the dates that the run resolved (e.g., from
+//! `--last-week`), so resuming on a later day fetches the same range.
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Describes the progress of one `enphase-fetch` run
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Checkpoint {
+    /// system whose data is being fetched
+    pub system_id: i32,
+    /// first date requested for this run
+    pub start_date: chrono::NaiveDate,
+    /// first date after the ones requested for this run
+    pub end_date: chrono::NaiveDate,
+    /// last date whose data has been completely written out, if any
+    pub last_completed: Option<chrono::NaiveDate>,
+    /// file that data is being written to (none for stdout or `output_dir`)
+    pub output: Option<PathBuf>,
+    /// directory that data is being written to, if any
+    pub output_dir: Option<PathBuf>,
+}
+
+impl Checkpoint {
+    /// Returns the first date that has not yet been written out
+    pub fn next_date(&self) -> chrono::NaiveDate {
+        match self.last_completed {
+            Some(date) => date.succ(),
+            None => self.start_date,
+        }
+    }
+
+    /// Loads a checkpoint saved by a previous run
+    pub fn load(path: &Path) -> Result<Checkpoint, anyhow::Error> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("read checkpoint {:?}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("parsing checkpoint {:?}", path.display()))
+    }
+
+    /// Saves the checkpoint to `path`, replacing its contents atomically
+    ///
+    /// The directory containing `path` is created if needed.
+    pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let contents =
+            toml::to_string(self).context("serializing checkpoint")?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("mkdir {:?}", parent.display()))?;
+        }
+        let tmp_path = path.with_extension("toml.tmp");
+        std::fs::write(&tmp_path, contents)
+            .with_context(|| format!("write {:?}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, path).with_context(|| {
+            format!("rename {:?} to {:?}", tmp_path.display(), path.display())
+        })
+    }
+
+    /// Removes the checkpoint at `path` once a run has finished
+    ///
+    /// It's not an error if the file doesn't exist.
+    pub fn remove(path: &Path) -> Result<(), anyhow::Error> {
+        match std::fs::remove_file(path) {
+            Ok(()) => Ok(()),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                Ok(())
+            }
+            Err(error) => Err(error)
+                .with_context(|| format!("remove {:?}", path.display())),
+        }
+    }
+}
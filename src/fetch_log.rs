@@ -0,0 +1,144 @@
+//! Records what production data we've fetched and whether it was complete
+//!
+//! Enlighten happily returns data for a day even if the Envoy hasn't finished
+//! uploading that day's data (e.g., because it was offline for a while).  The
+//! `meta` block in each response tells us the end of the last interval for
+//! which Enlighten has energy data (`last_energy_at`).  If that's before the
+//! end of the day we asked for, the data for that day is incomplete.  We record
+//! that here so that we can refetch the day later.
+//!
+//! The same day may be fetched into different places (stdout, a file given
+//! with `--output`, or a file in an `--output-dir`).  Data written to one of
+//! these says nothing about what's in the others, so the log keeps track of
+//! each day separately for each place it was written.
+
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Describes one fetch of one (UTC) day's data
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct DayFetch {
+    pub date: chrono::NaiveDate,
+    /// file that the data was written to (none for stdout)
+    ///
+    /// Logs written before this was recorded don't have it.  Those entries
+    /// are treated as having gone to stdout.
+    #[serde(default)]
+    pub output: Option<PathBuf>,
+    pub fetched_at: chrono::DateTime<chrono::Utc>,
+    /// end of the last interval for which Enlighten had energy data at the
+    /// time of the fetch
+    pub last_energy_at: chrono::DateTime<chrono::Utc>,
+    /// number of intervals returned
+    pub nintervals: usize,
+    /// whether Enlighten had data through the end of the day
+    pub complete: bool,
+}
+
+impl DayFetch {
+    /// Describes a fetch of `date`'s data into `output`, given the
+    /// `last_energy_at` value from the response
+    pub fn new(
+        date: chrono::NaiveDate,
+        output: Option<&Path>,
+        last_energy_at: chrono::DateTime<chrono::Utc>,
+        nintervals: usize,
+    ) -> DayFetch {
+        let day_end = chrono::DateTime::<chrono::Utc>::from_utc(
+            date.succ().and_hms(0, 0, 0),
+            chrono::Utc,
+        );
+        DayFetch {
+            date,
+            output: output.map(Path::to_owned),
+            fetched_at: chrono::Utc::now(),
+            last_energy_at,
+            nintervals,
+            complete: last_energy_at >= day_end,
+        }
+    }
+}
+
+/// Persistent record of the most recent fetch of each day into each output
+///
+/// This is stored as a CSV file with one [`DayFetch`] per line.
+pub struct FetchLog {
+    path: PathBuf,
+    days: BTreeMap<(Option<PathBuf>, chrono::NaiveDate), DayFetch>,
+}
+
+impl FetchLog {
+    /// Loads the fetch log from `path`
+    ///
+    /// It's not an error if the file doesn't exist.  That just means we
+    /// haven't fetched anything yet.
+    pub fn load(path: &Path) -> Result<FetchLog, anyhow::Error> {
+        let mut days = BTreeMap::new();
+        match std::fs::File::open(path) {
+            Ok(file) => {
+                for record in csv::Reader::from_reader(file).deserialize() {
+                    let day: DayFetch = record.with_context(|| {
+                        format!("reading fetch log {:?}", path.display())
+                    })?;
+                    days.insert((day.output.clone(), day.date), day);
+                }
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => (),
+            Err(error) => {
+                return Err(error).with_context(|| {
+                    format!("open fetch log {:?}", path.display())
+                })
+            }
+        }
+
+        Ok(FetchLog { path: path.to_owned(), days })
+    }
+
+    /// Returns the most recent fetch of `date` into `output` (or stdout, if
+    /// `output` is `None`), if any
+    pub fn get(
+        &self,
+        output: Option<&Path>,
+        date: &chrono::NaiveDate,
+    ) -> Option<&DayFetch> {
+        self.days.get(&(output.map(Path::to_owned), *date))
+    }
+
+    /// Records a fetch and saves the log
+    ///
+    /// We save the whole log each time so that if we crash partway through a
+    /// long fetch, the log still reflects what we fetched.
+    pub fn record(&mut self, day: DayFetch) -> Result<(), anyhow::Error> {
+        self.days.insert((day.output.clone(), day.date), day);
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), anyhow::Error> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("mkdir {:?}", parent.display()))?;
+        }
+        let tmp_path = self.path.with_extension("csv.tmp");
+        let file = std::fs::File::create(&tmp_path)
+            .with_context(|| format!("create {:?}", tmp_path.display()))?;
+        let mut writer = csv::Writer::from_writer(file);
+        for day in self.days.values() {
+            writer
+                .serialize(day)
+                .with_context(|| format!("write {:?}", tmp_path.display()))?;
+        }
+        writer
+            .flush()
+            .with_context(|| format!("flush {:?}", tmp_path.display()))?;
+        drop(writer);
+        std::fs::rename(&tmp_path, &self.path).with_context(|| {
+            format!(
+                "rename {:?} to {:?}",
+                tmp_path.display(),
+                self.path.display()
+            )
+        })
+    }
+}
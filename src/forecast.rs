@@ -0,0 +1,457 @@
+//! Forecasting solar production from history
+//!
+//! To forecast production for an hour, we look at what the array produced in
+//! the same (local) hour of the day on days near the same time of year, in
+//! every year of history.  The mean of those is the "expected" production,
+//! which reflects typical weather.  A high percentile of them approximates
+//! production on a clear day.
+//!
+//! If we have a cloud-cover forecast for the hour, we start from the clear-day
+//! value and reduce it according to the cloud cover (using the Kasten-Czeplak
+//! relationship between cloud cover and sunlight).  Otherwise, the forecast is
+//! just the expected value.
+//!
+//! Cloud cover comes from a CSV file with columns "datetime_utc" (the start of
+//! each hour, in RFC 3339 format) and "cloud_cover" (percent of the sky
+//! covered).
+//!
+//! To see how good the forecasts are, [`Forecaster::backtest()`] forecasts
+//! some recent days using only the history before them and compares with what
+//! actually happened.  As a baseline, it also reports the error of simply
+//! predicting that each day will be the same as the day before.
+
+use crate::bucket;
+use crate::common::local_day_hours;
+use crate::data_aggregator::DataLoader;
+use crate::stats;
+use anyhow::Context;
+use chrono::Datelike;
+use chrono::Timelike;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Days on either side of a date that count as "the same time of year"
+const SEASON_WINDOW_DAYS: i64 = 15;
+
+/// Percentile of historical production used as the clear-day value
+const CLEAR_DAY_PERCENTILE: f64 = 90.0;
+
+/// Forecast cloud cover for each hour
+pub struct CloudCover {
+    /// fraction of the sky covered (0-1), by the start of each hour (UTC)
+    by_hour: BTreeMap<chrono::DateTime<chrono::Utc>, f64>,
+}
+
+#[derive(Deserialize)]
+struct CloudCoverRecord {
+    datetime_utc: chrono::DateTime<chrono::Utc>,
+    cloud_cover: f64,
+}
+
+impl CloudCover {
+    /// Loads cloud cover from the CSV file at `path` (see the module
+    /// documentation for the format)
+    pub fn load(path: &Path) -> Result<CloudCover, anyhow::Error> {
+        let mut reader = csv::Reader::from_path(path)
+            .with_context(|| format!("open {:?}", path.display()))?;
+        let mut by_hour = BTreeMap::new();
+        for (i, maybe_record) in reader.deserialize().enumerate() {
+            let record: CloudCoverRecord = maybe_record.with_context(|| {
+                format!("read record {} from {:?}", i + 1, path.display())
+            })?;
+            let hour_start = record
+                .datetime_utc
+                .with_minute(0)
+                .and_then(|t| t.with_second(0))
+                .and_then(|t| t.with_nanosecond(0))
+                .unwrap();
+            by_hour.insert(
+                hour_start,
+                (record.cloud_cover / 100.0).clamp(0.0, 1.0),
+            );
+        }
+        Ok(CloudCover { by_hour })
+    }
+
+    fn get(&self, hour_start: &chrono::DateTime<chrono::Utc>) -> Option<f64> {
+        self.by_hour.get(hour_start).copied()
+    }
+}
+
+/// Returns the fraction of clear-sky sunlight that gets through with the given
+/// cloud cover (0-1), per Kasten and Czeplak (1980)
+fn cloud_factor(cloud_cover: f64) -> f64 {
+    1.0 - 0.75 * cloud_cover.powf(3.4)
+}
+
+/// Forecast for one hour
+///
+/// Energy values are in watt-hours.
+#[derive(Debug, Serialize)]
+pub struct HourForecast {
+    pub hour_start_utc: chrono::DateTime<chrono::Utc>,
+    pub hour_start_local: chrono::NaiveDateTime,
+    /// mean production in this hour at this time of year
+    pub expected: f64,
+    /// production in this hour at this time of year on a clear day
+    pub clear_day: f64,
+    /// forecast cloud cover (percent), if we have it
+    pub cloud_cover: Option<f64>,
+    /// forecast production
+    pub forecast: f64,
+}
+
+/// One hour of production history
+struct Sample {
+    date: chrono::NaiveDate,
+    hour: u32,
+    produced: f64,
+}
+
+/// Forecasts production from historical data
+pub struct Forecaster {
+    /// complete (not partial) hours of production from days with a full day
+    /// of production data, in order
+    history: Vec<Sample>,
+}
+
+impl Forecaster {
+    /// Builds a forecaster from the production data in `aggr`
+    ///
+    /// Enlighten doesn't report intervals when the system isn't producing, so
+    /// hours with no data in a day that we have all of the production data
+    /// for (see [`DataLoader::has_full_day_of_production()`]) count as
+    /// producing nothing.  Other days are left out, since we can't tell
+    /// missing data from darkness there.
+    pub fn new(aggr: &DataLoader) -> Forecaster {
+        let hours =
+            aggr.aggregate(|t: &chrono::DateTime<chrono::Utc>| Some(*t));
+        let mut history = Vec::new();
+        for (day_start, _) in aggr.aggregate(bucket::Days) {
+            let date = day_start.date();
+            if !aggr.has_full_day_of_production(&date) {
+                continue;
+            }
+            for hour_start in local_day_hours(&date) {
+                let hour_end = hour_start + chrono::Duration::hours(1);
+                let mut produced = 0;
+                let mut partial = false;
+                for (_, totals) in hours.range(hour_start..hour_end) {
+                    produced += totals.produced.as_wh();
+                    partial |= totals.production_partial_hours > 0;
+                }
+                if !partial {
+                    history.push(Sample {
+                        date,
+                        hour: hour_start.with_timezone(&chrono::Local).hour(),
+                        produced: produced as f64,
+                    });
+                }
+            }
+        }
+        Forecaster { history }
+    }
+
+    /// Returns the last (local) day for which we have production data
+    pub fn last_date(&self) -> Option<chrono::NaiveDate> {
+        self.history.last().map(|s| s.date)
+    }
+
+    /// Forecasts the hour starting at `hour_start` using only history from
+    /// before `cutoff`
+    ///
+    /// Returns `None` if there's no history for this hour and time of year.
+    fn forecast_hour(
+        &self,
+        hour_start: &chrono::DateTime<chrono::Utc>,
+        cutoff: &chrono::NaiveDate,
+        cloud_cover: Option<&CloudCover>,
+    ) -> Option<HourForecast> {
+        let local = hour_start.with_timezone(&chrono::Local).naive_local();
+        let date = local.date();
+        let mut values: Vec<f64> = self
+            .history
+            .iter()
+            .take_while(|s| s.date < *cutoff)
+            .filter(|s| {
+                s.hour == local.hour()
+                    && season_distance(&s.date, &date) <= SEASON_WINDOW_DAYS
+            })
+            .map(|s| s.produced)
+            .collect();
+        if values.is_empty() {
+            return None;
+        }
+        stats::sort(&mut values);
+        let expected = stats::mean(&values);
+        let clear_day = stats::percentile(&values, CLEAR_DAY_PERCENTILE);
+        let cloud_cover = cloud_cover.and_then(|c| c.get(hour_start));
+        let forecast = match cloud_cover {
+            Some(c) => clear_day * cloud_factor(c),
+            None => expected,
+        };
+        Some(HourForecast {
+            hour_start_utc: *hour_start,
+            hour_start_local: local,
+            expected: round(expected),
+            clear_day: round(clear_day),
+            cloud_cover: cloud_cover.map(|c| round(c * 100.0)),
+            forecast: round(forecast),
+        })
+    }
+
+    /// Forecasts each hour of `ndays` (local) days starting with `start`
+    pub fn forecast(
+        &self,
+        start: &chrono::NaiveDate,
+        ndays: u32,
+        cloud_cover: Option<&CloudCover>,
+    ) -> Vec<HourForecast> {
+        let cutoff = chrono::naive::MAX_DATE;
+        (0..ndays)
+            .map(|i| *start + chrono::Duration::days(i64::from(i)))
+            .flat_map(|date| local_day_hours(&date))
+            .filter_map(|h| self.forecast_hour(&h, &cutoff, cloud_cover))
+            .collect()
+    }
+
+    /// Forecasts each of the last `ndays` days of history using only the
+    /// history before them, and compares with what actually happened
+    pub fn backtest(
+        &self,
+        ndays: u32,
+        cloud_cover: Option<&CloudCover>,
+    ) -> (Vec<BacktestDay>, BacktestSummary) {
+        let mut actual: BTreeMap<chrono::NaiveDate, BTreeMap<u32, f64>> =
+            BTreeMap::new();
+        for sample in &self.history {
+            *actual
+                .entry(sample.date)
+                .or_default()
+                .entry(sample.hour)
+                .or_default() += sample.produced;
+        }
+        let last = match self.last_date() {
+            Some(last) => last,
+            None => return (Vec::new(), BacktestSummary::default()),
+        };
+        let cutoff = last - chrono::Duration::days(i64::from(ndays) - 1);
+
+        let mut days = Vec::new();
+        let mut hourly_errors = Vec::new();
+        for (date, hours) in actual.range(cutoff..) {
+            let forecasts: Vec<HourForecast> = local_day_hours(date)
+                .iter()
+                .filter_map(|h| self.forecast_hour(h, &cutoff, cloud_cover))
+                .collect();
+            // Only compare hours that we have both a forecast and data for.
+            // The persistence forecast covers the same hours of the day
+            // before (if we have all of them).
+            let previous = actual.get(&date.pred());
+            let mut produced = 0.0;
+            let mut forecast = 0.0;
+            let mut persistence = previous.map(|_| 0.0);
+            for f in &forecasts {
+                let hour = f.hour_start_local.hour();
+                if let Some(a) = hours.get(&hour) {
+                    produced += a;
+                    forecast += f.forecast;
+                    hourly_errors.push(f.forecast - a);
+                    persistence = persistence
+                        .and_then(|p| Some(p + previous?.get(&hour)?));
+                }
+            }
+            days.push(BacktestDay {
+                date: *date,
+                produced: round(produced),
+                forecast: round(forecast),
+                error: round(forecast - produced),
+                persistence: persistence.map(round),
+                persistence_error: persistence.map(|p| round(p - produced)),
+            });
+        }
+
+        let abs_errors: Vec<f64> = days.iter().map(|d| d.error.abs()).collect();
+        let persistence_abs_errors: Vec<f64> = days
+            .iter()
+            .filter_map(|d| d.persistence_error.map(f64::abs))
+            .collect();
+        let total_produced: f64 = days.iter().map(|d| d.produced).sum();
+        let summary = BacktestSummary {
+            ndays: days.len(),
+            mean_abs_error: round(stats::mean(&abs_errors)),
+            mean_abs_error_pct: (total_produced > 0.0).then(|| {
+                round(abs_errors.iter().sum::<f64>() * 100.0 / total_produced)
+            }),
+            bias: round(stats::mean(
+                &days.iter().map(|d| d.error).collect::<Vec<_>>(),
+            )),
+            hourly_rms_error: round(
+                stats::mean(
+                    &hourly_errors.iter().map(|e| e * e).collect::<Vec<_>>(),
+                )
+                .sqrt(),
+            ),
+            persistence_mean_abs_error: (!persistence_abs_errors.is_empty())
+                .then(|| round(stats::mean(&persistence_abs_errors))),
+        };
+        (days, summary)
+    }
+}
+
+/// Compares the forecast for one day with what actually happened
+///
+/// Energy values are in watt-hours.
+#[derive(Debug, Serialize)]
+pub struct BacktestDay {
+    pub date: chrono::NaiveDate,
+    pub produced: f64,
+    pub forecast: f64,
+    /// `forecast - produced`
+    pub error: f64,
+    /// production the day before in the same hours as `produced` (the
+    /// "persistence" forecast)
+    pub persistence: Option<f64>,
+    /// `persistence - produced`
+    pub persistence_error: Option<f64>,
+}
+
+/// Summarizes forecast error over a backtest
+///
+/// Energy values are in watt-hours.
+#[derive(Debug, Default)]
+pub struct BacktestSummary {
+    pub ndays: usize,
+    /// mean absolute error in daily production
+    pub mean_abs_error: f64,
+    /// total absolute error in daily production as a percentage of total
+    /// production
+    pub mean_abs_error_pct: Option<f64>,
+    /// mean error in daily production (positive if forecasts are too high)
+    pub bias: f64,
+    /// root mean square error in hourly production
+    pub hourly_rms_error: f64,
+    /// mean absolute error of the persistence forecast, for comparison
+    pub persistence_mean_abs_error: Option<f64>,
+}
+
+/// Returns the number of days between the days of the year of `a` and `b`,
+/// wrapping around the end of the year
+fn season_distance(a: &chrono::NaiveDate, b: &chrono::NaiveDate) -> i64 {
+    let d = (i64::from(a.ordinal()) - i64::from(b.ordinal())).abs();
+    d.min(365 - d)
+}
+
+/// Rounds to tenths of a watt-hour
+fn round(value: f64) -> f64 {
+    (value * 10.0).round() / 10.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::EnergyProduced;
+    use crate::common::WattHours;
+    use crate::data_aggregator::Source;
+
+    fn june(day: u32) -> chrono::NaiveDate {
+        chrono::NaiveDate::from_ymd(2022, 6, day)
+    }
+
+    #[test]
+    fn test_missing_hours_produce_nothing() {
+        // Two days with production only in (local) hours 10 and 11, plus a
+        // token amount in the first and last hours so that the data covers
+        // both UTC days that each local day overlaps.
+        let mut records = Vec::new();
+        for (day, wh) in [(1, 500), (2, 300)] {
+            let hours = local_day_hours(&june(day));
+            let last = hours.len() - 1;
+            for (i, wh) in [(0, 1), (10, wh), (11, wh), (last, 1)] {
+                // Data is kept by UTC hour.  In time zones that aren't a
+                // whole number of hours from UTC, use the UTC hour that
+                // starts during this local hour.
+                let t = hours[i] + chrono::Duration::minutes(59);
+                records.push(EnergyProduced {
+                    datetime_utc: t,
+                    datetime_local: t.with_timezone(&chrono::Local),
+                    energy_wh: WattHours::from(wh),
+                    devices_reporting: None,
+                    quality: None,
+                });
+            }
+        }
+        let mut aggr = DataLoader::new();
+        aggr.load_production(
+            Source::new("test production"),
+            records.into_iter().map(Ok),
+        )
+        .unwrap();
+
+        let forecaster = Forecaster::new(&aggr);
+        assert_eq!(forecaster.last_date(), Some(june(2)));
+        let forecasts = forecaster.forecast(&june(3), 1, None);
+        assert_eq!(forecasts.len(), 24);
+        let expected: Vec<f64> = forecasts.iter().map(|f| f.expected).collect();
+        let mut want = vec![0.0; 24];
+        want[0] = 1.0;
+        want[10] = 400.0;
+        want[11] = 400.0;
+        want[23] = 1.0;
+        assert_eq!(expected, want);
+        // The clear-day value interpolates between the two days.
+        assert_eq!(forecasts[10].clear_day, 480.0);
+    }
+
+    #[test]
+    fn test_backtest() {
+        let sample =
+            |day, hour, produced| Sample { date: june(day), hour, produced };
+        let forecaster = Forecaster {
+            history: vec![
+                sample(1, 10, 500.0),
+                sample(1, 11, 300.0),
+                sample(2, 10, 300.0),
+                sample(2, 11, 100.0),
+                sample(2, 13, 70.0),
+                sample(3, 10, 460.0),
+                sample(3, 11, 200.0),
+            ],
+        };
+
+        // Both days are forecast from June 1 alone: 500 Wh at 10:00 and
+        // 300 Wh at 11:00.  Hour 13 on June 2 has no forecast, so it isn't
+        // counted in production or in the persistence forecast for June 3.
+        let (days, summary) = forecaster.backtest(2, None);
+        let got: Vec<_> = days
+            .iter()
+            .map(|d| {
+                (
+                    d.date,
+                    d.produced,
+                    d.forecast,
+                    d.error,
+                    d.persistence,
+                    d.persistence_error,
+                )
+            })
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                (june(2), 400.0, 800.0, 400.0, Some(800.0), Some(400.0)),
+                (june(3), 660.0, 800.0, 140.0, Some(400.0), Some(-260.0)),
+            ]
+        );
+        assert_eq!(summary.ndays, 2);
+        assert_eq!(summary.mean_abs_error, 270.0);
+        assert_eq!(summary.bias, 270.0);
+        // 540 Wh of error out of 1060 Wh produced
+        assert_eq!(summary.mean_abs_error_pct, Some(50.9));
+        // hourly errors are 200, 200, 40, and 100 Wh
+        assert_eq!(summary.hourly_rms_error, 151.3);
+        assert_eq!(summary.persistence_mean_abs_error, Some(330.0));
+    }
+}
@@ -0,0 +1,203 @@
+//! Data structures for tracking the equipment installed in a system
+
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::io::Write;
+
+/// Describes one piece of equipment (an Envoy, microinverter, or meter)
+#[derive(Clone, Debug, serde::Deserialize, PartialEq, Eq, serde::Serialize)]
+pub struct Device {
+    pub kind: DeviceKind,
+    pub serial_number: String,
+    pub model: String,
+    pub sku: String,
+    pub part_number: String,
+    pub manufacturer: String,
+    pub name: String,
+    pub status: String,
+}
+
+impl Device {
+    /// Returns whether `self` and `other` describe the same equipment (i.e.,
+    /// they're the same apart from their status)
+    fn same_equipment(&self, other: &Device) -> bool {
+        Device { status: String::new(), ..self.clone() }
+            == Device { status: String::new(), ..other.clone() }
+    }
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    serde::Deserialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceKind {
+    Envoy,
+    Inverter,
+    Meter,
+}
+
+/// Uniquely identifies a device within an [`Inventory`]
+type DeviceKey = (DeviceKind, String);
+
+/// Describes all of the equipment in a system at some point in time
+///
+/// Devices are always kept sorted by kind and serial number so that the
+/// serialized form is stable.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Inventory {
+    devices: BTreeMap<DeviceKey, Device>,
+}
+
+impl Inventory {
+    pub fn new() -> Inventory {
+        Inventory::default()
+    }
+
+    /// Adds `device` to the inventory
+    ///
+    /// If we already have a device of the same kind with the same serial
+    /// number, any non-empty fields of `device` replace the existing ones.
+    /// This is useful because different Enlighten endpoints provide different
+    /// information about the same device.
+    pub fn add(&mut self, device: Device) {
+        let key = (device.kind, device.serial_number.clone());
+        match self.devices.get_mut(&key) {
+            None => {
+                self.devices.insert(key, device);
+            }
+            Some(existing) => {
+                let merge = |old: &mut String, new: String| {
+                    if !new.is_empty() {
+                        *old = new;
+                    }
+                };
+                merge(&mut existing.model, device.model);
+                merge(&mut existing.sku, device.sku);
+                merge(&mut existing.part_number, device.part_number);
+                merge(&mut existing.manufacturer, device.manufacturer);
+                merge(&mut existing.name, device.name);
+                merge(&mut existing.status, device.status);
+            }
+        }
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = &Device> {
+        self.devices.values()
+    }
+
+    pub fn read_csv<R: Read>(input: R) -> Result<Inventory, anyhow::Error> {
+        let mut inventory = Inventory::new();
+        for record in csv::Reader::from_reader(input).deserialize() {
+            inventory.add(record.context("reading inventory record")?);
+        }
+        Ok(inventory)
+    }
+
+    pub fn write_csv<W: Write>(&self, output: W) -> Result<(), anyhow::Error> {
+        let mut writer = csv::Writer::from_writer(output);
+        for device in self.devices() {
+            writer.serialize(device).context("writing inventory record")?;
+        }
+        writer.flush().context("flushing inventory")
+    }
+
+    /// Returns a list of differences in equipment between `self` (the older
+    /// inventory) and `newer`
+    ///
+    /// Devices' statuses come and go (e.g., an Envoy that briefly loses its
+    /// connection), so they're not considered here.  See
+    /// [`Inventory::status_changes()`].
+    pub fn diff(&self, newer: &Inventory) -> Vec<InventoryChange> {
+        let mut changes = Vec::new();
+        for (key, old) in &self.devices {
+            match newer.devices.get(key) {
+                None => changes.push(InventoryChange::Removed(old.clone())),
+                Some(new) if !old.same_equipment(new) => {
+                    changes.push(InventoryChange::Changed {
+                        old: old.clone(),
+                        new: new.clone(),
+                    })
+                }
+                Some(_) => (),
+            }
+        }
+        for (key, new) in &newer.devices {
+            if !self.devices.contains_key(key) {
+                changes.push(InventoryChange::Added(new.clone()));
+            }
+        }
+        changes
+    }
+
+    /// Returns a description of each device in both `self` (the older
+    /// inventory) and `newer` whose status is different
+    pub fn status_changes(&self, newer: &Inventory) -> Vec<String> {
+        self.devices
+            .iter()
+            .filter_map(|(key, old)| {
+                let new = newer.devices.get(key)?;
+                if new.status == old.status {
+                    return None;
+                }
+                Some(format!(
+                    "{:?} {}: status {:?} -> {:?}",
+                    new.kind, new.serial_number, old.status, new.status
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Describes a difference between two [`Inventory`] snapshots
+#[derive(Debug)]
+pub enum InventoryChange {
+    Added(Device),
+    Removed(Device),
+    Changed { old: Device, new: Device },
+}
+
+impl std::fmt::Display for InventoryChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InventoryChange::Added(d) => write!(
+                f,
+                "added {:?} {} (model {:?}, sku {:?})",
+                d.kind, d.serial_number, d.model, d.sku
+            ),
+            InventoryChange::Removed(d) => write!(
+                f,
+                "removed {:?} {} (model {:?}, sku {:?})",
+                d.kind, d.serial_number, d.model, d.sku
+            ),
+            InventoryChange::Changed { old, new } => {
+                write!(f, "changed {:?} {}:", new.kind, new.serial_number)?;
+                let fields = [
+                    ("model", &old.model, &new.model),
+                    ("sku", &old.sku, &new.sku),
+                    ("part_number", &old.part_number, &new.part_number),
+                    ("manufacturer", &old.manufacturer, &new.manufacturer),
+                    ("name", &old.name, &new.name),
+                ];
+                for (label, old_value, new_value) in fields {
+                    if old_value != new_value {
+                        write!(
+                            f,
+                            " {} {:?} -> {:?}",
+                            label, old_value, new_value
+                        )?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -1,5 +1,29 @@
 //! Common facilities used by the various tools here
 
+pub mod alert;
+pub mod anomaly;
+pub mod base_load;
+pub mod bucket;
+pub mod channel;
 pub mod common;
-pub mod pge;
 pub mod data_aggregator;
+pub mod data_source;
+pub mod disaggregation;
+pub mod enlighten;
+pub mod enlighten_error;
+pub mod enlighten_replay;
+pub mod enlighten_v4;
+pub mod envoy_local;
+pub mod ev_charging;
+pub mod fetch_checkpoint;
+pub mod fetch_log;
+pub mod forecast;
+pub mod inventory;
+pub mod load_profile;
+pub mod pge;
+pub mod report_config;
+pub mod solar_model;
+pub mod stats;
+pub mod tariff;
+pub mod weather;
+pub mod year_over_year;
@@ -0,0 +1,199 @@
+//! Load profiles: typical energy use for each hour of the day
+//!
+//! A profile groups the hourly data by calendar month, by whether the day is a
+//! weekday or a weekend day, and by (local) hour of the day.  For each group,
+//! it reports the mean and a few percentiles of the energy produced, net used,
+//! and consumed in that hour.  This is useful for sizing batteries and for
+//! deciding when to run big loads.
+//!
+//! Only hours with net usage data and complete production data are included.
+//! Otherwise "consumed" would be too low for those hours and drag the
+//! profile down.
+
+use crate::data_aggregator::DataLoader;
+use crate::stats;
+use chrono::Datelike;
+use chrono::Timelike;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Whether a day is a weekday (Monday through Friday) or not
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DayType {
+    Weekday,
+    Weekend,
+}
+
+impl DayType {
+    pub fn of(date: &chrono::NaiveDate) -> DayType {
+        if date.weekday().number_from_monday() <= 5 {
+            DayType::Weekday
+        } else {
+            DayType::Weekend
+        }
+    }
+}
+
+/// Summarizes one hour of the day for one month and type of day
+///
+/// Energy values are in watt-hours.
+#[derive(Debug, Serialize)]
+pub struct ProfileHour {
+    pub month: u32,
+    pub day_type: DayType,
+    /// local hour of the day (0-23)
+    pub hour: u32,
+    /// number of hours of data that went into this summary
+    pub nhours: usize,
+    pub produced_mean: f64,
+    pub produced_p10: f64,
+    pub produced_p50: f64,
+    pub produced_p90: f64,
+    pub net_used_mean: f64,
+    pub net_used_p10: f64,
+    pub net_used_p50: f64,
+    pub net_used_p90: f64,
+    pub consumed_mean: f64,
+    pub consumed_p10: f64,
+    pub consumed_p50: f64,
+    pub consumed_p90: f64,
+}
+
+#[derive(Default)]
+struct Samples {
+    produced: Vec<f64>,
+    net_used: Vec<f64>,
+    consumed: Vec<f64>,
+}
+
+/// Computes the load profile from the loaded data
+///
+/// Groups with no data are omitted.
+pub fn load_profile(aggr: &DataLoader) -> Vec<ProfileHour> {
+    let mut groups: BTreeMap<(u32, DayType, u32), Samples> = BTreeMap::new();
+    let hours = aggr.aggregate(|t: &chrono::DateTime<chrono::Utc>| Some(*t));
+    for (start_utc, totals) in hours {
+        if totals.net_usage_hours == 0 || totals.production_partial_hours > 0 {
+            continue;
+        }
+        let start = start_utc.with_timezone(&chrono::Local).naive_local();
+        let key = (start.month(), DayType::of(&start.date()), start.hour());
+        let samples = groups.entry(key).or_default();
+        samples.produced.push(totals.produced.as_wh() as f64);
+        samples.net_used.push(totals.net_used.as_wh() as f64);
+        samples.consumed.push(totals.consumed.as_wh() as f64);
+    }
+
+    groups
+        .into_iter()
+        .map(|((month, day_type, hour), mut samples)| {
+            let produced = summarize(&mut samples.produced);
+            let net_used = summarize(&mut samples.net_used);
+            let consumed = summarize(&mut samples.consumed);
+            ProfileHour {
+                month,
+                day_type,
+                hour,
+                nhours: samples.produced.len(),
+                produced_mean: produced[0],
+                produced_p10: produced[1],
+                produced_p50: produced[2],
+                produced_p90: produced[3],
+                net_used_mean: net_used[0],
+                net_used_p10: net_used[1],
+                net_used_p50: net_used[2],
+                net_used_p90: net_used[3],
+                consumed_mean: consumed[0],
+                consumed_p10: consumed[1],
+                consumed_p50: consumed[2],
+                consumed_p90: consumed[3],
+            }
+        })
+        .collect()
+}
+
+/// Returns the mean, 10th, 50th, and 90th percentiles of `values`, rounded
+/// to tenths of a watt-hour
+fn summarize(values: &mut [f64]) -> [f64; 4] {
+    stats::sort(values);
+    [
+        stats::mean(values),
+        stats::percentile(values, 10.0),
+        stats::percentile(values, 50.0),
+        stats::percentile(values, 90.0),
+    ]
+    .map(|v| (v * 10.0).round() / 10.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::EnergyProduced;
+    use crate::common::NetEnergyUsed;
+    use crate::common::Quality;
+    use crate::common::WattHours;
+    use crate::data_aggregator::Source;
+    use chrono::TimeZone;
+
+    /// Returns the start of UTC hour `h` on day `day` of June 2022
+    fn hour(day: u32, h: u32) -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc.ymd(2022, 6, day).and_hms(h, 0, 0)
+    }
+
+    #[test]
+    fn test_skips_incomplete_hours() {
+        let mut aggr = DataLoader::new();
+        let produced = [
+            (hour(1, 18), 500, Quality::Ok),
+            (hour(8, 18), 300, Quality::Ok),
+            (hour(1, 19), 400, Quality::Partial),
+            (hour(1, 20), 600, Quality::Ok),
+        ];
+        aggr.load_production(
+            Source::new("test production"),
+            produced.into_iter().map(|(t, wh, quality)| {
+                Ok(EnergyProduced {
+                    datetime_utc: t,
+                    datetime_local: t.with_timezone(&chrono::Local),
+                    energy_wh: WattHours::from(wh),
+                    devices_reporting: None,
+                    quality: Some(quality),
+                })
+            }),
+        )
+        .unwrap();
+        // There's no net usage data for 20:00.
+        let net_used =
+            [(hour(1, 18), 200), (hour(8, 18), 100), (hour(1, 19), 100)];
+        aggr.load_net_usage(
+            Source::new("test net usage"),
+            net_used.into_iter().map(|(t, wh)| {
+                Ok(NetEnergyUsed {
+                    timestamp_start_utc: t,
+                    net_used_wh: WattHours::from(wh),
+                })
+            }),
+        )
+        .unwrap();
+
+        // Only the two complete hours are left.  They're a week apart, so
+        // they're at the same local hour on the same type of day.
+        let profile = load_profile(&aggr);
+        assert_eq!(profile.len(), 1);
+        let p = &profile[0];
+        let local = hour(1, 18).with_timezone(&chrono::Local).naive_local();
+        assert_eq!(p.month, local.month());
+        assert_eq!(p.day_type, DayType::of(&local.date()));
+        assert_eq!(p.hour, local.hour());
+        assert_eq!(p.nhours, 2);
+        assert_eq!(p.produced_mean, 400.0);
+        assert_eq!(p.produced_p10, 320.0);
+        assert_eq!(p.produced_p50, 400.0);
+        assert_eq!(p.produced_p90, 480.0);
+        assert_eq!(p.net_used_mean, 150.0);
+        assert_eq!(p.consumed_mean, 550.0);
+        assert_eq!(p.consumed_p10, 430.0);
+        assert_eq!(p.consumed_p90, 670.0);
+    }
+}
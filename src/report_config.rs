@@ -0,0 +1,222 @@
+//! Configuration for the "report" tool
+//!
+//! This is loaded from a TOML file so that the configuration for a particular
+//! house can be kept in version control alongside (or apart from) its data.
+//! Relative paths in the file are interpreted relative to the directory
+//! containing the file, so `report` can be run from any working directory.
+//! Every setting is optional.  The defaults match what `report` does without a
+//! config file.
+
+use crate::base_load::Annotation;
+use crate::channel::ChannelKind;
+use crate::data_aggregator::ConflictPolicy;
+use crate::disaggregation;
+use crate::disaggregation::ApplianceSignature;
+use crate::solar_model::ArrayConfig;
+use crate::tariff::Tariff;
+use anyhow::Context;
+use serde::Deserialize;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Describes what data to load and which reports to generate
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReportConfig {
+    /// directories containing data files (in any supported format)
+    #[serde(default = "default_data_dirs")]
+    pub data_dirs: Vec<PathBuf>,
+    /// more directories containing data files, each loaded into a particular
+    /// channel
+    #[serde(default)]
+    pub channels: Vec<ChannelConfig>,
+    /// directory in which to write the reports
+    #[serde(default = "default_output_dir")]
+    pub output_dir: PathBuf,
+    /// time zone used for reports (e.g., "America/Los_Angeles")
+    ///
+    /// The default is the system's local time zone.
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// names of the reports to generate
+    #[serde(default = "default_reports")]
+    pub reports: Vec<String>,
+    /// what to do when two data files disagree about the same hour
+    #[serde(default = "default_conflicts")]
+    pub conflicts: ConflictPolicy,
+    /// first (local) day to include in reports
+    #[serde(default)]
+    pub from: Option<chrono::NaiveDate>,
+    /// last (local) day to include in reports
+    #[serde(default)]
+    pub to: Option<chrono::NaiveDate>,
+    /// day of the month on which the utility reads the meter (for the
+    /// "billing-cycles" report)
+    #[serde(default)]
+    pub meter_read_day: Option<u32>,
+    /// any date on which the annual true-up happens (for the "true-up-years"
+    /// report)
+    #[serde(default)]
+    pub true_up_date: Option<chrono::NaiveDate>,
+    /// CSV file of daily temperatures (for the "weather-normalized" report)
+    #[serde(default)]
+    pub temperature_file: Option<PathBuf>,
+    /// base temperature for computing heating and cooling degree days (in the
+    /// same units as the temperature file)
+    #[serde(default = "default_degree_day_base")]
+    pub degree_day_base: f64,
+    /// description of the solar array (for the "performance" report)
+    #[serde(default)]
+    pub array: Option<ArrayConfig>,
+    /// CSV file of hourly irradiance (optional, for the "performance" report)
+    #[serde(default)]
+    pub irradiance_file: Option<PathBuf>,
+    /// whether the irradiance file describes a typical year (like TMY data)
+    /// rather than actual weather
+    #[serde(default)]
+    pub irradiance_typical_year: bool,
+    /// CSV file of hourly cloud cover (optional, for the "forecast" and
+    /// "forecast-backtest" reports)
+    #[serde(default)]
+    pub cloud_cover_file: Option<PathBuf>,
+    /// number of days to forecast, starting today
+    #[serde(default = "default_forecast_days")]
+    pub forecast_days: u32,
+    /// number of days at the end of the data to forecast when backtesting
+    #[serde(default = "default_backtest_days")]
+    pub backtest_days: u32,
+    /// price of electricity per kWh (for estimating the cost of anomalies,
+    /// and of EV charging if there's no `tariff`)
+    #[serde(default)]
+    pub electricity_rate: Option<f64>,
+    /// time-of-use prices (for estimating the cost of EV charging)
+    #[serde(default)]
+    pub tariff: Option<Tariff>,
+    /// notes about changes to the house (for the "base-load-*" reports)
+    #[serde(default)]
+    pub annotations: Vec<Annotation>,
+    /// signatures of appliances to look for (for the "appliance-*" reports)
+    #[serde(default)]
+    pub appliances: Vec<ApplianceSignature>,
+    /// whether to replace reports in an existing output directory (rather
+    /// than failing if it exists)
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+impl Default for ReportConfig {
+    fn default() -> Self {
+        ReportConfig {
+            data_dirs: default_data_dirs(),
+            channels: Vec::new(),
+            output_dir: default_output_dir(),
+            timezone: None,
+            reports: default_reports(),
+            conflicts: default_conflicts(),
+            from: None,
+            to: None,
+            meter_read_day: None,
+            true_up_date: None,
+            temperature_file: None,
+            degree_day_base: default_degree_day_base(),
+            array: None,
+            irradiance_file: None,
+            irradiance_typical_year: false,
+            cloud_cover_file: None,
+            forecast_days: default_forecast_days(),
+            backtest_days: default_backtest_days(),
+            electricity_rate: None,
+            tariff: None,
+            annotations: Vec::new(),
+            appliances: Vec::new(),
+            overwrite: false,
+        }
+    }
+}
+
+/// Describes a directory of data files to load into a particular channel (see
+/// [`crate::channel`])
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelConfig {
+    /// directory containing data files (in any supported format)
+    pub data_dir: PathBuf,
+    /// name of the channel (for files with one column per circuit, this is a
+    /// prefix for the circuits' channels)
+    ///
+    /// The default is the name of the kind of data in the files.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// kind of data in the files, if it's not what their format says (e.g.,
+    /// "consumption" for consumption data from `envoy-poll`, which is in the
+    /// same format as production data)
+    #[serde(default)]
+    pub kind: Option<ChannelKind>,
+}
+
+fn default_data_dirs() -> Vec<PathBuf> {
+    vec![
+        PathBuf::from("local-data/production"),
+        PathBuf::from("local-data/pge"),
+    ]
+}
+
+fn default_output_dir() -> PathBuf {
+    PathBuf::from("generated-reports")
+}
+
+fn default_conflicts() -> ConflictPolicy {
+    ConflictPolicy::KeepFirst
+}
+
+fn default_degree_day_base() -> f64 {
+    65.0
+}
+
+fn default_forecast_days() -> u32 {
+    3
+}
+
+fn default_backtest_days() -> u32 {
+    30
+}
+
+fn default_reports() -> Vec<String> {
+    ["yearly", "monthly", "daily", "hourly"]
+        .iter()
+        .map(|s| String::from(*s))
+        .collect()
+}
+
+impl ReportConfig {
+    /// Loads the config file at `path`
+    ///
+    /// Returns an error if any of the `appliances` doesn't make sense.
+    pub fn load(path: &Path) -> Result<ReportConfig, anyhow::Error> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("read config {:?}", path.display()))?;
+        let mut config: ReportConfig = toml::from_str(&contents)
+            .with_context(|| format!("parsing config {:?}", path.display()))?;
+        disaggregation::validate_signatures(&config.appliances)
+            .with_context(|| format!("checking config {:?}", path.display()))?;
+
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        for dir in &mut config.data_dirs {
+            *dir = base.join(&dir);
+        }
+        for channel in &mut config.channels {
+            channel.data_dir = base.join(&channel.data_dir);
+        }
+        config.output_dir = base.join(&config.output_dir);
+        if let Some(file) = &mut config.temperature_file {
+            *file = base.join(&file);
+        }
+        if let Some(file) = &mut config.irradiance_file {
+            *file = base.join(&file);
+        }
+        if let Some(file) = &mut config.cloud_cover_file {
+            *file = base.join(&file);
+        }
+        Ok(config)
+    }
+}
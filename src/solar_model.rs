@@ -0,0 +1,603 @@
+//! Model of expected solar production
+//!
+//! Given a description of the array (where it is, which way it faces, and how
+//! big it is), we estimate how much it should produce in each hour, either
+//! under clear skies or (if we have it) under measured irradiance.  Comparing
+//! that with what it actually produced gives the *performance ratio*, which
+//! lets us spot soiling, new shading, failing equipment, and long-term panel
+//! degradation.
+//!
+//! The model is deliberately simple, in the spirit of PVWatts: we compute the
+//! sun's position, split sunlight into direct and diffuse parts, project them
+//! onto the plane of the array, and scale by the array's DC rating less a flat
+//! percentage for system losses (inverters, wiring, temperature, etc.).  The
+//! absolute numbers are only good to within 10-20%, but that doesn't matter
+//! much for spotting changes over time.
+//!
+//! Irradiance data can come from a CSV file with columns "datetime_utc" (the
+//! start of each hour, in RFC 3339 format), "ghi" (global horizontal
+//! irradiance, in W/m^2), and optionally "dni" and "dhi" (direct normal and
+//! diffuse horizontal irradiance).  If "dni" and "dhi" are missing, we estimate
+//! them from "ghi".  Each value is taken to be the average over the hour.  For
+//! typical meteorological year (TMY) data, which describes a representative
+//! year rather than a real one, set `irradiance_typical_year` so that hours are
+//! matched by month, day, and hour, ignoring the year.
+
+use crate::bucket;
+use crate::common::local_day_hours;
+use crate::data_aggregator::DataLoader;
+use crate::stats;
+use anyhow::Context;
+use chrono::Datelike;
+use chrono::Timelike;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::path::Path;
+
+/// Describes a solar array
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArrayConfig {
+    /// latitude in degrees (north is positive)
+    pub latitude: f64,
+    /// longitude in degrees (east is positive)
+    pub longitude: f64,
+    /// tilt of the panels from horizontal, in degrees
+    pub tilt: f64,
+    /// direction the panels face, in degrees clockwise from north (so 180 is
+    /// due south)
+    pub azimuth: f64,
+    /// total DC rating of the panels, in kilowatts
+    pub dc_kw: f64,
+    /// system losses, as a percentage of DC output
+    #[serde(default = "default_losses_pct")]
+    pub losses_pct: f64,
+}
+
+fn default_losses_pct() -> f64 {
+    14.0
+}
+
+/// Fraction of ground-reflected light (typical for grass or pavement)
+const ALBEDO: f64 = 0.2;
+
+/// Sunlight arriving at the top of the atmosphere, in W/m^2
+const SOLAR_CONSTANT: f64 = 1367.0;
+
+/// Direction of the sun (or of a surface's normal) as a unit vector with
+/// components pointing east, north, and up
+#[derive(Clone, Copy, Debug)]
+struct Direction {
+    east: f64,
+    north: f64,
+    up: f64,
+}
+
+impl Direction {
+    fn dot(&self, other: &Direction) -> f64 {
+        self.east * other.east + self.north * other.north + self.up * other.up
+    }
+}
+
+/// Returns the direction of the sun at time `t` as seen from the given
+/// location, along with the extraterrestrial irradiance
+///
+/// This uses NOAA's "General Solar Position Calculations", which are accurate
+/// to within a fraction of a degree.
+fn sun_position(
+    t: &chrono::DateTime<chrono::Utc>,
+    latitude: f64,
+    longitude: f64,
+) -> (Direction, f64) {
+    let day_of_year = f64::from(t.ordinal());
+    let hour = f64::from(t.hour())
+        + f64::from(t.minute()) / 60.0
+        + f64::from(t.second()) / 3600.0;
+    let g = 2.0 * PI / 365.0 * (day_of_year - 1.0 + (hour - 12.0) / 24.0);
+    let eqtime = 229.18
+        * (0.000075 + 0.001868 * g.cos()
+            - 0.032077 * g.sin()
+            - 0.014615 * (2.0 * g).cos()
+            - 0.040849 * (2.0 * g).sin());
+    let declination = 0.006918 - 0.399912 * g.cos() + 0.070257 * g.sin()
+        - 0.006758 * (2.0 * g).cos()
+        + 0.000907 * (2.0 * g).sin()
+        - 0.002697 * (3.0 * g).cos()
+        + 0.00148 * (3.0 * g).sin();
+    let true_solar_minutes = hour * 60.0 + eqtime + 4.0 * longitude;
+    let hour_angle = (true_solar_minutes / 4.0 - 180.0).to_radians();
+
+    let lat = latitude.to_radians();
+    let direction = Direction {
+        east: -declination.cos() * hour_angle.sin(),
+        north: declination.sin() * lat.cos()
+            - declination.cos() * hour_angle.cos() * lat.sin(),
+        up: declination.sin() * lat.sin()
+            + declination.cos() * hour_angle.cos() * lat.cos(),
+    };
+    let extraterrestrial =
+        SOLAR_CONSTANT * (1.0 + 0.033 * (2.0 * PI * day_of_year / 365.0).cos());
+    (direction, extraterrestrial)
+}
+
+/// Irradiance on a horizontal surface, in W/m^2
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Irradiance {
+    /// global horizontal irradiance
+    pub ghi: f64,
+    /// direct normal irradiance
+    pub dni: f64,
+    /// diffuse horizontal irradiance
+    pub dhi: f64,
+}
+
+/// Returns the clear-sky irradiance with the sun at the given elevation
+///
+/// This is the Meinel model (with the Kasten-Young air mass formula), taking
+/// diffuse light to be 10% of direct.
+fn clear_sky(cos_zenith: f64) -> Irradiance {
+    if cos_zenith <= 0.0 {
+        return Irradiance::default();
+    }
+    let zenith_deg = cos_zenith.acos().to_degrees();
+    let air_mass =
+        1.0 / (cos_zenith + 0.50572 * (96.07995 - zenith_deg).powf(-1.6364));
+    let dni = 1353.0 * 0.7f64.powf(air_mass.powf(0.678));
+    let dhi = 0.1 * dni * cos_zenith;
+    Irradiance { ghi: dni * cos_zenith + dhi, dni, dhi }
+}
+
+/// Splits global horizontal irradiance into direct and diffuse parts using
+/// the Erbs model
+fn decompose(ghi: f64, cos_zenith: f64, extraterrestrial: f64) -> Irradiance {
+    if ghi <= 0.0 || cos_zenith <= 0.01 {
+        return Irradiance { ghi: ghi.max(0.0), dni: 0.0, dhi: ghi.max(0.0) };
+    }
+    let kt = (ghi / (extraterrestrial * cos_zenith)).min(1.0);
+    let diffuse_fraction = if kt <= 0.22 {
+        1.0 - 0.09 * kt
+    } else if kt <= 0.8 {
+        0.9511 - 0.1604 * kt + 4.388 * kt.powi(2) - 16.638 * kt.powi(3)
+            + 12.336 * kt.powi(4)
+    } else {
+        0.165
+    };
+    let dhi = diffuse_fraction * ghi;
+    Irradiance { ghi, dni: (ghi - dhi) / cos_zenith, dhi }
+}
+
+/// Hourly irradiance data loaded from a file
+pub struct IrradianceData {
+    by_hour: BTreeMap<
+        chrono::DateTime<chrono::Utc>,
+        (f64, Option<f64>, Option<f64>),
+    >,
+    typical_year: bool,
+}
+
+#[derive(Deserialize)]
+struct IrradianceRecord {
+    datetime_utc: chrono::DateTime<chrono::Utc>,
+    ghi: f64,
+    #[serde(default)]
+    dni: Option<f64>,
+    #[serde(default)]
+    dhi: Option<f64>,
+}
+
+impl IrradianceData {
+    /// Loads hourly irradiance from the CSV file at `path` (see the module
+    /// documentation for the format)
+    ///
+    /// If `typical_year` is set, the data is used for every year.
+    pub fn load(
+        path: &Path,
+        typical_year: bool,
+    ) -> Result<IrradianceData, anyhow::Error> {
+        let mut reader = csv::Reader::from_path(path)
+            .with_context(|| format!("open {:?}", path.display()))?;
+        let mut by_hour = BTreeMap::new();
+        for (i, maybe_record) in reader.deserialize().enumerate() {
+            let record: IrradianceRecord = maybe_record.with_context(|| {
+                format!("read record {} from {:?}", i + 1, path.display())
+            })?;
+            let key = hour_key(&record.datetime_utc, typical_year);
+            by_hour.insert(key, (record.ghi, record.dni, record.dhi));
+        }
+        Ok(IrradianceData { by_hour, typical_year })
+    }
+
+    /// Returns the irradiance for the hour starting at `hour_start`, if we
+    /// have it
+    fn get(
+        &self,
+        hour_start: &chrono::DateTime<chrono::Utc>,
+        cos_zenith: f64,
+        extraterrestrial: f64,
+    ) -> Option<Irradiance> {
+        let key = hour_key(hour_start, self.typical_year);
+        let (ghi, dni, dhi) = *self.by_hour.get(&key)?;
+        Some(match (dni, dhi) {
+            (Some(dni), Some(dhi)) => Irradiance { ghi, dni, dhi },
+            _ => decompose(ghi, cos_zenith, extraterrestrial),
+        })
+    }
+}
+
+/// Returns the start of the hour containing `t`, in a fixed (leap) year if
+/// `typical_year` is set (so that typical-year data applies to any year)
+fn hour_key(
+    t: &chrono::DateTime<chrono::Utc>,
+    typical_year: bool,
+) -> chrono::DateTime<chrono::Utc> {
+    let year = if typical_year { 2000 } else { t.year() };
+    let naive = chrono::NaiveDate::from_ymd(year, t.month(), t.day()).and_hms(
+        t.hour(),
+        0,
+        0,
+    );
+    chrono::DateTime::from_utc(naive, chrono::Utc)
+}
+
+impl ArrayConfig {
+    /// Returns the direction that the panels face
+    fn normal(&self) -> Direction {
+        let tilt = self.tilt.to_radians();
+        let azimuth = self.azimuth.to_radians();
+        Direction {
+            east: tilt.sin() * azimuth.sin(),
+            north: tilt.sin() * azimuth.cos(),
+            up: tilt.cos(),
+        }
+    }
+
+    /// Returns the array's output (in watts) given irradiance on a horizontal
+    /// surface and the sun's direction
+    fn power(&self, irradiance: &Irradiance, sun: &Direction) -> f64 {
+        let normal = self.normal();
+        let tilt = self.tilt.to_radians();
+        let beam = if sun.up > 0.0 {
+            irradiance.dni * normal.dot(sun).max(0.0)
+        } else {
+            0.0
+        };
+        let sky = irradiance.dhi * (1.0 + tilt.cos()) / 2.0;
+        let ground = irradiance.ghi * ALBEDO * (1.0 - tilt.cos()) / 2.0;
+        let plane_of_array = beam + sky + ground;
+        self.dc_kw * plane_of_array * (1.0 - self.losses_pct / 100.0)
+    }
+
+    /// Returns the expected production (in watt-hours) under clear skies for
+    /// the hour starting at `hour_start`
+    pub fn clear_sky_wh(
+        &self,
+        hour_start: &chrono::DateTime<chrono::Utc>,
+    ) -> f64 {
+        // Sample the middle of each quarter hour so that we capture sunrise
+        // and sunset reasonably well.
+        let samples = 4;
+        (0..samples)
+            .map(|i| {
+                let t = *hour_start
+                    + chrono::Duration::seconds(i64::from(
+                        (2 * i + 1) * 3600 / (2 * samples),
+                    ));
+                let (sun, _) = sun_position(&t, self.latitude, self.longitude);
+                self.power(&clear_sky(sun.up), &sun)
+            })
+            .sum::<f64>()
+            / f64::from(samples)
+    }
+
+    /// Returns the expected production (in watt-hours) under the measured
+    /// irradiance for the hour starting at `hour_start`, if we have
+    /// irradiance data for it
+    pub fn irradiance_wh(
+        &self,
+        irradiance: &IrradianceData,
+        hour_start: &chrono::DateTime<chrono::Utc>,
+    ) -> Option<f64> {
+        let middle = *hour_start + chrono::Duration::minutes(30);
+        let (sun, extraterrestrial) =
+            sun_position(&middle, self.latitude, self.longitude);
+        let measured = irradiance.get(hour_start, sun.up, extraterrestrial)?;
+        Some(self.power(&measured, &sun))
+    }
+}
+
+/// Compares one day's measured production with the model
+///
+/// Energy values are in watt-hours.
+#[derive(Debug, Serialize)]
+pub struct DailyPerformance {
+    pub date: chrono::NaiveDate,
+    pub produced: i64,
+    /// number of hours for which production data was reported as partial
+    pub production_partial_hours: usize,
+    /// expected production under clear skies
+    pub expected_clear_sky: f64,
+    /// expected production under measured irradiance (empty if we don't have
+    /// irradiance data for the whole day)
+    pub expected_irradiance: Option<f64>,
+    /// `produced` divided by the best expectation we have (irradiance, if
+    /// available, or clear-sky otherwise)
+    pub performance_ratio: Option<f64>,
+    /// 90th percentile of the performance ratio over the last 30 days, which
+    /// approximates the performance on clear days
+    pub clear_day_ratio_30d: Option<f64>,
+    /// whether `clear_day_ratio_30d` is well below what's typical for this
+    /// time of year, which may indicate soiling, new shading, or failed
+    /// equipment
+    pub underperforming: bool,
+}
+
+/// Summary of performance over the whole data set
+#[derive(Debug)]
+pub struct PerformanceSummary {
+    /// estimated change in performance per year, as a percentage (negative
+    /// for degradation), if there's enough data
+    pub degradation_pct_per_year: Option<f64>,
+    /// number of year-over-year monthly comparisons used for the estimate
+    pub ncomparisons: usize,
+}
+
+/// Number of days over which we look for clear days
+const CLEAR_DAY_WINDOW: usize = 30;
+
+/// Minimum number of days in a window to estimate clear-day performance
+const CLEAR_DAY_MIN_DAYS: usize = 10;
+
+/// Percentile of daily performance ratios used to estimate clear-day
+/// performance
+const CLEAR_DAY_PERCENTILE: f64 = 90.0;
+
+/// Fraction of typical clear-day performance below which we flag a day
+const UNDERPERFORMING_THRESHOLD: f64 = 0.9;
+
+/// Compares measured production in the loaded data with the model for each
+/// (local) day that has a full day of production data
+///
+/// Days at the edges of the data (or next to a gap) would look like the array
+/// was underperforming, so they're left out (see
+/// [`DataLoader::has_full_day_of_production()`]).
+pub fn daily_performance(
+    aggr: &DataLoader,
+    array: &ArrayConfig,
+    irradiance: Option<&IrradianceData>,
+) -> (Vec<DailyPerformance>, PerformanceSummary) {
+    let mut days = Vec::new();
+    for (day_start, totals) in aggr.aggregate(bucket::Days) {
+        let date = day_start.date();
+        if !aggr.has_full_day_of_production(&date) {
+            continue;
+        }
+        let hours = local_day_hours(&date);
+        let expected_clear_sky: f64 =
+            hours.iter().map(|h| array.clear_sky_wh(h)).sum();
+        let expected_irradiance = irradiance.and_then(|irradiance| {
+            hours
+                .iter()
+                .map(|h| array.irradiance_wh(irradiance, h))
+                .sum::<Option<f64>>()
+        });
+        let produced = totals.produced.as_wh();
+        let expected = expected_irradiance.unwrap_or(expected_clear_sky);
+        let performance_ratio =
+            (expected > 0.0).then(|| produced as f64 / expected);
+        days.push(DailyPerformance {
+            date,
+            produced,
+            production_partial_hours: totals.production_partial_hours,
+            expected_clear_sky: round(expected_clear_sky, 1),
+            expected_irradiance: expected_irradiance.map(|e| round(e, 1)),
+            performance_ratio,
+            clear_day_ratio_30d: None,
+            underperforming: false,
+        });
+    }
+
+    // Estimate clear-day performance from the best days in a trailing window.
+    // Days with partial data would make the array look worse than it is, so
+    // leave those out.
+    let usable_ratio = |d: &DailyPerformance| {
+        d.performance_ratio.filter(|_| d.production_partial_hours == 0)
+    };
+    for i in 0..days.len() {
+        let window_start =
+            days[i].date - chrono::Duration::days(CLEAR_DAY_WINDOW as i64 - 1);
+        let mut ratios: Vec<f64> = days[..=i]
+            .iter()
+            .rev()
+            .take_while(|d| d.date >= window_start)
+            .filter_map(usable_ratio)
+            .collect();
+        if ratios.len() >= CLEAR_DAY_MIN_DAYS {
+            stats::sort(&mut ratios);
+            days[i].clear_day_ratio_30d =
+                Some(stats::percentile(&ratios, CLEAR_DAY_PERCENTILE));
+        }
+    }
+
+    // What's typical for each calendar month (across all years) accounts for
+    // shading that happens every year when the sun is low.  Compare each
+    // day's clear-day performance with that.
+    let mut by_month: BTreeMap<u32, Vec<f64>> = BTreeMap::new();
+    let mut by_year_month: BTreeMap<(i32, u32), Vec<f64>> = BTreeMap::new();
+    for day in &days {
+        if let Some(ratio) = usable_ratio(day) {
+            by_month.entry(day.date.month()).or_default().push(ratio);
+            by_year_month
+                .entry((day.date.year(), day.date.month()))
+                .or_default()
+                .push(ratio);
+        }
+    }
+    let typical: BTreeMap<u32, f64> = by_month
+        .into_iter()
+        .map(|(month, mut ratios)| {
+            stats::sort(&mut ratios);
+            (month, stats::percentile(&ratios, CLEAR_DAY_PERCENTILE))
+        })
+        .collect();
+    for day in &mut days {
+        if let (Some(recent), Some(typical)) =
+            (day.clear_day_ratio_30d, typical.get(&day.date.month()))
+        {
+            day.underperforming = recent < typical * UNDERPERFORMING_THRESHOLD;
+        }
+    }
+
+    // Estimate degradation by comparing each month's clear-day performance
+    // with the same month in the previous year, which cancels out seasonal
+    // effects.  Taking the median of these changes keeps a bad month (e.g.,
+    // dirty panels) from throwing off the estimate.
+    let monthly: BTreeMap<(i32, u32), f64> = by_year_month
+        .into_iter()
+        .filter(|(_, ratios)| ratios.len() >= CLEAR_DAY_MIN_DAYS)
+        .map(|(key, mut ratios)| {
+            stats::sort(&mut ratios);
+            (key, stats::percentile(&ratios, CLEAR_DAY_PERCENTILE))
+        })
+        .collect();
+    let mut changes: Vec<f64> = monthly
+        .iter()
+        .filter_map(|((year, month), ratio)| {
+            let prior = monthly.get(&(year - 1, *month))?;
+            (*prior > 0.0).then(|| ratio / prior - 1.0)
+        })
+        .collect();
+    stats::sort(&mut changes);
+    let degradation_pct_per_year = (!changes.is_empty())
+        .then(|| round(stats::percentile(&changes, 50.0) * 100.0, 2));
+
+    for day in &mut days {
+        day.performance_ratio = day.performance_ratio.map(|r| round(r, 3));
+        day.clear_day_ratio_30d = day.clear_day_ratio_30d.map(|r| round(r, 3));
+    }
+
+    let summary = PerformanceSummary {
+        degradation_pct_per_year,
+        ncomparisons: changes.len(),
+    };
+    (days, summary)
+}
+
+/// Rounds `value` to `places` decimal places
+fn round(value: f64, places: i32) -> f64 {
+    let scale = 10f64.powi(places);
+    (value * scale).round() / scale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// Returns the sun's elevation above the horizon, in degrees
+    fn elevation(sun: &Direction) -> f64 {
+        sun.up.asin().to_degrees()
+    }
+
+    #[test]
+    fn test_sun_position() {
+        // At the June solstice, the sun's declination is 23.45 degrees.  At
+        // noon UTC, it's (nearly) due south of any place on the prime
+        // meridian, so its elevation is 90 - latitude + 23.45 degrees.
+        let noon = chrono::Utc.ymd(2022, 6, 21).and_hms(12, 0, 0);
+        let (sun, _) = sun_position(&noon, 23.45, 0.0);
+        assert!(elevation(&sun) > 89.5, "{:?}", sun);
+        let (sun, _) = sun_position(&noon, 37.77, 0.0);
+        assert!((elevation(&sun) - 75.68).abs() < 0.1, "{:?}", sun);
+        assert!(sun.north < 0.0 && sun.east.abs() < 0.01, "{:?}", sun);
+
+        // At midnight, it's as far below the northern horizon.
+        let midnight = chrono::Utc.ymd(2022, 6, 21).and_hms(0, 0, 0);
+        let (sun, _) = sun_position(&midnight, 37.77, 0.0);
+        assert!((elevation(&sun) + 28.78).abs() < 0.1, "{:?}", sun);
+        assert!(sun.north > 0.0, "{:?}", sun);
+
+        // At 08:00 UTC, it's morning on the prime meridian (so the sun is in
+        // the east), night at 90 degrees west, and afternoon at 90 degrees
+        // east (so the sun is in the west).
+        let morning = chrono::Utc.ymd(2022, 6, 21).and_hms(8, 0, 0);
+        let (sun, _) = sun_position(&morning, 37.77, 0.0);
+        assert!(sun.east > 0.0 && sun.up > 0.0, "{:?}", sun);
+        let (sun, _) = sun_position(&morning, 37.77, -90.0);
+        assert!(sun.up < 0.0, "{:?}", sun);
+        let (sun, _) = sun_position(&morning, 37.77, 90.0);
+        assert!(sun.east < 0.0 && sun.up > 0.0, "{:?}", sun);
+
+        // The Earth is closest to the sun in early January, so sunlight at
+        // the top of the atmosphere is 1367 * (1 + 0.033) W/m^2 then and
+        // 1367 * (1 - 0.033) in early July.
+        let january = chrono::Utc.ymd(2022, 1, 1).and_hms(12, 0, 0);
+        let (_, extraterrestrial) = sun_position(&january, 37.77, 0.0);
+        assert!((extraterrestrial - 1412.1).abs() < 0.1);
+        let july = chrono::Utc.ymd(2022, 7, 1).and_hms(12, 0, 0);
+        let (_, extraterrestrial) = sun_position(&july, 37.77, 0.0);
+        assert!((extraterrestrial - 1321.9).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_clear_sky() {
+        // With the sun overhead, the air mass is 1, so direct sunlight is
+        // 1353 * 0.7 W/m^2, and diffuse light adds another 10%.
+        let overhead = clear_sky(1.0);
+        assert!((overhead.dni - 947.2).abs() < 0.1, "{:?}", overhead);
+        assert!((overhead.dhi - 94.7).abs() < 0.1, "{:?}", overhead);
+        assert!((overhead.ghi - 1041.9).abs() < 0.1, "{:?}", overhead);
+
+        // With the sun 30 degrees above the horizon, the air mass is about 2:
+        // direct sunlight is 1353 * 0.7^(2^0.678), and only half of it falls
+        // on a horizontal surface.
+        let low = clear_sky(0.5);
+        assert!((low.dni - 765.5).abs() < 0.1, "{:?}", low);
+        assert!((low.ghi - 1.1 * 765.5 * 0.5).abs() < 0.1, "{:?}", low);
+
+        let night = clear_sky(-0.2);
+        assert_eq!((night.ghi, night.dni, night.dhi), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_decompose() {
+        // A clearness index of 0.5 puts 65.9% of the light in the diffuse
+        // part.
+        let irradiance = decompose(500.0, 1.0, 1000.0);
+        assert!((irradiance.dhi - 329.6).abs() < 0.1, "{:?}", irradiance);
+        assert!((irradiance.dni - 170.4).abs() < 0.1, "{:?}", irradiance);
+
+        // Overcast skies are nearly all diffuse light.
+        let irradiance = decompose(100.0, 1.0, 1000.0);
+        assert!((irradiance.dhi - 99.1).abs() < 1e-9, "{:?}", irradiance);
+    }
+
+    #[test]
+    fn test_clear_sky_wh() {
+        // A flat 1 kW array with 14% losses under the (nearly) overhead sun
+        // gets about 0.86 * 1041.9 Wh, less a little for the sun being a few
+        // degrees off overhead at the ends of the hour.
+        let array = ArrayConfig {
+            latitude: 23.45,
+            longitude: 0.0,
+            tilt: 0.0,
+            azimuth: 180.0,
+            dc_kw: 1.0,
+            losses_pct: 14.0,
+        };
+        let hour = chrono::Utc.ymd(2022, 6, 21).and_hms(11, 30, 0);
+        assert!((array.clear_sky_wh(&hour) - 893.5).abs() < 0.1);
+        let night = chrono::Utc.ymd(2022, 6, 21).and_hms(0, 0, 0);
+        assert_eq!(array.clear_sky_wh(&night), 0.0);
+
+        // Tilting the array toward the low winter sun helps.
+        let december = chrono::Utc.ymd(2022, 12, 21).and_hms(11, 30, 0);
+        let tilted =
+            ArrayConfig { latitude: 40.0, tilt: 40.0, ..array.clone() };
+        let flat = ArrayConfig { latitude: 40.0, ..array };
+        assert!(
+            tilted.clear_sky_wh(&december) > 1.3 * flat.clear_sky_wh(&december)
+        );
+    }
+}
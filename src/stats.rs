@@ -0,0 +1,81 @@
+//! Simple summary statistics
+
+/// Returns the mean of `values` (or 0 if there are none)
+pub fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<f64>() / (values.len() as f64)
+    }
+}
+
+/// Returns the `p`th percentile (0-100) of `sorted`, which must be sorted
+/// in increasing order (or 0 if it's empty)
+///
+/// This interpolates linearly between the two closest values, the same way
+/// spreadsheets' `PERCENTILE` function does.
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (p / 100.0).clamp(0.0, 1.0) * ((sorted.len() - 1) as f64);
+    let below = rank.floor() as usize;
+    let above = rank.ceil() as usize;
+    sorted[below] + (sorted[above] - sorted[below]) * (rank - below as f64)
+}
+
+/// Sorts `values` in increasing order (as required by [`percentile()`])
+pub fn sort(values: &mut [f64]) {
+    values.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+}
+
+/// Fits `y = b[0] * x[0] + b[1] * x[1] + ...` to the given observations by
+/// ordinary least squares, returning the coefficients `b`
+///
+/// Each element of `xs` holds the regressors for one observation, and the
+/// corresponding element of `ys` is the observed value.  Include a regressor
+/// that's always 1 to get an intercept.  Returns `None` if there's no unique
+/// solution (e.g., if there are too few observations or two regressors are
+/// proportional to each other).
+pub fn least_squares(xs: &[Vec<f64>], ys: &[f64]) -> Option<Vec<f64>> {
+    let n = xs.first()?.len();
+    assert_eq!(xs.len(), ys.len());
+    assert!(xs.iter().all(|x| x.len() == n));
+
+    // Solve the normal equations (X'X) b = X'y by Gaussian elimination with
+    // partial pivoting.  Each row of `m` is one equation, augmented with the
+    // right-hand side.  This is plenty accurate for the handful of regressors
+    // we use.
+    let mut m = vec![vec![0.0; n + 1]; n];
+    for (x, y) in xs.iter().zip(ys) {
+        for i in 0..n {
+            for j in 0..n {
+                m[i][j] += x[i] * x[j];
+            }
+            m[i][n] += x[i] * y;
+        }
+    }
+
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&a, &b| {
+                m[a][col].abs().partial_cmp(&m[b][col].abs()).unwrap()
+            })
+            .unwrap();
+        if m[pivot][col].abs() < 1e-9 {
+            return None;
+        }
+        m.swap(col, pivot);
+        let pivot_row = m[col].clone();
+        for (row, equation) in m.iter_mut().enumerate() {
+            if row != col {
+                let factor = equation[col] / pivot_row[col];
+                for (v, p) in equation.iter_mut().zip(&pivot_row).skip(col) {
+                    *v -= factor * p;
+                }
+            }
+        }
+    }
+
+    Some((0..n).map(|i| m[i][n] / m[i][i]).collect())
+}
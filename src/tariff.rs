@@ -0,0 +1,60 @@
+//! Electricity prices
+//!
+//! A tariff is a time-of-use schedule (see [`TouSchedule`]) plus a price per
+//! kWh for each of its periods.  A flat rate is just a schedule with no
+//! periods other than the default one.
+
+use crate::bucket::TouSchedule;
+use anyhow::bail;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Describes what electricity costs at different times
+#[derive(Clone, Debug, Deserialize)]
+pub struct Tariff {
+    #[serde(flatten)]
+    pub schedule: TouSchedule,
+    /// price per kWh in each period, by period name
+    pub rates: BTreeMap<String, f64>,
+}
+
+impl Tariff {
+    /// Returns a tariff that charges `rate` per kWh at all times
+    pub fn flat(rate: f64) -> Tariff {
+        let name = String::from("flat");
+        Tariff {
+            schedule: TouSchedule {
+                periods: Vec::new(),
+                default_period: name.clone(),
+            },
+            rates: BTreeMap::from([(name, rate)]),
+        }
+    }
+
+    /// Checks that every period in the schedule has a rate
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let names = self
+            .schedule
+            .periods
+            .iter()
+            .map(|p| &p.name)
+            .chain(std::iter::once(&self.schedule.default_period));
+        for name in names {
+            if !self.rates.contains_key(name) {
+                bail!("tariff has no rate for period {:?}", name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the price per kWh for the hour starting at `hour_start`
+    ///
+    /// Panics if the tariff is missing a rate (see [`Tariff::validate()`]).
+    pub fn rate_at(&self, hour_start: &chrono::DateTime<chrono::Utc>) -> f64 {
+        let period = self.schedule.period_at(hour_start);
+        *self
+            .rates
+            .get(period)
+            .unwrap_or_else(|| panic!("tariff has no rate for {:?}", period))
+    }
+}
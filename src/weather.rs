@@ -0,0 +1,418 @@
+//! Weather normalization using heating and cooling degree days
+//!
+//! Energy use swings with the weather, which makes it hard to compare one
+//! period with another.  To correct for that, we fit a simple model of daily
+//! consumption:
+//!
+//! ```text
+//! consumed = base + (per_hdd * HDD) + (per_cdd * CDD)
+//! ```
+//!
+//! where HDD and CDD are the day's heating and cooling degree days: how far
+//! the day's mean temperature was below (or above) a base temperature.  The
+//! weather-normalized consumption for a day is what the model says the day
+//! would have used in "normal" weather (the average degree days for that
+//! calendar day across all of the temperature data), plus whatever the model
+//! didn't explain about the actual day.  A real efficiency gain shows up as a
+//! drop in normalized consumption; a mild winter doesn't.
+//!
+//! Temperatures come from a CSV file of daily temperatures, like the "Daily
+//! Summaries" CSV export of NOAA's GHCN-Daily data set.  We use the "TAVG"
+//! column if it has a value and the midpoint of "TMAX" and "TMIN" otherwise.
+//! Any units work, as long as the base temperature uses the same ones.
+
+use crate::bucket;
+use crate::common::local_day_hours;
+use crate::data_aggregator::DataLoader;
+use crate::stats;
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use chrono::Datelike;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Loads the mean temperature for each day from the CSV file at `path`
+///
+/// If the file has data from more than one station, temperatures for the
+/// same day are averaged.  Days with no temperature data are skipped.
+pub fn load_daily_temperatures(
+    path: &Path,
+) -> Result<BTreeMap<chrono::NaiveDate, f64>, anyhow::Error> {
+    let mut reader = csv::Reader::from_path(path)
+        .with_context(|| format!("open {:?}", path.display()))?;
+    let headers = reader
+        .headers()
+        .with_context(|| format!("read header from {:?}", path.display()))?
+        .clone();
+    let column = |name: &str| {
+        headers.iter().position(|h| {
+            h.trim_start_matches('\u{feff}').eq_ignore_ascii_case(name)
+        })
+    };
+    let date_column = column("DATE")
+        .ok_or_else(|| anyhow!("{:?}: no \"DATE\" column", path.display()))?;
+    let tavg_column = column("TAVG");
+    let tmax_column = column("TMAX");
+    let tmin_column = column("TMIN");
+    if tavg_column.is_none() && (tmax_column.is_none() || tmin_column.is_none())
+    {
+        bail!(
+            "{:?}: need a \"TAVG\" column or \"TMAX\" and \"TMIN\" columns",
+            path.display()
+        );
+    }
+
+    let mut sums: BTreeMap<chrono::NaiveDate, (f64, usize)> = BTreeMap::new();
+    for (i, maybe_record) in reader.records().enumerate() {
+        let record = maybe_record.with_context(|| {
+            format!("read record {} from {:?}", i + 1, path.display())
+        })?;
+        let value =
+            |column: Option<usize>| -> Result<Option<f64>, anyhow::Error> {
+                match column.and_then(|c| record.get(c)).map(str::trim) {
+                    None | Some("") => Ok(None),
+                    Some(s) => s.parse().map(Some).with_context(|| {
+                        format!(
+                            "{:?}: record {}: bad temperature {:?}",
+                            path.display(),
+                            i + 1,
+                            s
+                        )
+                    }),
+                }
+            };
+        let date_str = record.get(date_column).unwrap_or("").trim();
+        let date = chrono::NaiveDate::parse_from_str(date_str, "%Y-%m-%d")
+            .with_context(|| {
+                format!(
+                    "{:?}: record {}: bad date {:?}",
+                    path.display(),
+                    i + 1,
+                    date_str
+                )
+            })?;
+        let temperature = match (
+            value(tavg_column)?,
+            value(tmax_column)?,
+            value(tmin_column)?,
+        ) {
+            (Some(tavg), _, _) => tavg,
+            (None, Some(tmax), Some(tmin)) => (tmax + tmin) / 2.0,
+            _ => continue,
+        };
+        let sum = sums.entry(date).or_insert((0.0, 0));
+        sum.0 += temperature;
+        sum.1 += 1;
+    }
+
+    Ok(sums
+        .into_iter()
+        .map(|(date, (sum, n))| (date, sum / (n as f64)))
+        .collect())
+}
+
+/// Returns the heating and cooling degree days for a day with mean
+/// temperature `temperature`
+pub fn degree_days(temperature: f64, base: f64) -> (f64, f64) {
+    ((base - temperature).max(0.0), (temperature - base).max(0.0))
+}
+
+/// Model of daily consumption as a function of degree days (see the module
+/// documentation)
+///
+/// Energy values are in watt-hours.
+#[derive(Debug)]
+pub struct WeatherModel {
+    /// consumption on a day with no heating or cooling degree days
+    pub base_wh: f64,
+    /// additional consumption for each heating degree day
+    pub wh_per_hdd: f64,
+    /// additional consumption for each cooling degree day
+    pub wh_per_cdd: f64,
+    /// fraction of the variation in daily consumption that the model explains
+    pub r_squared: f64,
+    /// number of days used to fit the model
+    pub ndays: usize,
+}
+
+impl WeatherModel {
+    /// Fits the model to a set of `(hdd, cdd, consumed_wh)` observations
+    ///
+    /// If none of the days needed heating (or cooling), that term is left out
+    /// of the model.  Returns `None` if there isn't enough data.
+    pub fn fit(days: &[(f64, f64, f64)]) -> Option<WeatherModel> {
+        let use_hdd = days.iter().any(|(hdd, _, _)| *hdd > 0.0);
+        let use_cdd = days.iter().any(|(_, cdd, _)| *cdd > 0.0);
+        let xs: Vec<Vec<f64>> = days
+            .iter()
+            .map(|(hdd, cdd, _)| {
+                let mut x = vec![1.0];
+                if use_hdd {
+                    x.push(*hdd);
+                }
+                if use_cdd {
+                    x.push(*cdd);
+                }
+                x
+            })
+            .collect();
+        let ys: Vec<f64> = days.iter().map(|(_, _, y)| *y).collect();
+        if ys.len() <= xs.first()?.len() {
+            return None;
+        }
+        let mut b = stats::least_squares(&xs, &ys)?.into_iter();
+        let base_wh = b.next().unwrap();
+        let wh_per_hdd = if use_hdd { b.next().unwrap() } else { 0.0 };
+        let wh_per_cdd = if use_cdd { b.next().unwrap() } else { 0.0 };
+        let mut model = WeatherModel {
+            base_wh,
+            wh_per_hdd,
+            wh_per_cdd,
+            r_squared: 0.0,
+            ndays: days.len(),
+        };
+
+        let mean = stats::mean(&ys);
+        let total: f64 = ys.iter().map(|y| (y - mean).powi(2)).sum();
+        let residual: f64 = days
+            .iter()
+            .map(|(hdd, cdd, y)| (y - model.predict(*hdd, *cdd)).powi(2))
+            .sum();
+        model.r_squared =
+            if total > 0.0 { 1.0 - residual / total } else { 1.0 };
+        Some(model)
+    }
+
+    /// Returns the predicted consumption for a day with the given degree days
+    pub fn predict(&self, hdd: f64, cdd: f64) -> f64 {
+        self.base_wh + self.wh_per_hdd * hdd + self.wh_per_cdd * cdd
+    }
+}
+
+/// Describes one day of weather-normalized consumption
+///
+/// Energy values are in watt-hours.
+#[derive(Debug, Serialize)]
+pub struct NormalizedDay {
+    pub date: chrono::NaiveDate,
+    pub mean_temperature: f64,
+    pub hdd: f64,
+    pub cdd: f64,
+    /// average heating degree days for this calendar day
+    pub normal_hdd: f64,
+    /// average cooling degree days for this calendar day
+    pub normal_cdd: f64,
+    pub consumed: i64,
+    /// consumption predicted by the model for this day's weather
+    pub predicted: f64,
+    /// consumption adjusted to normal weather
+    pub normalized_consumed: f64,
+    /// whether this day was used to fit the model (days missing any net usage
+    /// or production data, or with partial production data, aren't)
+    pub used_in_fit: bool,
+}
+
+/// Fits a [`WeatherModel`] to the loaded data and returns it along with the
+/// weather-normalized consumption for each (local) day that has temperature
+/// data
+pub fn normalize(
+    aggr: &DataLoader,
+    temperatures: &BTreeMap<chrono::NaiveDate, f64>,
+    base_temperature: f64,
+) -> Result<(WeatherModel, Vec<NormalizedDay>), anyhow::Error> {
+    // "Normal" degree days for each calendar day are the averages over all the
+    // temperature data we have for that calendar day.
+    let mut sums: BTreeMap<(u32, u32), (f64, f64, usize)> = BTreeMap::new();
+    for (date, temperature) in temperatures {
+        let (hdd, cdd) = degree_days(*temperature, base_temperature);
+        let sum = sums.entry((date.month(), date.day())).or_default();
+        sum.0 += hdd;
+        sum.1 += cdd;
+        sum.2 += 1;
+    }
+    let normals: BTreeMap<(u32, u32), (f64, f64)> = sums
+        .into_iter()
+        .map(|(k, (hdd, cdd, n))| (k, (hdd / (n as f64), cdd / (n as f64))))
+        .collect();
+
+    let mut days = Vec::new();
+    let mut observations = Vec::new();
+    for (day_start, totals) in aggr.aggregate(bucket::Days) {
+        // Skip days with only sub-meter data (e.g., EV charging).
+        if totals.nhours == 0 {
+            continue;
+        }
+        let date = day_start.date();
+        let temperature = match temperatures.get(&date) {
+            Some(t) => *t,
+            None => continue,
+        };
+        let (hdd, cdd) = degree_days(temperature, base_temperature);
+        let consumed = totals.consumed.as_wh();
+        // Consumption is only right if we have all of the production and net
+        // usage data.  Days have 23 or 25 hours when daylight saving time
+        // starts or ends.  (There's no production data for hours when the
+        // system isn't producing, so see `has_full_day_of_production()`.)
+        let used_in_fit = totals.net_usage_hours
+            == local_day_hours(&date).len()
+            && aggr.has_full_day_of_production(&date)
+            && totals.production_partial_hours == 0;
+        if used_in_fit {
+            observations.push((hdd, cdd, consumed as f64));
+        }
+        days.push((date, temperature, hdd, cdd, consumed, used_in_fit));
+    }
+
+    let model = WeatherModel::fit(&observations).ok_or_else(|| {
+        anyhow!(
+            "not enough data to fit a weather model ({} complete days with \
+            temperature data)",
+            observations.len()
+        )
+    })?;
+
+    let round = |v: f64| (v * 10.0).round() / 10.0;
+    let normalized = days
+        .into_iter()
+        .map(|(date, temperature, hdd, cdd, consumed, used_in_fit)| {
+            let (normal_hdd, normal_cdd) = normals[&(date.month(), date.day())];
+            let predicted = model.predict(hdd, cdd);
+            let normalized_consumed = (consumed as f64) - predicted
+                + model.predict(normal_hdd, normal_cdd);
+            NormalizedDay {
+                date,
+                mean_temperature: round(temperature),
+                hdd: round(hdd),
+                cdd: round(cdd),
+                normal_hdd: round(normal_hdd),
+                normal_cdd: round(normal_cdd),
+                consumed,
+                predicted: round(predicted),
+                normalized_consumed: round(normalized_consumed),
+                used_in_fit,
+            }
+        })
+        .collect();
+
+    Ok((model, normalized))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::EnergyProduced;
+    use crate::common::NetEnergyUsed;
+    use crate::common::WattHours;
+    use crate::data_aggregator::Source;
+
+    #[test]
+    fn test_fit_exact() {
+        // consumed = 1000 + 100 * HDD + 50 * CDD
+        let days = [
+            (0.0, 0.0, 1000.0),
+            (10.0, 0.0, 2000.0),
+            (0.0, 4.0, 1200.0),
+            (5.0, 2.0, 1600.0),
+        ];
+        let model = WeatherModel::fit(&days).unwrap();
+        assert!((model.base_wh - 1000.0).abs() < 1e-6);
+        assert!((model.wh_per_hdd - 100.0).abs() < 1e-6);
+        assert!((model.wh_per_cdd - 50.0).abs() < 1e-6);
+        assert!((model.r_squared - 1.0).abs() < 1e-9);
+        assert_eq!(model.ndays, 4);
+        assert!((model.predict(2.0, 1.0) - 1250.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_fit_residuals() {
+        // No cooling, so that term is left out.  The least-squares line
+        // through (0, 1000), (1, 1200), and (2, 1300) has slope 150 and
+        // intercept 1016.67.  The residuals are -16.67, 33.33, and -16.67, so
+        // the residual sum of squares is 1666.67 out of a total of 46666.67.
+        let days = [(0.0, 0.0, 1000.0), (1.0, 0.0, 1200.0), (2.0, 0.0, 1300.0)];
+        let model = WeatherModel::fit(&days).unwrap();
+        assert!((model.base_wh - 3050.0 / 3.0).abs() < 1e-6);
+        assert!((model.wh_per_hdd - 150.0).abs() < 1e-6);
+        assert_eq!(model.wh_per_cdd, 0.0);
+        assert!((model.r_squared - 27.0 / 28.0).abs() < 1e-9);
+
+        // Two days can't determine two coefficients and say how well they fit.
+        assert!(WeatherModel::fit(&days[..2]).is_none());
+        assert!(WeatherModel::fit(&[]).is_none());
+    }
+
+    #[test]
+    fn test_normalize_skips_day_missing_an_hour() {
+        // Four ordinary 24-hour days with 10 Wh of production every hour.
+        // Each day's consumption is 1200 + 120 * HDD (with a base of 65),
+        // except that one hour of net usage is missing on the last day.
+        let base_temperature = 65.0;
+        let temperatures: BTreeMap<chrono::NaiveDate, f64> =
+            [(1, 60.0), (2, 55.0), (3, 65.0), (4, 50.0)]
+                .into_iter()
+                .map(|(d, t)| (chrono::NaiveDate::from_ymd(2022, 6, d), t))
+                .collect();
+        let mut production = Vec::new();
+        let mut net_usage = Vec::new();
+        for (date, temperature) in &temperatures {
+            let (hdd, _) = degree_days(*temperature, base_temperature);
+            let net_wh = ((1200.0 + 120.0 * hdd - 240.0) / 24.0) as i32;
+            let hours = local_day_hours(date);
+            assert_eq!(hours.len(), 24);
+            for (i, start) in hours.into_iter().enumerate() {
+                // Data is kept by UTC hour.  In time zones that aren't a
+                // whole number of hours from UTC, use the UTC hour that
+                // starts during this local hour.
+                let t = start + chrono::Duration::minutes(59);
+                production.push(EnergyProduced {
+                    datetime_utc: t,
+                    datetime_local: t.with_timezone(&chrono::Local),
+                    energy_wh: WattHours::from(10),
+                    devices_reporting: None,
+                    quality: None,
+                });
+                if date.day() != 4 || i != 20 {
+                    net_usage.push(NetEnergyUsed {
+                        timestamp_start_utc: t,
+                        net_used_wh: WattHours::from(net_wh),
+                    });
+                }
+            }
+        }
+        let mut aggr = DataLoader::new();
+        aggr.load_production(
+            Source::new("test production"),
+            production.into_iter().map(Ok),
+        )
+        .unwrap();
+        aggr.load_net_usage(
+            Source::new("test net usage"),
+            net_usage.into_iter().map(Ok),
+        )
+        .unwrap();
+
+        let (model, days) =
+            normalize(&aggr, &temperatures, base_temperature).unwrap();
+        assert_eq!(model.ndays, 3);
+        assert!((model.base_wh - 1200.0).abs() < 1e-6);
+        assert!((model.wh_per_hdd - 120.0).abs() < 1e-6);
+        assert_eq!(model.wh_per_cdd, 0.0);
+
+        let used: Vec<(u32, i64, bool)> = days
+            .iter()
+            .map(|d| (d.date.day(), d.consumed, d.used_in_fit))
+            .collect();
+        assert_eq!(
+            used,
+            vec![
+                (1, 1800, true),
+                (2, 2400, true),
+                (3, 1200, true),
+                (4, 240 + 23 * 115, false)
+            ]
+        );
+        assert_eq!(days[3].predicted, 3000.0);
+    }
+}
@@ -0,0 +1,177 @@
+//! Year-over-year comparisons
+//!
+//! These line up each month (or each day of the year) with the same period in
+//! each earlier year so that you can see the effect of changes to the house
+//! (e.g., a new heat pump or EV).  Periods are compared in local time.
+
+use crate::data_aggregator::DataLoader;
+use crate::data_aggregator::EnergyTotals;
+use chrono::Datelike;
+use serde::Serialize;
+
+/// How finely to divide up each year
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Period {
+    /// calendar months
+    Month,
+    /// days of the year (by month and day, so that leap years don't throw off
+    /// the rest of the year)
+    Day,
+}
+
+/// Compares one period in one year with the same period in an earlier year
+///
+/// Energy values are in watt-hours.  The "prior" values are empty if there's
+/// no data for the period in any earlier year.  Percentages are empty if the
+/// prior value is zero.  Check `nhours` and `prior_nhours` before reading too
+/// much into a comparison: periods at the start and end of the data may be
+/// incomplete.
+#[derive(Debug, Serialize)]
+pub struct Comparison {
+    /// the month ("MM") or day ("MM-DD") being compared
+    pub period: String,
+    pub year: i32,
+    /// number of hours of data for this period
+    pub nhours: usize,
+    pub produced: i64,
+    pub net_used: i64,
+    pub consumed: i64,
+    /// the earlier year being compared against
+    pub prior_year: Option<i32>,
+    pub prior_nhours: Option<usize>,
+    pub prior_produced: Option<i64>,
+    pub prior_net_used: Option<i64>,
+    pub prior_consumed: Option<i64>,
+    pub produced_delta: Option<i64>,
+    pub produced_delta_pct: Option<f64>,
+    pub net_used_delta: Option<i64>,
+    pub net_used_delta_pct: Option<f64>,
+    pub consumed_delta: Option<i64>,
+    pub consumed_delta_pct: Option<f64>,
+}
+
+/// Compares each period in the loaded data with the same period in each
+/// earlier year that has data for it
+///
+/// There's one result for each pair of years, or just one for the period if
+/// there's no earlier year to compare with.  Results are sorted by period,
+/// then by year, then by prior year, so that (e.g.) all of the Januaries are
+/// together.
+pub fn compare(aggr: &DataLoader, period: Period) -> Vec<Comparison> {
+    let mut totals = aggr.aggregate(|t: &chrono::DateTime<chrono::Utc>| {
+        let date = t.with_timezone(&chrono::Local).date().naive_local();
+        let label = match period {
+            Period::Month => format!("{:02}", date.month()),
+            Period::Day => format!("{:02}-{:02}", date.month(), date.day()),
+        };
+        Some((label, date.year()))
+    });
+    // Skip periods with only sub-meter data (e.g., EV charging).
+    totals.retain(|_, t| t.nhours > 0);
+
+    let mut rv = Vec::new();
+    for ((label, year), current) in &totals {
+        let earlier = (label.clone(), i32::MIN)..(label.clone(), *year);
+        let priors: Vec<_> = totals
+            .range(earlier)
+            .map(|((_, prior_year), prior)| (*prior_year, prior))
+            .collect();
+        if priors.is_empty() {
+            rv.push(comparison(label, *year, current, None));
+        }
+        for prior in priors {
+            rv.push(comparison(label, *year, current, Some(prior)));
+        }
+    }
+    rv
+}
+
+/// Compares `current` (the totals for `period` in `year`) with `prior` (the
+/// totals for the same period in an earlier year), if any
+fn comparison(
+    period: &str,
+    year: i32,
+    current: &EnergyTotals,
+    prior: Option<(i32, &EnergyTotals)>,
+) -> Comparison {
+    let wh = |t: &EnergyTotals| {
+        [t.produced.as_wh(), t.net_used.as_wh(), t.consumed.as_wh()]
+    };
+    let [produced, net_used, consumed] = wh(current);
+    let prior_wh = prior.map(|(_, p)| wh(p));
+    let delta = |i: usize| prior_wh.map(|p| wh(current)[i] - p[i]);
+    let delta_pct =
+        |i: usize| prior_wh.and_then(|p| percent_change(p[i], wh(current)[i]));
+    Comparison {
+        period: period.to_owned(),
+        year,
+        nhours: current.nhours,
+        produced,
+        net_used,
+        consumed,
+        prior_year: prior.map(|(y, _)| y),
+        prior_nhours: prior.map(|(_, p)| p.nhours),
+        prior_produced: prior_wh.map(|p| p[0]),
+        prior_net_used: prior_wh.map(|p| p[1]),
+        prior_consumed: prior_wh.map(|p| p[2]),
+        produced_delta: delta(0),
+        produced_delta_pct: delta_pct(0),
+        net_used_delta: delta(1),
+        net_used_delta_pct: delta_pct(1),
+        consumed_delta: delta(2),
+        consumed_delta_pct: delta_pct(2),
+    }
+}
+
+/// Returns the change from `before` to `after` as a percentage of `before`
+/// (rounded to tenths), or `None` if `before` is zero
+fn percent_change(before: i64, after: i64) -> Option<f64> {
+    if before == 0 {
+        return None;
+    }
+    let pct = ((after - before) as f64) * 100.0 / (before as f64).abs();
+    Some((pct * 10.0).round() / 10.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::EnergyProduced;
+    use crate::common::WattHours;
+    use crate::data_aggregator::Source;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_compare_with_each_earlier_year() {
+        let records = [(2020, 100), (2021, 200), (2022, 300)].map(|(y, wh)| {
+            let local = chrono::Local.ymd(y, 1, 15).and_hms(12, 0, 0);
+            Ok(EnergyProduced {
+                datetime_utc: local.with_timezone(&chrono::Utc),
+                datetime_local: local,
+                energy_wh: WattHours::from(wh),
+                devices_reporting: None,
+                quality: None,
+            })
+        });
+        let mut aggr = DataLoader::new();
+        aggr.load_production(Source::new("test"), records.into_iter()).unwrap();
+
+        let comparisons = compare(&aggr, Period::Month);
+        let summary: Vec<_> = comparisons
+            .iter()
+            .map(|c| (c.year, c.prior_year, c.produced_delta))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (2020, None, None),
+                (2021, Some(2020), Some(100)),
+                (2022, Some(2020), Some(200)),
+                (2022, Some(2021), Some(100)),
+            ]
+        );
+        assert!(comparisons.iter().all(|c| c.period == "01"));
+        assert_eq!(comparisons[2].produced_delta_pct, Some(200.0));
+        assert_eq!(comparisons[3].prior_produced, Some(200));
+    }
+}
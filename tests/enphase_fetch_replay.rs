@@ -0,0 +1,445 @@
+//! Tests for `enphase-fetch`, run against recorded Enlighten responses
+//!
+//! Each directory in `tests/fixtures/enlighten` holds the responses for one
+//! scenario (see `home_energy_tools::enlighten_replay`).  We run the real
+//! binary with `--replay` so that everything from the generated client to the
+//! CSV output is exercised.  The fixtures describe a system in California, so
+//! we run the tool in that time zone.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Which API version the creds file is set up for
+#[derive(Clone, Copy)]
+enum ApiVersion {
+    V2,
+    V4,
+}
+
+/// Result of running `enphase-fetch`
+struct Run {
+    success: bool,
+    stderr: String,
+    /// CSV records written to stdout (not including the header row)
+    records: Vec<csv::StringRecord>,
+    /// directory containing the fetch log and checkpoint
+    dir: PathBuf,
+}
+
+impl Run {
+    fn record(&self, datetime_utc: &str) -> &csv::StringRecord {
+        self.records
+            .iter()
+            .find(|r| &r[0] == datetime_utc)
+            .unwrap_or_else(|| panic!("no record for {}", datetime_utc))
+    }
+
+    fn total_wh(&self) -> i64 {
+        self.records.iter().map(|r| r[2].parse::<i64>().unwrap()).sum()
+    }
+
+    /// Returns the fetch log's records as (date, nintervals, complete)
+    fn fetch_log(&self) -> Vec<(String, String, String)> {
+        let mut reader =
+            csv::Reader::from_path(self.dir.join("fetch-log.csv")).unwrap();
+        let headers = reader.headers().unwrap().clone();
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|h| h == name)
+                .unwrap_or_else(|| panic!("fetch log has no {:?} column", name))
+        };
+        let (date, nintervals, complete) =
+            (column("date"), column("nintervals"), column("complete"));
+        reader
+            .records()
+            .map(|r| {
+                let r = r.unwrap();
+                (
+                    r[date].to_owned(),
+                    r[nintervals].to_owned(),
+                    r[complete].to_owned(),
+                )
+            })
+            .collect()
+    }
+}
+
+fn fetch(
+    test_name: &str,
+    scenario: &str,
+    version: ApiVersion,
+    first_day: &str,
+    last_day: &str,
+) -> Run {
+    let dir = test_dir(test_name, version);
+    run(&dir, scenario, &["--start-date", first_day, "--end-date", last_day])
+}
+
+/// Creates an empty directory for the test, with a creds file for `version`
+fn test_dir(test_name: &str, version: ApiVersion) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("enphase-fetch-replay")
+        .join(test_name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // When replaying, the values in the creds file don't matter, but which
+    // ones are there determines the API version.
+    let creds = match version {
+        ApiVersion::V2 => {
+            "enlighten_key = \"test-key\"\nenlighten_user_id = \"test-user\"\n"
+        }
+        ApiVersion::V4 => {
+            "enlighten_key = \"test-key\"\n[oauth]\nclient_id = \"test-client\"\n\
+            client_secret = \"test-secret\"\n"
+        }
+    };
+    std::fs::write(dir.join("enphase_creds.toml"), creds).unwrap();
+    dir
+}
+
+/// Runs `enphase-fetch` against the fixtures for `scenario`, keeping the
+/// fetch log and checkpoint in `dir`
+fn run(dir: &Path, scenario: &str, args: &[&str]) -> Run {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/enlighten")
+        .join(scenario);
+    let output = Command::new(env!("CARGO_BIN_EXE_enphase-fetch"))
+        .env("TZ", "America/Los_Angeles")
+        .arg("--creds-file")
+        .arg(dir.join("enphase_creds.toml"))
+        .arg("--replay")
+        .arg(&fixtures)
+        .arg("--fetch-log")
+        .arg(dir.join("fetch-log.csv"))
+        .arg("--checkpoint")
+        .arg(dir.join("checkpoint.toml"))
+        .args(args)
+        .output()
+        .unwrap();
+
+    let mut reader = csv::Reader::from_reader(&output.stdout[..]);
+    let records = if output.stdout.is_empty() {
+        Vec::new()
+    } else {
+        assert_eq!(
+            reader.headers().unwrap(),
+            vec![
+                "datetime_utc",
+                "datetime_local",
+                "energy_wh",
+                "devices_reporting",
+                "quality"
+            ]
+        );
+        reader.records().map(|r| r.unwrap()).collect()
+    };
+    Run {
+        success: output.status.success(),
+        stderr: String::from_utf8(output.stderr).unwrap(),
+        records,
+        dir: dir.to_owned(),
+    }
+}
+
+#[test]
+fn test_spring_forward() {
+    // Daylight saving time started at 2am local time on 2022-03-13.  That
+    // local day is 23 hours long and covers parts of two UTC days.
+    let run = fetch(
+        "spring_forward",
+        "spring-forward",
+        ApiVersion::V2,
+        "2022-03-13",
+        "2022-03-13",
+    );
+    assert!(run.success, "{}", run.stderr);
+    assert_eq!(run.records.len(), 284);
+    assert_eq!(run.total_wh(), 34213 + 34302);
+
+    // The evening of the previous local day was still in standard time.
+    let r = run.record("2022-03-13T01:00:00Z");
+    assert_eq!(&r[1], "2022-03-12T17:00:00-08:00");
+    // Afterwards, local times are in daylight time.
+    let r = run.record("2022-03-13T19:00:00Z");
+    assert_eq!(&r[1], "2022-03-13T12:00:00-07:00");
+    assert_eq!(&r[3], "20");
+    assert_eq!(&r[4], "ok");
+
+    // Intervals at dawn and dusk (when some microinverters haven't reported)
+    // are flagged as partial.
+    let r = run.record("2022-03-13T14:25:00Z");
+    assert_eq!(&r[1], "2022-03-13T07:25:00-07:00");
+    assert_eq!(&r[3], "17");
+    assert_eq!(&r[4], "partial");
+
+    assert_eq!(
+        run.fetch_log(),
+        vec![
+            (
+                String::from("2022-03-13"),
+                String::from("142"),
+                String::from("true")
+            ),
+            (
+                String::from("2022-03-14"),
+                String::from("142"),
+                String::from("true")
+            ),
+        ]
+    );
+    // The checkpoint is removed after a successful run.
+    assert!(!run.dir.join("checkpoint.toml").exists());
+}
+
+#[test]
+fn test_fall_back() {
+    // Daylight saving time ended at 2am local time on 2022-11-06.  That
+    // local day is 25 hours long.
+    let run = fetch(
+        "fall_back",
+        "fall-back",
+        ApiVersion::V2,
+        "2022-11-06",
+        "2022-11-06",
+    );
+    assert!(run.success, "{}", run.stderr);
+    assert_eq!(run.records.len(), 250);
+    assert_eq!(run.total_wh(), 30206 + 30109);
+
+    let r = run.record("2022-11-06T00:30:00Z");
+    assert_eq!(&r[1], "2022-11-05T17:30:00-07:00");
+    let r = run.record("2022-11-06T20:00:00Z");
+    assert_eq!(&r[1], "2022-11-06T12:00:00-08:00");
+    let r = run.record("2022-11-07T00:30:00Z");
+    assert_eq!(&r[1], "2022-11-06T16:30:00-08:00");
+
+    let dates: Vec<String> =
+        run.fetch_log().into_iter().map(|(date, _, _)| date).collect();
+    assert_eq!(dates, vec!["2022-11-06", "2022-11-07"]);
+}
+
+#[test]
+fn test_empty_day() {
+    // The system was offline for all of 2022-01-10 (UTC).  That's not an
+    // error: there's just no data for that day.
+    let run = fetch(
+        "empty_day",
+        "empty-day",
+        ApiVersion::V2,
+        "2022-01-10",
+        "2022-01-10",
+    );
+    assert!(run.success, "{}", run.stderr);
+    assert_eq!(run.records.len(), 103);
+    assert!(run.records.iter().all(|r| r[0].starts_with("2022-01-11")));
+    assert_eq!(run.total_wh(), 27709);
+
+    // Enlighten has data through a later time, so the day is complete.
+    assert_eq!(
+        run.fetch_log(),
+        vec![
+            (
+                String::from("2022-01-10"),
+                String::from("0"),
+                String::from("true")
+            ),
+            (
+                String::from("2022-01-11"),
+                String::from("103"),
+                String::from("true")
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_rate_limited_v2() {
+    // v2 reports being rate-limited with a 409.  We retry until it works.
+    let run = fetch(
+        "rate_limited_v2",
+        "rate-limit-v2",
+        ApiVersion::V2,
+        "2022-06-21",
+        "2022-06-21",
+    );
+    assert!(run.success, "{}", run.stderr);
+    assert_eq!(
+        run.stderr
+            .matches("rate limited: Usage limit exceeded for plan Watt")
+            .count(),
+        2,
+        "{}",
+        run.stderr
+    );
+    assert_eq!(run.stderr.matches("will retry").count(), 2, "{}", run.stderr);
+    assert_eq!(run.records.len(), 178 * 2);
+    assert_eq!(run.total_wh(), 42765 * 2);
+}
+
+#[test]
+fn test_rate_limited_v4() {
+    // v4 reports being rate-limited with a 429 and a different body.
+    let run = fetch(
+        "rate_limited_v4",
+        "rate-limit-v4",
+        ApiVersion::V4,
+        "2022-06-21",
+        "2022-06-21",
+    );
+    assert!(run.success, "{}", run.stderr);
+    assert_eq!(run.stderr.matches("rate limited").count(), 1, "{}", run.stderr);
+    assert_eq!(run.stderr.matches("will retry").count(), 1, "{}", run.stderr);
+    assert_eq!(run.records.len(), 178 * 2);
+    assert_eq!(run.total_wh(), 42765 * 2);
+}
+
+#[test]
+fn test_unprocessable_not_retried() {
+    let run = fetch(
+        "unprocessable",
+        "errors",
+        ApiVersion::V2,
+        "2022-02-01",
+        "2022-02-01",
+    );
+    assert!(!run.success);
+    assert!(
+        run.stderr.contains(
+            "unprocessable request: Requested date range is invalid for this \
+            system"
+        ),
+        "{}",
+        run.stderr
+    );
+    assert!(!run.stderr.contains("will retry"), "{}", run.stderr);
+    assert!(run.records.is_empty());
+    // The checkpoint is kept so that the run can be resumed.
+    assert!(run.dir.join("checkpoint.toml").exists());
+}
+
+#[test]
+fn test_server_error_retried() {
+    // Server errors are retried, but not forever.
+    let run = fetch(
+        "server_error",
+        "errors",
+        ApiVersion::V2,
+        "2022-02-02",
+        "2022-02-02",
+    );
+    assert!(!run.success);
+    assert!(run.stderr.contains("server error (503"), "{}", run.stderr);
+    assert_eq!(run.stderr.matches("will retry").count(), 5, "{}", run.stderr);
+    assert!(run.records.is_empty());
+}
+
+#[test]
+fn test_output_dir_ignores_stdout_fetches() {
+    // A stale file for 2022-03-13 (UTC) is already in the output directory.
+    // Fetching that day to stdout says nothing about what's in the file, so
+    // a later --output-dir run must still replace it.
+    let dir = test_dir("output_dir_after_stdout", ApiVersion::V2);
+    let output_dir = dir.join("production");
+    std::fs::create_dir_all(&output_dir).unwrap();
+    let stale_file = output_dir.join("enphase-2022-03-13.csv");
+    std::fs::write(
+        &stale_file,
+        "datetime_utc,datetime_local,energy_wh,devices_reporting,quality\n",
+    )
+    .unwrap();
+
+    let dates = ["--start-date", "2022-03-13", "--end-date", "2022-03-13"];
+    let run_stdout = run(&dir, "spring-forward", &dates);
+    assert!(run_stdout.success, "{}", run_stdout.stderr);
+    assert_eq!(run_stdout.records.len(), 284);
+
+    let output_dir_arg = output_dir.to_str().unwrap();
+    let dir_args = [&dates[..], &["--output-dir", output_dir_arg]].concat();
+    let run_dir = run(&dir, "spring-forward", &dir_args);
+    assert!(run_dir.success, "{}", run_dir.stderr);
+    assert!(!run_dir.stderr.contains("already complete"), "{}", run_dir.stderr);
+    let nrecords =
+        |path: &Path| csv::Reader::from_path(path).unwrap().records().count();
+    assert_eq!(nrecords(&stale_file), 142);
+    assert_eq!(nrecords(&output_dir.join("enphase-2022-03-14.csv")), 142);
+
+    // Now the log says that the files are complete.
+    let run_again = run(&dir, "spring-forward", &dir_args);
+    assert!(run_again.success, "{}", run_again.stderr);
+    assert_eq!(
+        run_again.stderr.matches("already complete").count(),
+        2,
+        "{}",
+        run_again.stderr
+    );
+}
+
+#[test]
+fn test_resume_uses_checkpoint_dates() {
+    // The first run fails on 2022-02-01 and leaves a checkpoint behind.
+    let dir = test_dir("resume", ApiVersion::V2);
+    let dates = ["--start-date", "2022-02-01", "--end-date", "2022-02-01"];
+    let first = run(&dir, "errors", &dates);
+    assert!(!first.success);
+    let checkpoint =
+        std::fs::read_to_string(dir.join("checkpoint.toml")).unwrap();
+    assert!(checkpoint.contains("end_date = \"2022-02-03\""), "{}", checkpoint);
+
+    // Resuming with different dates is refused.
+    let conflicting = run(
+        &dir,
+        "errors",
+        &["--resume", "--start-date", "2022-02-01", "--end-date", "2022-02-02"],
+    );
+    assert!(!conflicting.success);
+    assert!(
+        conflicting.stderr.contains(
+            "cannot resume with UTC days 2022-02-01 through 2022-02-03: \
+            previous run was fetching 2022-02-01 through 2022-02-02"
+        ),
+        "{}",
+        conflicting.stderr
+    );
+
+    // Without any dates, the resumed run picks up the checkpoint's range
+    // (rather than defaulting to yesterday) and gets as far as the first run.
+    let resumed = run(&dir, "errors", &["--resume"]);
+    assert!(!resumed.success);
+    assert!(
+        resumed.stderr.contains(
+            "resuming run that started at 2022-02-01 from 2022-02-01 \
+            (through 2022-02-02)"
+        ),
+        "{}",
+        resumed.stderr
+    );
+    assert!(
+        resumed.stderr.contains("unprocessable request"),
+        "{}",
+        resumed.stderr
+    );
+}
+
+#[test]
+fn test_default_paths() {
+    // By default, the fetch log and checkpoint go into "local-data", which
+    // doesn't exist yet.  A plain run to stdout should still work.
+    let dir = test_dir("default_paths", ApiVersion::V2);
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/enlighten/empty-day");
+    let output = Command::new(env!("CARGO_BIN_EXE_enphase-fetch"))
+        .current_dir(&dir)
+        .env("TZ", "America/Los_Angeles")
+        .arg("--replay")
+        .arg(&fixtures)
+        .args(["--start-date", "2022-01-10", "--end-date", "2022-01-10"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(output.status.success(), "{}", stderr);
+    assert!(dir.join("local-data/fetch-log.csv").exists());
+    assert!(!dir.join("local-data/fetch-checkpoint.toml").exists());
+}
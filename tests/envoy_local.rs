@@ -0,0 +1,216 @@
+//! Tests for the Envoy client and counter tracking used by `envoy-poll`
+//!
+//! The client is exercised against a mock Envoy on localhost that serves
+//! recorded responses from `tests/fixtures/envoy`.
+
+use chrono::TimeZone;
+use home_energy_tools::common::WattHours;
+use home_energy_tools::envoy_local::CounterSample;
+use home_energy_tools::envoy_local::CounterTracker;
+use home_energy_tools::envoy_local::EnvoyClient;
+use home_energy_tools::envoy_local::Measurement;
+use std::path::Path;
+use std::path::PathBuf;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+
+/// Mock Envoy serving the recorded responses in one fixture directory
+///
+/// A request for "/production.json" is answered with the contents of
+/// "production.json" (and so on), or a 404 if there's no such file.  If
+/// `token` is set, requests without that token get a 401, like newer
+/// firmware.
+struct MockEnvoy {
+    base_url: String,
+}
+
+impl MockEnvoy {
+    async fn start(scenario: &str, token: Option<&str>) -> MockEnvoy {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/envoy")
+            .join(scenario);
+        let authorization = token.map(|t| format!("Bearer {}", t));
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let dir = dir.clone();
+                let authorization = authorization.clone();
+                tokio::spawn(serve(stream, dir, authorization));
+            }
+        });
+        MockEnvoy { base_url }
+    }
+}
+
+/// Reads one request from `stream` and writes the response
+async fn serve(
+    mut stream: tokio::net::TcpStream,
+    dir: PathBuf,
+    authorization: Option<String>,
+) {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await.unwrap();
+        if n == 0 {
+            return;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request).into_owned();
+    let path = request.split_whitespace().nth(1).unwrap_or("/").to_owned();
+    let authorized = match &authorization {
+        None => true,
+        Some(expected) => request
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .any(|(name, value)| {
+                name.eq_ignore_ascii_case("authorization")
+                    && value.trim() == expected
+            }),
+    };
+
+    let fixture = dir.join(path.trim_start_matches('/').replace('/', "_"));
+    let (status, body) = if !authorized {
+        ("401 Unauthorized", String::new())
+    } else {
+        match std::fs::read_to_string(&fixture) {
+            Ok(body) => ("200 OK", body),
+            Err(_) => ("404 Not Found", String::new()),
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\ncontent-type: application/json\r\n\
+        content-length: {}\r\nconnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_metered_envoy() {
+    let envoy = MockEnvoy::start("metered", Some("test-token")).await;
+    let client =
+        EnvoyClient::new(&envoy.base_url, Some(String::from("test-token")))
+            .unwrap();
+    let response = client.production().await.unwrap();
+
+    // With a production meter, we use it rather than the microinverters.
+    let produced = response.produced().unwrap();
+    assert_eq!(produced.kind, "eim");
+    assert_eq!(produced.reading_time, 1641772812);
+    assert_eq!(produced.wh_lifetime, 4951876.518);
+
+    // Consumption comes from the total, not net, consumption meter.
+    let consumed = response.consumed().unwrap();
+    assert_eq!(consumed.measurement_type.as_deref(), Some("total-consumption"));
+    assert_eq!(consumed.wh_lifetime, 7162323.618);
+}
+
+#[tokio::test]
+async fn test_metered_envoy_requires_token() {
+    let envoy = MockEnvoy::start("metered", Some("test-token")).await;
+
+    let client = EnvoyClient::new(&envoy.base_url, None).unwrap();
+    let error = client.production().await.unwrap_err();
+    assert!(format!("{:#}", error).contains("401"), "{:#}", error);
+
+    let client =
+        EnvoyClient::new(&envoy.base_url, Some(String::from("wrong-token")))
+            .unwrap();
+    let error = client.production().await.unwrap_err();
+    assert!(format!("{:#}", error).contains("401"), "{:#}", error);
+}
+
+#[tokio::test]
+async fn test_unmetered_envoy() {
+    // Older firmware doesn't need a token.
+    let envoy = MockEnvoy::start("unmetered", None).await;
+    let client = EnvoyClient::new(&envoy.base_url, None).unwrap();
+    let response = client.production().await.unwrap();
+
+    // The Envoy reports a production meter, but it's not installed, so we
+    // use the microinverters.
+    let produced = response.produced().unwrap();
+    assert_eq!(produced.kind, "inverters");
+    assert_eq!(produced.active_count, Some(16));
+    assert_eq!(produced.wh_lifetime, 3105522.0);
+    assert!(response.consumed().is_none());
+}
+
+fn reading(reading_time: i64, wh_lifetime: f64) -> Measurement {
+    Measurement {
+        kind: String::from("eim"),
+        measurement_type: Some(String::from("production")),
+        active_count: Some(1),
+        reading_time,
+        w_now: 0.0,
+        wh_lifetime,
+    }
+}
+
+fn assert_energy(sample: CounterSample, start: i64, energy_wh: i32) {
+    match sample {
+        CounterSample::Energy(record) => {
+            assert_eq!(record.datetime_utc, chrono::Utc.timestamp(start, 0));
+            assert_eq!(record.energy_wh, WattHours::from(energy_wh));
+        }
+        other => panic!("expected energy, found {:?}", other),
+    }
+}
+
+#[test]
+fn test_counter_tracker() {
+    let mut tracker = CounterTracker::new();
+    assert!(matches!(
+        tracker.sample(&reading(1641772800, 1000.4)),
+        CounterSample::First
+    ));
+    assert_energy(tracker.sample(&reading(1641772860, 1020.6)), 1641772800, 21);
+    // The lifetime counter is rounded rather than each difference, so
+    // rounding errors don't accumulate.
+    assert_energy(tracker.sample(&reading(1641772920, 1040.4)), 1641772860, 19);
+}
+
+#[test]
+fn test_counter_tracker_stale() {
+    let mut tracker = CounterTracker::new();
+    tracker.sample(&reading(1641772800, 1000.0));
+    assert_energy(tracker.sample(&reading(1641772860, 1020.0)), 1641772800, 20);
+
+    // The Envoy hasn't taken a new reading, so there's nothing to report, even
+    // if the counter looks different.
+    assert!(matches!(
+        tracker.sample(&reading(1641772860, 1020.0)),
+        CounterSample::Stale
+    ));
+    assert!(matches!(
+        tracker.sample(&reading(1641772800, 1030.0)),
+        CounterSample::Stale
+    ));
+
+    // The next interval starts from the last new reading.
+    assert_energy(tracker.sample(&reading(1641772920, 1045.0)), 1641772860, 25);
+}
+
+#[test]
+fn test_counter_tracker_went_backwards() {
+    let mut tracker = CounterTracker::new();
+    tracker.sample(&reading(1641772800, 5000.0));
+    match tracker.sample(&reading(1641772860, 12.0)) {
+        CounterSample::WentBackwards { start, from_wh, to_wh } => {
+            assert_eq!(start, chrono::Utc.timestamp(1641772800, 0));
+            assert_eq!(from_wh, 5000);
+            assert_eq!(to_wh, 12);
+        }
+        other => panic!("expected counter reset, found {:?}", other),
+    }
+
+    // After a reset, we pick up from the new value of the counter.
+    assert_energy(tracker.sample(&reading(1641772920, 30.0)), 1641772860, 18);
+}